@@ -1,4 +1,24 @@
+use std::process::Command;
+
 fn main() {
-    // This is a placeholder build script
-    // worker-build will handle the actual build process
+    // worker-build handles the actual build process; this only records the
+    // git revision for /health/deep (CI can set GIT_SHA explicitly)
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let sha = std::env::var("GIT_SHA").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|sha| sha.trim().to_string())
+    });
+
+    println!(
+        "cargo:rustc-env=GIT_SHA={}",
+        sha.unwrap_or_else(|| "unknown".to_string())
+    );
 }
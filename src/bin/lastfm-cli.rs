@@ -84,6 +84,7 @@ fn build_cli() -> Command {
         .subcommand(build_tag_command())
         .subcommand(build_user_command())
         .subcommand(build_library_command())
+        .subcommand(build_search_command())
         .subcommand(build_auth_command())
         .subcommand(build_my_command())
 }
@@ -458,11 +459,48 @@ fn build_library_command() -> Command {
 fn build_auth_command() -> Command {
     Command::new("auth")
         .about("Authentication commands")
-        .subcommand(Command::new("login").about("Authenticate with Last.fm"))
+        .subcommand(
+            Command::new("login")
+                .about("Authenticate with Last.fm")
+                .arg(
+                    Arg::new("password")
+                        .help("Log in with username and password instead of a browser")
+                        .long("password")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("username")
+                        .help("Username for password login")
+                        .long("username")
+                        .requires("password"),
+                ),
+        )
         .subcommand(Command::new("status").about("Check authentication status"))
         .subcommand(Command::new("logout").about("Log out and clear session"))
 }
 
+fn build_search_command() -> Command {
+    Command::new("search")
+        .about("Search artists, albums and tracks in one ranked list")
+        .arg(Arg::new("query").help("Search query").required(true))
+        .arg(Arg::new("limit").help("Results per type").long("limit"))
+        .arg(
+            Arg::new("artist_limit")
+                .help("Artist results (0 to skip artists)")
+                .long("artist-limit"),
+        )
+        .arg(
+            Arg::new("album_limit")
+                .help("Album results (0 to skip albums)")
+                .long("album-limit"),
+        )
+        .arg(
+            Arg::new("track_limit")
+                .help("Track results (0 to skip tracks)")
+                .long("track-limit"),
+        )
+}
+
 fn build_my_command() -> Command {
     Command::new("my")
         .about("Commands for authenticated user (requires login)")
@@ -572,6 +610,8 @@ async fn handle_category_command(
             let args = extract_args(sub_matches);
             (full_name, args)
         }
+        // Top-level commands without subcommands, e.g. `search`
+        None if registry.get(category).is_some() => (category.to_string(), extract_args(matches)),
         None => return Err(CliError::invalid_command("No subcommand specified")),
     };
 
@@ -597,11 +637,11 @@ fn extract_args(matches: &clap::ArgMatches) -> CommandArgs {
     let mut args = CommandArgs::default();
 
     // Known flags to check
-    let flag_names = ["autocorrect", "extended"];
+    let flag_names = ["autocorrect", "extended", "password"];
 
-    // Extract flags - check if they are present
+    // Extract flags - check if they are set
     for flag in flag_names {
-        if matches.try_contains_id(flag).unwrap_or(false) {
+        if let Ok(Some(true)) = matches.try_get_one::<bool>(flag) {
             args.flags.insert(flag.to_string(), true);
         }
     }
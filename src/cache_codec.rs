@@ -0,0 +1,188 @@
+// Binary format for KV cache values: a small header, an optionally gzipped
+// body, and transparent chunking for bodies over the KV value size limit.
+//
+// Header (11 bytes): b"LFC", version, encoding, chunk count (u16, big endian),
+// generation (u32, big endian). Chunk 0 carries the header; chunks 1.. are
+// stored under `{key}:chunk:{generation}:{i}`. The generation is derived from
+// the payload, so a rewrite never reads chunks left behind by an earlier,
+// longer value; those simply expire. Values without the magic prefix are
+// legacy plain-text entries.
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 3] = b"LFC";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 11;
+
+/// KV values are limited to 25 MiB; stay comfortably below it
+pub const MAX_CHUNK_BYTES: usize = 20 * 1024 * 1024;
+
+// Small bodies are not worth compressing
+const MIN_COMPRESS_BYTES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity = 0,
+    Gzip = 1,
+}
+
+impl Encoding {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Identity),
+            1 => Some(Self::Gzip),
+            _ => None,
+        }
+    }
+}
+
+/// Key for an extra chunk of the value whose first chunk is `first`
+pub fn chunk_key(cache_key: &str, first: &[u8], index: usize) -> String {
+    let generation = first
+        .get(7..HEADER_LEN)
+        .map(hex::encode)
+        .unwrap_or_default();
+    format!("{cache_key}:chunk:{generation}:{index}")
+}
+
+fn gzip(body: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).ok()?;
+    encoder.finish().ok()
+}
+
+/// Encode a body into one or more KV values of at most `chunk_size` bytes
+pub fn encode(body: &str, chunk_size: usize) -> Vec<Vec<u8>> {
+    let compressed = if body.len() >= MIN_COMPRESS_BYTES {
+        gzip(body.as_bytes()).filter(|compressed| compressed.len() < body.len())
+    } else {
+        None
+    };
+    let (encoding, payload) = match compressed {
+        Some(compressed) => (Encoding::Gzip, compressed),
+        None => (Encoding::Identity, body.as_bytes().to_vec()),
+    };
+
+    // The header shares the first chunk's budget
+    let chunk_size = chunk_size.max(HEADER_LEN + 1);
+    let first_len = payload.len().min(chunk_size - HEADER_LEN);
+    let (first, rest) = payload.split_at(first_len);
+    let mut chunks: Vec<Vec<u8>> = vec![first.to_vec()];
+    chunks.extend(rest.chunks(chunk_size).map(<[u8]>::to_vec));
+
+    let count = chunks.len() as u16;
+    let digest = md5::compute(&payload);
+    let mut header = MAGIC.to_vec();
+    header.extend([VERSION, encoding as u8]);
+    header.extend(count.to_be_bytes());
+    header.extend(&digest[..4]);
+    chunks[0].splice(0..0, header);
+
+    chunks
+}
+
+/// Number of chunks a stored value was split into, or `None` for a legacy entry
+pub fn chunk_count(first: &[u8]) -> Option<usize> {
+    if first.len() < HEADER_LEN || &first[..3] != MAGIC || first[3] != VERSION {
+        return None;
+    }
+    Some(u16::from_be_bytes([first[5], first[6]]) as usize)
+}
+
+/// Reassemble and decompress a value; legacy entries are returned as-is
+pub fn decode(chunks: &[Vec<u8>]) -> Result<String, String> {
+    let first = chunks.first().ok_or("Empty cache value")?;
+
+    let Some(count) = chunk_count(first) else {
+        return String::from_utf8(first.clone()).map_err(|e| e.to_string());
+    };
+    if chunks.len() != count {
+        return Err(format!("Expected {count} chunks, found {}", chunks.len()));
+    }
+
+    let encoding = Encoding::from_byte(first[4]).ok_or("Unknown cache encoding")?;
+    let mut payload = first[HEADER_LEN..].to_vec();
+    for chunk in &chunks[1..] {
+        payload.extend_from_slice(chunk);
+    }
+
+    let bytes = match encoding {
+        Encoding::Identity => payload,
+        Encoding::Gzip => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(payload.as_slice())
+                .read_to_end(&mut decompressed)
+                .map_err(|e| e.to_string())?;
+            decompressed
+        }
+    };
+
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large_body() -> String {
+        let tracks: Vec<String> = (0..500)
+            .map(|i| format!(r##"{{"name":"Track {i}","artist":{{"#text":"Radiohead"}}}}"##))
+            .collect();
+        format!(r#"{{"recenttracks":{{"track":[{}]}}}}"#, tracks.join(","))
+    }
+
+    #[test]
+    fn test_round_trip_compresses_large_bodies() {
+        let body = large_body();
+        let chunks = encode(&body, MAX_CHUNK_BYTES);
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0][4], Encoding::Gzip as u8);
+        assert!(chunks[0].len() < body.len() / 4);
+        assert_eq!(decode(&chunks).unwrap(), body);
+    }
+
+    #[test]
+    fn test_small_bodies_are_stored_uncompressed() {
+        let chunks = encode(r#"{"error":6}"#, MAX_CHUNK_BYTES);
+        assert_eq!(chunks[0][4], Encoding::Identity as u8);
+        assert_eq!(decode(&chunks).unwrap(), r#"{"error":6}"#);
+    }
+
+    #[test]
+    fn test_oversized_values_are_chunked() {
+        let body = large_body();
+        let chunks = encode(&body, 256);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 256));
+        assert_eq!(chunk_count(&chunks[0]), Some(chunks.len()));
+        assert_eq!(decode(&chunks).unwrap(), body);
+
+        // A missing chunk is an error rather than a truncated body
+        assert!(decode(&chunks[..chunks.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_legacy_plain_entries_decode_unchanged() {
+        let legacy = br#"{"artist":{"name":"Cher"}}"#.to_vec();
+        assert_eq!(chunk_count(&legacy), None);
+        assert_eq!(decode(&[legacy]).unwrap(), r#"{"artist":{"name":"Cher"}}"#);
+    }
+
+    #[test]
+    fn test_chunk_keys_change_with_the_value() {
+        let key = "lastfm:user.getRecentTracks:user=rj";
+        let first = encode(&large_body(), 256);
+        let second = encode(&large_body().replace("Radiohead", "Portishead"), 256);
+
+        let name = chunk_key(key, &first[0], 2);
+        assert!(name.starts_with("lastfm:user.getRecentTracks:user=rj:chunk:"));
+        assert!(name.ends_with(":2"));
+        assert_eq!(name, chunk_key(key, &encode(&large_body(), 256)[0], 2));
+        assert_ne!(name, chunk_key(key, &second[0], 2));
+    }
+}
@@ -21,6 +21,9 @@ pub struct AuthManager {
 pub struct Session {
     pub username: String,
     pub key: String,
+    /// Worker API key the session was issued for, sent back as `key_id`
+    #[serde(default)]
+    pub key_id: Option<String>,
 }
 
 /// Auth configuration stored in config file
@@ -28,6 +31,8 @@ pub struct Session {
 pub struct AuthConfig {
     pub username: Option<String>,
     pub session_key: Option<String>,
+    #[serde(default)]
+    pub key_id: Option<String>,
 }
 
 impl AuthManager {
@@ -44,7 +49,11 @@ impl AuthManager {
         let config = self.config_manager.load().await?;
 
         match (config.auth.username, config.auth.session_key) {
-            (Some(username), Some(key)) => Ok(Some(Session { username, key })),
+            (Some(username), Some(key)) => Ok(Some(Session {
+                username,
+                key,
+                key_id: config.auth.key_id,
+            })),
             _ => Ok(None),
         }
     }
@@ -54,8 +63,9 @@ impl AuthManager {
         Ok(self.get_session().await?.is_some())
     }
 
-    /// Generate auth URL for user to authorize the application
-    pub async fn generate_auth_url(&self) -> Result<String> {
+    /// Generate auth URL for user to authorize the application, with the id
+    /// of the worker API key the resulting token is bound to
+    pub async fn generate_auth_url(&self) -> Result<(String, Option<String>)> {
         // Get the auth URL from the worker which has the API key
         let api_client: &dyn ApiClient = &self.api_client;
         let data = api_client.get("/auth/url", &HashMap::new()).await?;
@@ -65,19 +75,53 @@ impl AuthManager {
             .and_then(|u| u.as_str())
             .ok_or_else(|| CliError::api("Invalid response: missing auth_url"))?
             .to_string();
+        let key_id = data
+            .get("key_id")
+            .and_then(|k| k.as_str())
+            .map(str::to_string);
 
-        Ok(auth_url)
+        Ok((auth_url, key_id))
     }
 
-    /// Get session from auth token
-    pub async fn get_session_from_token(&self, token: &str) -> Result<Session> {
+    /// Get session from auth token; `key_id` is the key from `generate_auth_url`
+    pub async fn get_session_from_token(
+        &self,
+        token: &str,
+        key_id: Option<&str>,
+    ) -> Result<Session> {
         let mut params = HashMap::new();
         params.insert("token".to_string(), token.to_string());
+        if let Some(key_id) = key_id {
+            params.insert("key_id".to_string(), key_id.to_string());
+        }
 
         // Make the request through the worker endpoint which will handle the API key
         let api_client: &dyn ApiClient = &self.api_client;
         let data = api_client.get("/auth/getSession", &params).await?;
 
+        Self::parse_session(&data)
+    }
+
+    /// Get session from username and password (auth.getMobileSession).
+    /// Credentials are sent in a POST body so they never appear in URLs.
+    pub async fn get_session_from_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Session> {
+        let body = serde_json::json!({
+            "username": username,
+            "password": password,
+        });
+
+        let api_client: &dyn ApiClient = &self.api_client;
+        let data = api_client.post("/auth/getMobileSession", &body).await?;
+
+        Self::parse_session(&data)
+    }
+
+    /// Parse a session from an auth.getSession / auth.getMobileSession response
+    fn parse_session(data: &serde_json::Value) -> Result<Session> {
         if let Some(_error) = data.get("error") {
             let message = data
                 .get("message")
@@ -102,13 +146,22 @@ impl AuthManager {
             .ok_or_else(|| CliError::api("Invalid response: missing session key"))?
             .to_string();
 
-        Ok(Session { username, key })
+        let key_id = session
+            .get("key_id")
+            .and_then(|k| k.as_str())
+            .map(str::to_string);
+
+        Ok(Session {
+            username,
+            key,
+            key_id,
+        })
     }
 
     /// Start the authentication flow
     pub async fn login(&self) -> Result<Session> {
         // Generate auth URL from worker
-        let auth_url = self.generate_auth_url().await?;
+        let (auth_url, key_id) = self.generate_auth_url().await?;
 
         println!("Opening browser for authorization...");
         println!("If the browser doesn't open, visit this URL:");
@@ -128,7 +181,40 @@ impl AuthManager {
             .map_err(|e| CliError::other(format!("Failed to read input: {e}")))?;
 
         // Get session from token
-        let session = self.get_session_from_token(&token).await?;
+        let session = self
+            .get_session_from_token(&token, key_id.as_deref())
+            .await?;
+
+        // Save session to config
+        self.save_session(&session).await?;
+
+        println!("✓ Successfully authenticated as '{}'", session.username);
+
+        Ok(session)
+    }
+
+    /// Authenticate with username and password, for headless servers without a browser
+    pub async fn login_with_password(&self, username: Option<String>) -> Result<Session> {
+        use dialoguer::{Input, Password};
+
+        let username = match username {
+            Some(username) => username,
+            None => Input::new()
+                .with_prompt("Username")
+                .interact_text()
+                .map_err(|e| CliError::other(format!("Failed to read input: {e}")))?,
+        };
+
+        // Allow non-interactive use in scripts
+        let password = match std::env::var("LASTFM_PASSWORD") {
+            Ok(password) if !password.is_empty() => password,
+            _ => Password::new()
+                .with_prompt("Password")
+                .interact()
+                .map_err(|e| CliError::other(format!("Failed to read input: {e}")))?,
+        };
+
+        let session = self.get_session_from_password(&username, &password).await?;
 
         // Save session to config
         self.save_session(&session).await?;
@@ -143,6 +229,7 @@ impl AuthManager {
         let mut config = self.config_manager.load().await?;
         config.auth.username = Some(session.username.clone());
         config.auth.session_key = Some(session.key.clone());
+        config.auth.key_id = session.key_id.clone();
         self.config_manager.save(&config).await?;
         Ok(())
     }
@@ -152,6 +239,7 @@ impl AuthManager {
         let mut config = self.config_manager.load().await?;
         config.auth.username = None;
         config.auth.session_key = None;
+        config.auth.key_id = None;
         self.config_manager.save(&config).await?;
         println!("Successfully logged out");
         Ok(())
@@ -161,6 +249,9 @@ impl AuthManager {
     pub async fn add_auth_params(&self, params: &mut HashMap<String, String>) -> Result<()> {
         if let Some(session) = self.get_session().await? {
             params.insert("sk".to_string(), session.key);
+            if let Some(key_id) = session.key_id {
+                params.insert("key_id".to_string(), key_id);
+            }
             // Don't sign locally - the worker will handle signing for authenticated requests
         }
 
@@ -11,7 +11,7 @@ use crate::cli::{
     traits::{ApiClient, Command, CommandArgs, CommandOutput, OutputMetadata},
 };
 
-use super::BaseCommand;
+use super::{get_flag, BaseCommand};
 
 /// Auth login command
 pub struct AuthLoginCommand {
@@ -42,7 +42,7 @@ impl Command for AuthLoginCommand {
         Ok(())
     }
 
-    async fn execute(&self, _args: &CommandArgs) -> Result<CommandOutput> {
+    async fn execute(&self, args: &CommandArgs) -> Result<CommandOutput> {
         // Get API client
         let api_client = match self
             .base
@@ -61,8 +61,14 @@ impl Command for AuthLoginCommand {
         // Create auth manager
         let auth_manager = AuthManager::new(api_client, self.config_manager.clone());
 
-        // Start login flow
-        let session = auth_manager.login().await?;
+        // Start login flow (password flow for headless servers)
+        let session = if get_flag(args, "password") {
+            auth_manager
+                .login_with_password(args.named.get("username").cloned())
+                .await?
+        } else {
+            auth_manager.login().await?
+        };
 
         Ok(CommandOutput {
             data: serde_json::json!({
@@ -9,6 +9,7 @@ pub mod config;
 pub mod geo;
 pub mod library;
 pub mod my;
+pub mod search;
 pub mod tag;
 pub mod track;
 pub mod user;
@@ -163,6 +164,9 @@ impl CommandRegistry {
             api_client.clone(),
         )));
 
+        // Register unified search
+        registry.register(Box::new(search::SearchCommand::new(api_client.clone())));
+
         registry
     }
 
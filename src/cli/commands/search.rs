@@ -0,0 +1,63 @@
+// Unified search command implementation
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::cli::{
+    error::{CliError, Result},
+    traits::{ApiClient, Command, CommandArgs, CommandOutput},
+};
+
+use super::{get_required_arg, BaseCommand};
+
+/// Search artists, albums and tracks at once
+pub struct SearchCommand {
+    base: BaseCommand,
+}
+
+impl SearchCommand {
+    pub fn new(api_client: Arc<dyn ApiClient>) -> Self {
+        Self {
+            base: BaseCommand::new(
+                "search",
+                "Search artists, albums and tracks in one ranked list",
+                api_client,
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for SearchCommand {
+    async fn execute(&self, args: &CommandArgs) -> Result<CommandOutput> {
+        let mut params = HashMap::new();
+
+        let query = get_required_arg(args, "query")?;
+        params.insert("q".to_string(), query);
+
+        // Per-type limits override the shared one; the worker applies defaults
+        for name in ["limit", "artist_limit", "album_limit", "track_limit"] {
+            if let Some(value) = args.named.get(name) {
+                params.insert(name.to_string(), value.clone());
+            }
+        }
+
+        self.base.execute_api_call("/search", params).await
+    }
+
+    fn name(&self) -> &str {
+        &self.base.name
+    }
+
+    fn description(&self) -> &str {
+        &self.base.description
+    }
+
+    fn validate_args(&self, args: &CommandArgs) -> Result<()> {
+        if args.positional.is_empty() && !args.named.contains_key("query") {
+            return Err(CliError::missing_argument("query"));
+        }
+        Ok(())
+    }
+}
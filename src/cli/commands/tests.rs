@@ -263,6 +263,8 @@ mod command_tests {
         assert!(registry.get("user.top-artists").is_some());
 
         assert!(registry.get("library.artists").is_some());
+
+        assert!(registry.get("search").is_some());
     }
 
     #[test]
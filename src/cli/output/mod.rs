@@ -155,6 +155,9 @@ fn format_value_as_table(value: &Value) -> Option<String> {
                     return format_search_tracks_table(track_matches);
                 }
             }
+            if let Some(search) = map.get("search") {
+                return format_unified_search_table(search);
+            }
 
             // Special handling for different response types
             if map.contains_key("artist") {
@@ -436,6 +439,28 @@ fn format_search_tracks_table(matches: &Value) -> Option<String> {
     Some(table.to_string())
 }
 
+/// Format unified search results (artists, albums and tracks) as a table
+fn format_unified_search_table(search: &Value) -> Option<String> {
+    let results = search.get("result")?.as_array()?;
+    let mut table = Table::new();
+    table.add_row(row!["#", "Type", "Name", "Artist", "Listeners"]);
+
+    for (i, result) in results.iter().enumerate() {
+        let kind = result.get("type").and_then(|t| t.as_str()).unwrap_or("");
+        let name = result.get("name").and_then(|n| n.as_str()).unwrap_or("");
+        let artist = result.get("artist").and_then(|a| a.as_str()).unwrap_or("");
+        let listeners = result
+            .get("listeners")
+            .and_then(|l| l.as_i64())
+            .map(|l| l.to_string())
+            .unwrap_or_default();
+
+        table.add_row(row![i + 1, kind, name, artist, listeners]);
+    }
+
+    Some(table.to_string())
+}
+
 /// Format top artists response as a table
 fn format_top_artists_table(data: &Value) -> Option<String> {
     let artists = data.get("artist")?.as_array()?;
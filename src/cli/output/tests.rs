@@ -171,6 +171,25 @@ mod output_tests {
         assert!(result.unwrap().contains("Test Artist"));
     }
 
+    #[test]
+    fn test_format_value_as_table_with_unified_search() {
+        let search = json!({
+            "search": {
+                "result": [
+                    {"type": "artist", "name": "Radiohead", "artist": null, "listeners": 5000000},
+                    {"type": "album", "name": "OK Computer", "artist": "Radiohead", "listeners": null}
+                ],
+                "@attr": {"query": "radiohead"}
+            }
+        });
+
+        let table = format_value_as_table(&search).unwrap();
+        assert!(table.contains("Type"));
+        assert!(table.contains("5000000"));
+        assert!(table.contains("OK Computer"));
+        assert!(table.contains("album"));
+    }
+
     #[test]
     fn test_format_generic_object_table() {
         let mut map = serde_json::Map::new();
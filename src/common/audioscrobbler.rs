@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+
+/// Most tracks one 1.2 submission may carry
+pub const MAX_SUBMISSION_TRACKS: usize = 50;
+
+/// How far a handshake timestamp may drift from our clock before BADTIME
+pub const MAX_CLOCK_SKEW_SECS: i64 = 60 * 60;
+
+const PROTOCOL_VERSIONS: &[&str] = &["1.2", "1.2.1"];
+
+/// The protocol's plain-text status replies
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Ok,
+    BadAuth,
+    BadTime,
+    BadSession,
+    Failed(String),
+}
+
+impl Reply {
+    pub fn failed(reason: impl Into<String>) -> Self {
+        Self::Failed(reason.into())
+    }
+
+    /// The response body, newline terminated
+    pub fn body(&self) -> String {
+        match self {
+            Self::Ok => "OK\n".to_string(),
+            Self::BadAuth => "BADAUTH\n".to_string(),
+            Self::BadTime => "BADTIME\n".to_string(),
+            Self::BadSession => "BADSESSION\n".to_string(),
+            Self::Failed(reason) => format!("FAILED {}\n", reason.replace('\n', " ")),
+        }
+    }
+}
+
+/// Successful handshake: the session id followed by the now-playing and submission URLs
+pub fn handshake_ok(session: &str, now_playing_url: &str, submission_url: &str) -> String {
+    format!("OK\n{session}\n{now_playing_url}\n{submission_url}\n")
+}
+
+/// Lowercase hex MD5, as used throughout the protocol
+pub fn md5_hex(value: &str) -> String {
+    format!("{:x}", md5::compute(value.as_bytes()))
+}
+
+/// The `a` token of a standard handshake: md5(md5(password) + timestamp)
+pub fn handshake_token(password_md5: &str, timestamp: i64) -> String {
+    md5_hex(&format!("{password_md5}{timestamp}"))
+}
+
+/// A `?hs=true` handshake request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub user: String,
+    pub timestamp: i64,
+    pub auth: String,
+    pub client: String,
+}
+
+impl Handshake {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, Reply> {
+        let get = |key: &str| params.get(key).map(String::as_str).unwrap_or_default();
+
+        if get("hs") != "true" {
+            return Err(Reply::failed("Not a handshake request"));
+        }
+        if !PROTOCOL_VERSIONS.contains(&get("p")) {
+            return Err(Reply::failed("Unsupported protocol version"));
+        }
+        if get("u").is_empty() || get("a").is_empty() {
+            return Err(Reply::BadAuth);
+        }
+        let timestamp = get("t")
+            .parse()
+            .map_err(|_| Reply::failed("Invalid timestamp"))?;
+
+        Ok(Self {
+            user: get("u").to_string(),
+            timestamp,
+            auth: get("a").to_ascii_lowercase(),
+            client: get("c").to_string(),
+        })
+    }
+
+    pub fn is_timely(&self, now: i64) -> bool {
+        (now - self.timestamp).abs() <= MAX_CLOCK_SKEW_SECS
+    }
+
+    /// Whether `auth` was derived from a password with this MD5
+    pub fn verifies(&self, password_md5: &str) -> bool {
+        handshake_token(password_md5, self.timestamp) == self.auth
+    }
+}
+
+fn copy_nonempty(
+    from: &HashMap<String, String>,
+    to: &mut HashMap<String, String>,
+    source: &str,
+    target: &str,
+) {
+    if let Some(value) = from.get(source).filter(|v| !v.is_empty()) {
+        to.insert(target.to_string(), value.clone());
+    }
+}
+
+/// `track.updateNowPlaying` parameters from a 1.2 now-playing notification
+pub fn now_playing_params(
+    params: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, String> {
+    let mut mapped = HashMap::new();
+    for (source, target) in [
+        ("a", "artist"),
+        ("t", "track"),
+        ("b", "album"),
+        ("l", "duration"),
+        ("n", "trackNumber"),
+        ("m", "mbid"),
+    ] {
+        copy_nonempty(params, &mut mapped, source, target);
+    }
+
+    if !mapped.contains_key("artist") || !mapped.contains_key("track") {
+        return Err("Artist and track are required".to_string());
+    }
+    Ok(mapped)
+}
+
+/// `track.scrobble` batch parameters from a 1.2 submission's `a[0]`, `t[0]`, ... fields
+pub fn submission_params(
+    params: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, String> {
+    let mut mapped = HashMap::new();
+    let mut index = 0;
+
+    while params.contains_key(&format!("a[{index}]")) {
+        if index == MAX_SUBMISSION_TRACKS {
+            return Err(format!(
+                "At most {MAX_SUBMISSION_TRACKS} tracks per submission"
+            ));
+        }
+        let field = |name: &str| format!("{name}[{index}]");
+
+        for (source, target) in [
+            ("a", "artist"),
+            ("t", "track"),
+            ("i", "timestamp"),
+            ("b", "album"),
+            ("l", "duration"),
+            ("n", "trackNumber"),
+            ("m", "mbid"),
+        ] {
+            copy_nonempty(params, &mut mapped, &field(source), &field(target));
+        }
+        for required in ["artist", "track", "timestamp"] {
+            if !mapped.contains_key(&field(required)) {
+                return Err(format!("Track {index} is missing its {required}"));
+            }
+        }
+        if mapped[&field("timestamp")].parse::<u64>().is_err() {
+            return Err(format!("Track {index} has an invalid timestamp"));
+        }
+
+        // Source P is the user's own choice; radio and recommendations are not
+        match params.get(&field("o")).map(String::as_str) {
+            Some("P") => {
+                mapped.insert(field("chosenByUser"), "1".to_string());
+            }
+            Some("R" | "E" | "L") => {
+                mapped.insert(field("chosenByUser"), "0".to_string());
+            }
+            _ => {}
+        }
+
+        index += 1;
+    }
+
+    if index == 0 {
+        return Err("No tracks submitted".to_string());
+    }
+    Ok(mapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_handshake_verification() {
+        let password_md5 = md5_hex("lfpt_secret");
+        let auth = handshake_token(&password_md5, 1_700_000_000);
+        let handshake = Handshake::from_params(&params(&[
+            ("hs", "true"),
+            ("p", "1.2.1"),
+            ("c", "tst"),
+            ("u", "rj"),
+            ("t", "1700000000"),
+            ("a", &auth.to_uppercase()),
+        ]))
+        .unwrap();
+
+        assert!(handshake.verifies(&password_md5));
+        assert!(!handshake.verifies(&md5_hex("wrong")));
+        assert!(handshake.is_timely(1_700_000_000 + MAX_CLOCK_SKEW_SECS));
+        assert!(!handshake.is_timely(1_700_000_000 - MAX_CLOCK_SKEW_SECS - 1));
+    }
+
+    #[test]
+    fn test_handshake_rejections() {
+        let base = [
+            ("hs", "true"),
+            ("p", "1.2"),
+            ("u", "rj"),
+            ("t", "1"),
+            ("a", "x"),
+        ];
+        assert!(Handshake::from_params(&params(&base)).is_ok());
+
+        let mut old = params(&base);
+        old.insert("p".to_string(), "1.1".to_string());
+        assert!(matches!(
+            Handshake::from_params(&old),
+            Err(Reply::Failed(_))
+        ));
+
+        let mut anonymous = params(&base);
+        anonymous.remove("u");
+        assert_eq!(Handshake::from_params(&anonymous), Err(Reply::BadAuth));
+    }
+
+    #[test]
+    fn test_now_playing_mapping() {
+        let mapped = now_playing_params(&params(&[
+            ("s", "session"),
+            ("a", "Radiohead"),
+            ("t", "Airbag"),
+            ("b", ""),
+            ("l", "284"),
+        ]))
+        .unwrap();
+
+        assert_eq!(mapped["artist"], "Radiohead");
+        assert_eq!(mapped["track"], "Airbag");
+        assert_eq!(mapped["duration"], "284");
+        assert!(!mapped.contains_key("album"));
+        assert!(!mapped.contains_key("s"));
+        assert!(now_playing_params(&params(&[("a", "Radiohead")])).is_err());
+    }
+
+    #[test]
+    fn test_submission_mapping() {
+        let mapped = submission_params(&params(&[
+            ("a[0]", "Radiohead"),
+            ("t[0]", "Airbag"),
+            ("i[0]", "1700000000"),
+            ("o[0]", "P"),
+            ("l[0]", "284"),
+            ("a[1]", "Cher"),
+            ("t[1]", "Believe"),
+            ("i[1]", "1700000300"),
+            ("o[1]", "R"),
+        ]))
+        .unwrap();
+
+        assert_eq!(mapped["artist[0]"], "Radiohead");
+        assert_eq!(mapped["timestamp[1]"], "1700000300");
+        assert_eq!(mapped["duration[0]"], "284");
+        assert_eq!(mapped["chosenByUser[0]"], "1");
+        assert_eq!(mapped["chosenByUser[1]"], "0");
+        assert!(!mapped.contains_key("o[0]"));
+    }
+
+    #[test]
+    fn test_submission_validation() {
+        assert!(submission_params(&params(&[])).is_err());
+        assert!(submission_params(&params(&[("a[0]", "Cher"), ("t[0]", "Believe")])).is_err());
+        assert!(submission_params(&params(&[
+            ("a[0]", "Cher"),
+            ("t[0]", "Believe"),
+            ("i[0]", "yesterday")
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_replies() {
+        assert_eq!(Reply::Ok.body(), "OK\n");
+        assert_eq!(Reply::BadSession.body(), "BADSESSION\n");
+        assert_eq!(
+            Reply::failed("Upstream\nerror").body(),
+            "FAILED Upstream error\n"
+        );
+        assert_eq!(
+            handshake_ok("abc", "https://np", "https://sub"),
+            "OK\nabc\nhttps://np\nhttps://sub\n"
+        );
+    }
+}
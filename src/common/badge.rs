@@ -0,0 +1,323 @@
+use super::format::escape_xml;
+use super::nowplaying::ListeningState;
+use std::collections::HashMap;
+
+/// Most artists a top-artists badge lists
+pub const MAX_BADGE_COUNT: usize = 10;
+const DEFAULT_BADGE_COUNT: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BadgeKind {
+    NowPlaying,
+    TopArtists,
+}
+
+/// Parse a badge name such as `nowplaying.svg`
+pub fn parse_badge_name(name: &str) -> Option<BadgeKind> {
+    match name {
+        "nowplaying.svg" => Some(BadgeKind::NowPlaying),
+        "top-artists.svg" => Some(BadgeKind::TopArtists),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+struct Palette {
+    background: &'static str,
+    border: &'static str,
+    accent: &'static str,
+    text: &'static str,
+    muted: &'static str,
+}
+
+impl Theme {
+    fn palette(&self) -> Palette {
+        match self {
+            Self::Light => Palette {
+                background: "#ffffff",
+                border: "#e4e2e2",
+                accent: "#d51007",
+                text: "#222222",
+                muted: "#6f6f6f",
+            },
+            Self::Dark => Palette {
+                background: "#1b1b1f",
+                border: "#34343a",
+                accent: "#ff4b3e",
+                text: "#f0f0f0",
+                muted: "#a0a0a8",
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    Small,
+    Medium,
+    Large,
+}
+
+impl Size {
+    fn scale(&self) -> f32 {
+        match self {
+            Self::Small => 0.8,
+            Self::Medium => 1.0,
+            Self::Large => 1.25,
+        }
+    }
+}
+
+/// `theme`, `size` and `count` query options; unknown values fall back to defaults
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BadgeOptions {
+    pub theme: Theme,
+    pub size: Size,
+    pub count: usize,
+}
+
+impl BadgeOptions {
+    pub fn from_params(params: &HashMap<String, String>) -> Self {
+        let theme = match params.get("theme").map(String::as_str) {
+            Some("dark") => Theme::Dark,
+            _ => Theme::Light,
+        };
+        let size = match params.get("size").map(String::as_str) {
+            Some("small") => Size::Small,
+            Some("large") => Size::Large,
+            _ => Size::Medium,
+        };
+        let count = params
+            .get("count")
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(DEFAULT_BADGE_COUNT)
+            .clamp(1, MAX_BADGE_COUNT);
+
+        Self { theme, size, count }
+    }
+}
+
+enum Line {
+    Primary(String),
+    Secondary(String),
+}
+
+// Shorten text to fit `max_chars`, ending with an ellipsis when cut
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    format!("{}…", kept.trim_end())
+}
+
+// A card with an accent heading and one text line per row
+fn render_card(heading: &str, lines: &[Line], options: &BadgeOptions) -> String {
+    let palette = options.theme.palette();
+    let scale = options.size.scale();
+    let px = |value: f32| (value * scale).round() as u32;
+
+    let width = px(400.0);
+    let padding = px(16.0);
+    let heading_size = px(13.0);
+    let line_size = px(14.0);
+    let line_height = px(22.0);
+    let height = padding * 2 + px(18.0) + line_height * lines.len() as u32;
+    // Rough average glyph width for the sans-serif stack below
+    let max_chars = ((width - padding * 2) as f32 / (line_size as f32 * 0.58)) as usize;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+         viewBox=\"0 0 {width} {height}\" role=\"img\" aria-label=\"{label}\">\n\
+         <title>{label}</title>\n\
+         <rect x=\"0.5\" y=\"0.5\" width=\"{w}\" height=\"{h}\" rx=\"6\" fill=\"{bg}\" stroke=\"{border}\"/>\n\
+         <g font-family=\"-apple-system, 'Segoe UI', Helvetica, Arial, sans-serif\">\n\
+         <text x=\"{padding}\" y=\"{heading_y}\" font-size=\"{heading_size}\" font-weight=\"600\" fill=\"{accent}\">{heading}</text>\n",
+        label = escape_xml(heading),
+        w = width - 1,
+        h = height - 1,
+        bg = palette.background,
+        border = palette.border,
+        accent = palette.accent,
+        heading_y = padding + heading_size,
+        heading = escape_xml(&truncate(heading, max_chars)),
+    );
+
+    for (index, line) in lines.iter().enumerate() {
+        let y = padding + px(18.0) + line_height * (index as u32 + 1) - px(6.0);
+        let (text, fill, weight) = match line {
+            Line::Primary(text) => (text, palette.text, "600"),
+            Line::Secondary(text) => (text, palette.muted, "400"),
+        };
+        svg.push_str(&format!(
+            "<text x=\"{padding}\" y=\"{y}\" font-size=\"{line_size}\" font-weight=\"{weight}\" fill=\"{fill}\">{}</text>\n",
+            escape_xml(&truncate(text, max_chars))
+        ));
+    }
+
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+/// What a user is playing, or what they played last
+pub fn now_playing_badge(user: &str, state: &ListeningState, options: &BadgeOptions) -> String {
+    let (heading, track) = match (&state.now_playing, &state.last_scrobble) {
+        (Some(track), _) => (format!("♫ {user} is listening to"), Some(track)),
+        (None, Some(track)) => (format!("♫ {user} last played"), Some(track)),
+        (None, None) => (format!("♫ {user}"), None),
+    };
+
+    let lines = match track {
+        Some(track) => {
+            let by = if track.album.is_empty() {
+                track.artist.clone()
+            } else {
+                format!("{} — {}", track.artist, track.album)
+            };
+            vec![Line::Primary(track.name.clone()), Line::Secondary(by)]
+        }
+        None => vec![Line::Secondary("Nothing played yet".to_string())],
+    };
+
+    render_card(&heading, &lines, options)
+}
+
+fn period_label(period: &str) -> &'static str {
+    match period {
+        "7day" => "last 7 days",
+        "1month" => "last month",
+        "3month" => "last 3 months",
+        "6month" => "last 6 months",
+        "12month" => "last year",
+        _ => "all time",
+    }
+}
+
+/// Card listing the first `options.count` of ranked `(artist, playcount)` pairs
+pub fn top_artists_badge(
+    user: &str,
+    period: &str,
+    artists: &[(String, String)],
+    options: &BadgeOptions,
+) -> String {
+    let heading = format!("♫ {user}'s top artists · {}", period_label(period));
+    let mut lines: Vec<Line> = artists
+        .iter()
+        .take(options.count)
+        .enumerate()
+        .map(|(rank, (name, plays))| Line::Primary(format!("{}. {name} · {plays} plays", rank + 1)))
+        .collect();
+    if lines.is_empty() {
+        lines.push(Line::Secondary("No scrobbles in this period".to_string()));
+    }
+
+    render_card(&heading, &lines, options)
+}
+
+/// Stand-in card when the data can't be shown, so embeds never break
+pub fn placeholder_badge(user: &str, message: &str, options: &BadgeOptions) -> String {
+    render_card(
+        &format!("♫ {user}"),
+        &[Line::Secondary(message.to_string())],
+        options,
+    )
+}
+
+/// Placeholder text for a Last.fm error code
+pub fn placeholder_message(code: u32) -> &'static str {
+    match code {
+        6 => "User not found",
+        17 => "This profile is private",
+        _ => "Listening data is unavailable right now",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::nowplaying::RecentTrack;
+
+    fn options(pairs: &[(&str, &str)]) -> BadgeOptions {
+        let params = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        BadgeOptions::from_params(&params)
+    }
+
+    #[test]
+    fn test_options() {
+        let defaults = options(&[]);
+        assert_eq!(defaults.theme, Theme::Light);
+        assert_eq!(defaults.size, Size::Medium);
+        assert_eq!(defaults.count, DEFAULT_BADGE_COUNT);
+
+        let custom = options(&[("theme", "dark"), ("size", "large"), ("count", "50")]);
+        assert_eq!(custom.theme, Theme::Dark);
+        assert_eq!(custom.size, Size::Large);
+        assert_eq!(custom.count, MAX_BADGE_COUNT);
+        assert_eq!(options(&[("count", "0")]).count, 1);
+    }
+
+    #[test]
+    fn test_now_playing_badge_escapes_text() {
+        let state = ListeningState {
+            now_playing: Some(RecentTrack {
+                artist: "Simon & Garfunkel".to_string(),
+                name: "The <Boxer>".to_string(),
+                album: String::new(),
+                url: String::new(),
+                timestamp: None,
+            }),
+            last_scrobble: None,
+        };
+        let svg = now_playing_badge("rj", &state, &options(&[]));
+
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"400\""));
+        assert!(svg.contains("rj is listening to"));
+        assert!(svg.contains("The &lt;Boxer&gt;"));
+        assert!(svg.contains("Simon &amp; Garfunkel"));
+        assert!(!svg.contains("<Boxer>"));
+    }
+
+    #[test]
+    fn test_top_artists_badge_respects_count_and_theme() {
+        let artists: Vec<(String, String)> = (1..=8)
+            .map(|i| (format!("Artist {i}"), (100 - i).to_string()))
+            .collect();
+        let svg = top_artists_badge(
+            "rj",
+            "7day",
+            &artists,
+            &options(&[("count", "3"), ("theme", "dark")]),
+        );
+
+        assert!(svg.contains("last 7 days"));
+        assert!(svg.contains("3. Artist 3 · 97 plays"));
+        assert!(!svg.contains("Artist 4"));
+        assert!(svg.contains("#1b1b1f"));
+    }
+
+    #[test]
+    fn test_long_text_is_truncated() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("a very long track title", 10), "a very lo…");
+    }
+
+    #[test]
+    fn test_placeholder() {
+        let svg = placeholder_badge("ghost", placeholder_message(6), &options(&[]));
+        assert!(svg.contains("User not found"));
+        assert_eq!(placeholder_message(17), "This profile is private");
+        assert_eq!(
+            parse_badge_name("nowplaying.svg"),
+            Some(BadgeKind::NowPlaying)
+        );
+        assert_eq!(parse_badge_name("top-artists.png"), None);
+    }
+}
@@ -0,0 +1,147 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Most sub-requests accepted in one batch
+pub const MAX_BATCH_ITEMS: usize = 20;
+
+/// Sub-requests in flight at once, to stay well inside the subrequest limit
+pub const MAX_CONCURRENT_ITEMS: usize = 5;
+
+/// Extra parameter a batch signature covers: the hex SHA-256 of the body
+pub const BODY_DIGEST_PARAM: &str = "body_sha256";
+
+/// Hex SHA-256 digest of a batch body, signed along with the query parameters
+pub fn body_digest(body: &str) -> String {
+    hex::encode(Sha256::digest(body.as_bytes()))
+}
+
+/// One `{method, params}` entry of a batch body
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BatchItem {
+    pub method: String,
+    #[serde(default)]
+    pub params: HashMap<String, Value>,
+}
+
+impl BatchItem {
+    /// Params as Last.fm query strings; numbers and booleans are accepted as-is
+    pub fn string_params(&self) -> HashMap<String, String> {
+        self.params
+            .iter()
+            .filter_map(|(key, value)| {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Number(n) => n.to_string(),
+                    Value::Bool(b) => b.to_string(),
+                    _ => return None,
+                };
+                Some((key.clone(), value))
+            })
+            .collect()
+    }
+}
+
+/// Parse a batch body: a JSON array of `{method, params}` objects
+pub fn parse_batch(body: &str) -> Result<Vec<BatchItem>, String> {
+    let items: Vec<BatchItem> = serde_json::from_str(body)
+        .map_err(|e| format!("Batch body must be an array of {{method, params}}: {e}"))?;
+
+    if items.is_empty() {
+        return Err("Batch must contain at least one request".to_string());
+    }
+    if items.len() > MAX_BATCH_ITEMS {
+        return Err(format!(
+            "Batch may contain at most {MAX_BATCH_ITEMS} requests"
+        ));
+    }
+
+    Ok(items)
+}
+
+/// Result entry for a sub-request that succeeded
+pub fn success_result(method: &str, cache_status: &str, response: Value) -> Value {
+    json!({
+        "method": method,
+        "status": 200,
+        "cache": cache_status,
+        "response": response,
+    })
+}
+
+/// Result entry for a sub-request that failed, with a Last.fm style error body
+pub fn error_result(method: &str, status: u16, code: u32, message: &str) -> Value {
+    json!({
+        "method": method,
+        "status": status,
+        "error": { "error": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_batch() {
+        let items = parse_batch(
+            r#"[
+                {"method": "chart.getTopArtists", "params": {"limit": 5}},
+                {"method": "user.getInfo", "params": {"user": "rj"}},
+                {"method": "chart.getTopTags"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].string_params()["limit"], "5");
+        assert_eq!(items[1].string_params()["user"], "rj");
+        assert!(items[2].params.is_empty());
+    }
+
+    #[test]
+    fn test_parse_batch_rejects_bad_bodies() {
+        assert!(parse_batch("{}").is_err());
+        assert!(parse_batch("[]").is_err());
+        assert!(parse_batch(r#"[{"params": {}}]"#).is_err());
+
+        let too_many = vec![r#"{"method": "chart.getTopTags"}"#; MAX_BATCH_ITEMS + 1];
+        let error = parse_batch(&format!("[{}]", too_many.join(","))).unwrap_err();
+        assert!(error.contains("at most"));
+    }
+
+    #[test]
+    fn test_nested_params_are_dropped() {
+        let items = parse_batch(
+            r#"[{"method": "artist.getInfo", "params": {"artist": "Cher", "x": {"y": 1}}}]"#,
+        )
+        .unwrap();
+        let params = items[0].string_params();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params["artist"], "Cher");
+    }
+
+    #[test]
+    fn test_body_digest() {
+        assert_eq!(
+            body_digest(""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_ne!(
+            body_digest(r#"[{"method": "chart.getTopTags"}]"#),
+            body_digest(r#"[{"method": "chart.getTopArtists"}]"#)
+        );
+    }
+
+    #[test]
+    fn test_result_entries() {
+        let ok = success_result("chart.getTopTags", "HIT", json!({"tags": {}}));
+        assert_eq!(ok["status"], 200);
+        assert_eq!(ok["cache"], "HIT");
+
+        let failed = error_result("artist.getInfo", 400, 6, "Artist not found");
+        assert_eq!(failed["error"]["error"], 6);
+        assert_eq!(failed["response"], Value::Null);
+    }
+}
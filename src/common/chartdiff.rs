@@ -0,0 +1,406 @@
+use super::format::find_list_items;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Earlier weeks checked for a re-entry, counting the previous week
+pub const REENTRY_LOOKBACK_WEEKS: usize = 4;
+
+/// Which weekly chart to compare
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChartType {
+    Artist,
+    Album,
+    Track,
+}
+
+impl ChartType {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "artist" => Some(Self::Artist),
+            "album" => Some(Self::Album),
+            "track" => Some(Self::Track),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Artist => "artist",
+            Self::Album => "album",
+            Self::Track => "track",
+        }
+    }
+
+    /// The Last.fm method for one week of this chart
+    pub fn method(&self) -> &'static str {
+        match self {
+            Self::Artist => "user.getWeeklyArtistChart",
+            Self::Album => "user.getWeeklyAlbumChart",
+            Self::Track => "user.getWeeklyTrackChart",
+        }
+    }
+}
+
+/// A `{from, to}` range from `user.getWeeklyChartList`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChartWeek {
+    pub from: i64,
+    pub to: i64,
+}
+
+// Numbers come back as strings from Last.fm, but accept either
+fn number<T: std::str::FromStr>(value: Option<&Value>) -> Option<T> {
+    match value? {
+        Value::String(s) => s.parse().ok(),
+        other => other.to_string().parse().ok(),
+    }
+}
+
+/// Ranges of a `user.getWeeklyChartList` response, oldest first
+pub fn chart_weeks(document: &Value) -> Vec<ChartWeek> {
+    let mut weeks: Vec<ChartWeek> = find_list_items(document)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|week| {
+            Some(ChartWeek {
+                from: number(week.get("from"))?,
+                to: number(week.get("to"))?,
+            })
+        })
+        .collect();
+    weeks.sort_by_key(|week| week.from);
+    weeks
+}
+
+/// The week starting at `from` (the latest when `None`) and the weeks before
+/// it, most recent first, up to `REENTRY_LOOKBACK_WEEKS`
+pub fn select_weeks(weeks: &[ChartWeek], from: Option<i64>) -> Option<(ChartWeek, Vec<ChartWeek>)> {
+    let index = match from {
+        Some(from) => weeks.iter().position(|week| week.from == from)?,
+        None => weeks.len().checked_sub(1)?,
+    };
+    let earlier = weeks[..index]
+        .iter()
+        .rev()
+        .take(REENTRY_LOOKBACK_WEEKS)
+        .copied()
+        .collect();
+    Some((weeks[index], earlier))
+}
+
+/// One row of a weekly chart
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChartEntry {
+    pub name: String,
+    /// The artist of an album or track
+    pub artist: Option<String>,
+    pub rank: u32,
+    pub playcount: u64,
+}
+
+impl ChartEntry {
+    // Identity across weeks; Last.fm's capitalisation can vary
+    fn key(&self) -> String {
+        format!(
+            "{}\u{1f}{}",
+            self.artist.as_deref().unwrap_or_default().to_lowercase(),
+            self.name.to_lowercase()
+        )
+    }
+
+    fn to_json(&self) -> Value {
+        let mut value = json!({ "name": self.name });
+        if let Some(artist) = &self.artist {
+            value["artist"] = artist.as_str().into();
+        }
+        value
+    }
+}
+
+/// Rows of a weekly artist, album or track chart
+pub fn chart_entries(document: &Value) -> Vec<ChartEntry> {
+    find_list_items(document)
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, item)| {
+            let name = item.get("name")?.as_str()?.to_string();
+            let artist = match item.get("artist") {
+                Some(Value::Object(artist)) => artist
+                    .get("#text")
+                    .or_else(|| artist.get("name"))
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                Some(Value::String(artist)) => Some(artist.clone()),
+                _ => None,
+            };
+            Some(ChartEntry {
+                name,
+                artist,
+                rank: number(item.get("@attr").and_then(|attr| attr.get("rank")))
+                    .unwrap_or(index as u32 + 1),
+                playcount: number(item.get("playcount")).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// How an entry moved since the previous week
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Movement {
+    New,
+    ReEntry,
+    Up(u32),
+    Down(u32),
+    Same,
+}
+
+impl Movement {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::New => "new",
+            Self::ReEntry => "re-entry",
+            Self::Up(_) => "up",
+            Self::Down(_) => "down",
+            Self::Same => "same",
+        }
+    }
+
+    // Positions gained, negative when lost
+    fn change(&self) -> Option<i64> {
+        match self {
+            Self::Up(n) => Some(*n as i64),
+            Self::Down(n) => Some(-(*n as i64)),
+            Self::Same => Some(0),
+            Self::New | Self::ReEntry => None,
+        }
+    }
+}
+
+/// This week's entry annotated against the previous week
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotated {
+    pub entry: ChartEntry,
+    pub previous: Option<ChartEntry>,
+    pub movement: Movement,
+}
+
+/// The week's entries with their movements, and the previous week's entries
+/// that dropped out. `earlier` holds the weeks before the previous one, so
+/// entries absent last week but seen there count as re-entries.
+pub fn diff(
+    current: &[ChartEntry],
+    previous: &[ChartEntry],
+    earlier: &[Vec<ChartEntry>],
+) -> (Vec<Annotated>, Vec<ChartEntry>) {
+    let previous_by_key: HashMap<String, &ChartEntry> =
+        previous.iter().map(|entry| (entry.key(), entry)).collect();
+    let seen_earlier: HashSet<String> = earlier.iter().flatten().map(ChartEntry::key).collect();
+
+    let annotated = current
+        .iter()
+        .map(|entry| {
+            let key = entry.key();
+            let previous = previous_by_key.get(&key).map(|entry| (*entry).clone());
+            let movement = match &previous {
+                Some(before) if entry.rank < before.rank => Movement::Up(before.rank - entry.rank),
+                Some(before) if entry.rank > before.rank => {
+                    Movement::Down(entry.rank - before.rank)
+                }
+                Some(_) => Movement::Same,
+                None if seen_earlier.contains(&key) => Movement::ReEntry,
+                None => Movement::New,
+            };
+            Annotated {
+                entry: entry.clone(),
+                previous,
+                movement,
+            }
+        })
+        .collect();
+
+    let current_keys: HashSet<String> = current.iter().map(ChartEntry::key).collect();
+    let dropped = previous
+        .iter()
+        .filter(|entry| !current_keys.contains(&entry.key()))
+        .cloned()
+        .collect();
+
+    (annotated, dropped)
+}
+
+/// Response body for a chart diff
+pub fn to_json(
+    user: &str,
+    chart: ChartType,
+    week: ChartWeek,
+    previous_week: Option<ChartWeek>,
+    annotated: &[Annotated],
+    dropped: &[ChartEntry],
+) -> Value {
+    let entries: Vec<Value> = annotated
+        .iter()
+        .map(|row| {
+            let mut value = row.entry.to_json();
+            value["rank"] = row.entry.rank.into();
+            value["playcount"] = row.entry.playcount.into();
+            value["movement"] = row.movement.label().into();
+            if let Some(change) = row.movement.change() {
+                value["rank_change"] = change.into();
+            }
+            if let Some(previous) = &row.previous {
+                value["previous_rank"] = previous.rank.into();
+                value["previous_playcount"] = previous.playcount.into();
+            }
+            let before = row.previous.as_ref().map_or(0, |p| p.playcount) as i64;
+            value["playcount_change"] = (row.entry.playcount as i64 - before).into();
+            value
+        })
+        .collect();
+
+    let dropped: Vec<Value> = dropped
+        .iter()
+        .map(|entry| {
+            let mut value = entry.to_json();
+            value["movement"] = "dropped".into();
+            value["previous_rank"] = entry.rank.into();
+            value["previous_playcount"] = entry.playcount.into();
+            value["playcount_change"] = (-(entry.playcount as i64)).into();
+            value
+        })
+        .collect();
+
+    let range =
+        |week: ChartWeek| json!({ "from": week.from.to_string(), "to": week.to.to_string() });
+    json!({
+        "weeklychartdiff": {
+            "entries": entries,
+            "dropped": dropped,
+            "@attr": {
+                "user": user,
+                "type": chart.as_str(),
+                "week": range(week),
+                "previous_week": previous_week.map(range),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, rank: u32, playcount: u64) -> ChartEntry {
+        ChartEntry {
+            name: name.to_string(),
+            artist: None,
+            rank,
+            playcount,
+        }
+    }
+
+    #[test]
+    fn test_chart_weeks_and_selection() {
+        let document = json!({"weeklychartlist": {"chart": [
+            {"#text": "", "from": "300", "to": "400"},
+            {"#text": "", "from": "100", "to": "200"},
+            {"#text": "", "from": "200", "to": "300"}
+        ], "@attr": {"user": "rj"}}});
+        let weeks = chart_weeks(&document);
+        assert_eq!(weeks[0], ChartWeek { from: 100, to: 200 });
+
+        let (latest, earlier) = select_weeks(&weeks, None).unwrap();
+        assert_eq!(latest.from, 300);
+        assert_eq!(
+            earlier.iter().map(|w| w.from).collect::<Vec<_>>(),
+            [200, 100]
+        );
+
+        let (chosen, earlier) = select_weeks(&weeks, Some(100)).unwrap();
+        assert_eq!(chosen.to, 200);
+        assert!(earlier.is_empty());
+        assert_eq!(select_weeks(&weeks, Some(150)), None);
+        assert_eq!(select_weeks(&[], None), None);
+    }
+
+    #[test]
+    fn test_chart_entries() {
+        let document = json!({"weeklytrackchart": {"track": [
+            {"name": "Airbag", "artist": {"#text": "Radiohead"}, "playcount": "12", "@attr": {"rank": "1"}},
+            {"name": "Believe", "artist": {"#text": "Cher"}, "playcount": "7"}
+        ]}});
+        let entries = chart_entries(&document);
+        assert_eq!(entries[0].artist.as_deref(), Some("Radiohead"));
+        assert_eq!(entries[0].playcount, 12);
+        assert_eq!(entries[1].rank, 2);
+    }
+
+    #[test]
+    fn test_movements() {
+        let previous = vec![entry("A", 1, 30), entry("B", 2, 20), entry("C", 3, 10)];
+        let current = vec![
+            entry("b", 1, 25),
+            entry("A", 2, 22),
+            entry("D", 3, 9),
+            entry("E", 4, 8),
+        ];
+        let earlier = vec![vec![entry("D", 5, 4)]];
+
+        let (annotated, dropped) = diff(&current, &previous, &earlier);
+        let movements: Vec<Movement> = annotated.iter().map(|row| row.movement).collect();
+        assert_eq!(
+            movements,
+            [
+                Movement::Up(1),
+                Movement::Down(1),
+                Movement::ReEntry,
+                Movement::New
+            ]
+        );
+        assert_eq!(annotated[0].previous.as_ref().unwrap().playcount, 20);
+        assert_eq!(dropped, [entry("C", 3, 10)]);
+    }
+
+    #[test]
+    fn test_same_name_different_artist() {
+        let mut by_cher = entry("Believe", 1, 5);
+        by_cher.artist = Some("Cher".to_string());
+        let mut by_other = entry("Believe", 1, 5);
+        by_other.artist = Some("Someone Else".to_string());
+
+        let (annotated, dropped) = diff(&[by_cher], &[by_other], &[]);
+        assert_eq!(annotated[0].movement, Movement::New);
+        assert_eq!(dropped.len(), 1);
+    }
+
+    #[test]
+    fn test_json_shape() {
+        let (annotated, dropped) = diff(
+            &[entry("A", 1, 30), entry("D", 2, 9)],
+            &[entry("A", 3, 10), entry("C", 1, 40)],
+            &[],
+        );
+        let week = ChartWeek { from: 200, to: 300 };
+        let body = to_json(
+            "rj",
+            ChartType::Artist,
+            week,
+            Some(ChartWeek { from: 100, to: 200 }),
+            &annotated,
+            &dropped,
+        );
+        let diff = &body["weeklychartdiff"];
+
+        assert_eq!(diff["entries"][0]["movement"], "up");
+        assert_eq!(diff["entries"][0]["rank_change"], 2);
+        assert_eq!(diff["entries"][0]["playcount_change"], 20);
+        assert_eq!(diff["entries"][1]["movement"], "new");
+        assert!(diff["entries"][1].get("rank_change").is_none());
+        assert_eq!(diff["dropped"][0]["playcount_change"], -40);
+        assert_eq!(diff["@attr"]["previous_week"]["from"], "100");
+        assert_eq!(
+            ChartType::parse("album").unwrap().method(),
+            "user.getWeeklyAlbumChart"
+        );
+    }
+}
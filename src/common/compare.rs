@@ -0,0 +1,280 @@
+use super::format::find_list_items;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+// Artists carry most of the score; tags smooth over different artists in
+// the same genres
+const ARTIST_WEIGHT: f64 = 0.7;
+const TAG_WEIGHT: f64 = 0.3;
+
+/// Entries listed per section of a comparison
+pub const MAX_LISTED: usize = 10;
+
+/// Ranked `(name, weight)` pairs from a top list, e.g. artists by `playcount`
+/// or tags by `count`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub entries: Vec<(String, f64)>,
+}
+
+impl Profile {
+    pub fn from_document(document: &Value, weight_key: &str) -> Self {
+        let entries = find_list_items(document)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|item| {
+                let name = item.get("name")?.as_str()?.to_string();
+                let weight = match item.get(weight_key)? {
+                    Value::String(s) => s.parse().ok()?,
+                    other => other.as_f64()?,
+                };
+                (!name.is_empty()).then_some((name, weight))
+            })
+            .collect();
+        Self { entries }
+    }
+
+    // Weights keyed by lowercase name
+    fn weights(&self) -> HashMap<String, f64> {
+        let mut weights = HashMap::new();
+        for (name, weight) in &self.entries {
+            *weights.entry(name.to_lowercase()).or_insert(0.0) += weight;
+        }
+        weights
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Cosine similarity of two weighted profiles, 0..=1
+pub fn cosine(a: &Profile, b: &Profile) -> f64 {
+    let (a, b) = (a.weights(), b.weights());
+    let dot: f64 = a
+        .iter()
+        .filter_map(|(name, x)| b.get(name).map(|y| x * y))
+        .sum();
+    let norm = |weights: &HashMap<String, f64>| weights.values().map(|w| w * w).sum::<f64>().sqrt();
+    let denominator = norm(&a) * norm(&b);
+    if denominator == 0.0 {
+        0.0
+    } else {
+        (dot / denominator).clamp(0.0, 1.0)
+    }
+}
+
+/// Share of names in either profile that are in both (Jaccard index)
+pub fn overlap(a: &Profile, b: &Profile) -> f64 {
+    let a: HashSet<String> = a.weights().into_keys().collect();
+    let b: HashSet<String> = b.weights().into_keys().collect();
+    let union = a.union(&b).count();
+    if union == 0 {
+        0.0
+    } else {
+        a.intersection(&b).count() as f64 / union as f64
+    }
+}
+
+/// Artist similarity weighted with tag similarity; artists alone when either
+/// user has no tags
+pub fn score(artists: (&Profile, &Profile), tags: (&Profile, &Profile)) -> f64 {
+    let artist_score = cosine(artists.0, artists.1);
+    if tags.0.is_empty() || tags.1.is_empty() {
+        return artist_score;
+    }
+    ARTIST_WEIGHT * artist_score + TAG_WEIGHT * cosine(tags.0, tags.1)
+}
+
+/// The old Tasteometer's wording for a score
+pub fn level(score: f64) -> &'static str {
+    match score {
+        s if s >= 0.9 => "Super",
+        s if s >= 0.7 => "Very High",
+        s if s >= 0.5 => "High",
+        s if s >= 0.3 => "Medium",
+        s if s >= 0.1 => "Low",
+        _ => "Very Low",
+    }
+}
+
+/// Entries in both profiles as `(name, weight in a, weight in b)`, by combined weight
+pub fn shared(a: &Profile, b: &Profile) -> Vec<(String, f64, f64)> {
+    let (a_weights, b_weights) = (a.weights(), b.weights());
+    let mut seen = HashSet::new();
+    let mut shared: Vec<(String, f64, f64)> = a
+        .entries
+        .iter()
+        .filter(|(name, _)| seen.insert(name.to_lowercase()))
+        .filter_map(|(name, _)| {
+            let key = name.to_lowercase();
+            Some((name.clone(), a_weights[&key], *b_weights.get(&key)?))
+        })
+        .collect();
+    shared.sort_by(|x, y| {
+        (y.1 + y.2)
+            .total_cmp(&(x.1 + x.2))
+            .then_with(|| x.0.cmp(&y.0))
+    });
+    shared
+}
+
+/// The highest-ranked names of `a` that `b` doesn't list at all
+pub fn only_in(a: &Profile, b: &Profile, count: usize) -> Vec<String> {
+    let b_weights = b.weights();
+    a.entries
+        .iter()
+        .filter(|(name, _)| !b_weights.contains_key(&name.to_lowercase()))
+        .take(count)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+fn round(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+// Play counts are whole numbers, shown as strings like Last.fm's own
+fn plays(weight: f64) -> String {
+    (weight.round() as u64).to_string()
+}
+
+/// Full comparison body for two users
+pub fn to_json(
+    users: (&str, &str),
+    period: &str,
+    artists: (&Profile, &Profile),
+    tags: (&Profile, &Profile),
+) -> Value {
+    let total = score(artists, tags);
+    let shared_artists: Vec<Value> = shared(artists.0, artists.1)
+        .into_iter()
+        .take(MAX_LISTED)
+        .map(|(name, first, second)| {
+            json!({
+                "name": name,
+                "playcount": { users.0: plays(first), users.1: plays(second) },
+                "combined": plays(first + second),
+            })
+        })
+        .collect();
+    let shared_tags: Vec<String> = shared(tags.0, tags.1)
+        .into_iter()
+        .take(MAX_LISTED)
+        .map(|(name, _, _)| name)
+        .collect();
+
+    json!({
+        "comparison": {
+            "score": round(total),
+            "level": level(total),
+            "artists": {
+                "similarity": round(cosine(artists.0, artists.1)),
+                "overlap": round(overlap(artists.0, artists.1)),
+                "shared": shared_artists,
+            },
+            "tags": {
+                "similarity": round(cosine(tags.0, tags.1)),
+                "shared": shared_tags,
+            },
+            "differences": {
+                users.0: {
+                    "artists": only_in(artists.0, artists.1, MAX_LISTED),
+                    "tags": only_in(tags.0, tags.1, MAX_LISTED),
+                },
+                users.1: {
+                    "artists": only_in(artists.1, artists.0, MAX_LISTED),
+                    "tags": only_in(tags.1, tags.0, MAX_LISTED),
+                },
+            },
+            "@attr": { "user1": users.0, "user2": users.1, "period": period },
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(entries: &[(&str, f64)]) -> Profile {
+        Profile {
+            entries: entries
+                .iter()
+                .map(|(name, weight)| (name.to_string(), *weight))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_profile_from_document() {
+        let document = json!({"topartists": {"artist": [
+            {"name": "Radiohead", "playcount": "120"},
+            {"name": "Portishead", "playcount": 30},
+            {"name": "No plays"}
+        ], "@attr": {"user": "rj"}}});
+        let profile = Profile::from_document(&document, "playcount");
+        assert_eq!(
+            profile.entries,
+            [
+                ("Radiohead".to_string(), 120.0),
+                ("Portishead".to_string(), 30.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cosine_and_overlap() {
+        let a = profile(&[("Radiohead", 3.0), ("Portishead", 4.0)]);
+        assert!((cosine(&a, &a) - 1.0).abs() < 1e-9);
+        assert_eq!(cosine(&a, &profile(&[("Cher", 10.0)])), 0.0);
+        assert_eq!(cosine(&a, &Profile::default()), 0.0);
+
+        // Names match case-insensitively
+        let b = profile(&[("radiohead", 3.0), ("Cher", 4.0)]);
+        assert!((cosine(&a, &b) - 9.0 / 25.0).abs() < 1e-9);
+        assert!((overlap(&a, &b) - 1.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_score_falls_back_to_artists_without_tags() {
+        let artists = profile(&[("Radiohead", 1.0)]);
+        let tags = profile(&[("rock", 1.0)]);
+        let none = Profile::default();
+        let other_tags = profile(&[("pop", 1.0)]);
+
+        assert_eq!(score((&artists, &artists), (&tags, &none)), 1.0);
+        assert!((score((&artists, &artists), (&tags, &other_tags)) - ARTIST_WEIGHT).abs() < 1e-9);
+        assert_eq!(level(0.95), "Super");
+        assert_eq!(level(0.55), "High");
+        assert_eq!(level(0.0), "Very Low");
+    }
+
+    #[test]
+    fn test_shared_and_differences() {
+        let a = profile(&[("Radiohead", 50.0), ("Portishead", 40.0), ("Björk", 5.0)]);
+        let b = profile(&[("Björk", 100.0), ("Radiohead", 10.0), ("Cher", 80.0)]);
+
+        let shared = shared(&a, &b);
+        let names: Vec<&str> = shared.iter().map(|(name, _, _)| name.as_str()).collect();
+        assert_eq!(names, ["Björk", "Radiohead"]);
+        assert_eq!(shared[0].1, 5.0);
+        assert_eq!(shared[0].2, 100.0);
+
+        assert_eq!(only_in(&a, &b, 5), ["Portishead"]);
+        assert_eq!(only_in(&b, &a, 5), ["Cher"]);
+    }
+
+    #[test]
+    fn test_json_shape() {
+        let a = profile(&[("Radiohead", 50.0), ("Portishead", 40.0)]);
+        let b = profile(&[("Radiohead", 10.0)]);
+        let body = to_json(("rj", "joanofarctan"), "3month", (&a, &b), (&a, &b));
+        let comparison = &body["comparison"];
+
+        assert_eq!(comparison["artists"]["shared"][0]["playcount"]["rj"], "50");
+        assert_eq!(comparison["artists"]["shared"][0]["combined"], "60");
+        assert_eq!(comparison["differences"]["rj"]["artists"][0], "Portishead");
+        assert_eq!(comparison["@attr"]["period"], "3month");
+        assert!(comparison["score"].as_f64().unwrap() > 0.7);
+    }
+}
@@ -0,0 +1,200 @@
+use super::format::find_list_items;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+/// Normalized pagination taken from Last.fm's `@attr` or OpenSearch fields
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Pagination {
+    pub page: u64,
+    pub per_page: u64,
+    pub total: u64,
+    pub total_pages: u64,
+}
+
+/// Proxy metadata reported alongside the data in a v2 envelope
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Meta {
+    pub method: String,
+    pub cached: bool,
+    pub stale: bool,
+    /// Seconds since the response was cached
+    pub age: Option<i64>,
+    /// Time spent waiting for Last.fm, when it was called
+    pub upstream_ms: Option<i64>,
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+    pub total: Option<u64>,
+    pub total_pages: Option<u64>,
+}
+
+impl Meta {
+    pub fn with_pagination(mut self, pagination: Option<Pagination>) -> Self {
+        if let Some(p) = pagination {
+            self.page = Some(p.page);
+            self.per_page = Some(p.per_page);
+            self.total = Some(p.total);
+            self.total_pages = Some(p.total_pages);
+        }
+        self
+    }
+}
+
+// Last.fm sends numbers as strings
+fn number(value: Option<&Value>) -> Option<u64> {
+    match value? {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Read pagination from a list response (`@attr`) or search response (`opensearch:*`)
+pub fn pagination(document: &Value) -> Option<Pagination> {
+    let container = document.as_object()?.values().next()?.as_object()?;
+
+    if let Some(attr) = container.get("@attr") {
+        let page = number(attr.get("page"));
+        let total_pages = number(attr.get("totalPages"));
+        if let (Some(page), Some(total_pages)) = (page, total_pages) {
+            return Some(Pagination {
+                page,
+                per_page: number(attr.get("perPage")).unwrap_or(0),
+                total: number(attr.get("total")).unwrap_or(0),
+                total_pages,
+            });
+        }
+    }
+
+    let total = number(container.get("opensearch:totalResults"))?;
+    let per_page = number(container.get("opensearch:itemsPerPage"))?.max(1);
+    let start_index = number(container.get("opensearch:startIndex")).unwrap_or(0);
+
+    Some(Pagination {
+        page: start_index / per_page + 1,
+        per_page,
+        total,
+        total_pages: total.div_ceil(per_page),
+    })
+}
+
+/// The payload of a Last.fm response: the item array for lists and searches,
+/// otherwise the single top-level entity (e.g. the `artist` of artist.getInfo).
+pub fn data(document: &Value) -> Value {
+    if let Some(items) = find_list_items(document) {
+        return Value::Array(items.into_iter().cloned().collect());
+    }
+
+    match document.as_object() {
+        Some(root) if root.len() == 1 => root.values().next().cloned().unwrap_or(Value::Null),
+        _ => document.clone(),
+    }
+}
+
+/// Stable machine-readable code for a Last.fm or proxy error code
+pub fn error_code(code: u32) -> &'static str {
+    match code {
+        2 => "invalid_service",
+        3 => "invalid_method",
+        4 => "authentication_failed",
+        5 => "invalid_format",
+        6 => "invalid_parameters",
+        7 => "invalid_resource",
+        8 => "operation_failed",
+        9 => "invalid_session_key",
+        10 => "invalid_api_key",
+        11 => "service_offline",
+        13 => "invalid_signature",
+        16 => "temporary_error",
+        26 => "suspended_api_key",
+        29 => "rate_limit_exceeded",
+        _ => "upstream_error",
+    }
+}
+
+/// HTTP status for an error code (v1 answers every error with 200, as Last.fm does)
+pub fn error_status(code: u32) -> u16 {
+    match code {
+        3 | 5 | 6 | 7 => 400,
+        4 | 9 | 10 | 13 => 401,
+        26 => 403,
+        29 => 429,
+        11 | 16 => 503,
+        _ => 502,
+    }
+}
+
+/// `{data, meta, error: null}` for a successful response
+pub fn success(data: Value, meta: &Meta) -> Value {
+    json!({ "data": data, "meta": meta, "error": Value::Null })
+}
+
+/// `{data: null, meta, error: {code, message, lastfm_code}}`
+pub fn failure(code: &str, message: &str, lastfm_code: Option<u32>, meta: &Meta) -> Value {
+    let mut error = Map::new();
+    error.insert("code".to_string(), code.into());
+    error.insert("message".to_string(), message.into());
+    if let Some(lastfm_code) = lastfm_code {
+        error.insert("lastfm_code".to_string(), lastfm_code.into());
+    }
+
+    json!({ "data": Value::Null, "meta": meta, "error": error })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_pagination_and_data() {
+        let document = json!({
+            "topartists": {
+                "artist": [{"name": "Radiohead"}, {"name": "Muse"}],
+                "@attr": {"page": "2", "perPage": "2", "total": "9", "totalPages": "5"}
+            }
+        });
+
+        assert_eq!(
+            pagination(&document),
+            Some(Pagination {
+                page: 2,
+                per_page: 2,
+                total: 9,
+                total_pages: 5
+            })
+        );
+        assert_eq!(data(&document)[1]["name"], "Muse");
+    }
+
+    #[test]
+    fn test_search_pagination() {
+        let document = json!({
+            "results": {
+                "opensearch:totalResults": "95",
+                "opensearch:startIndex": "30",
+                "opensearch:itemsPerPage": "30",
+                "artistmatches": {"artist": [{"name": "Cher"}]}
+            }
+        });
+
+        let pagination = pagination(&document).unwrap();
+        assert_eq!(pagination.page, 2);
+        assert_eq!(pagination.total_pages, 4);
+        assert_eq!(data(&document)[0]["name"], "Cher");
+    }
+
+    #[test]
+    fn test_entity_data_is_unwrapped() {
+        let document = json!({"artist": {"name": "Cher", "mbid": "x", "url": "u"}});
+        assert_eq!(pagination(&document), None);
+        assert_eq!(data(&document)["name"], "Cher");
+    }
+
+    #[test]
+    fn test_failure_envelope() {
+        let envelope = failure(error_code(6), "Artist not found", Some(6), &Meta::default());
+        assert_eq!(envelope["data"], Value::Null);
+        assert_eq!(envelope["error"]["code"], "invalid_parameters");
+        assert_eq!(envelope["error"]["lastfm_code"], 6);
+        assert_eq!(error_status(6), 400);
+    }
+}
@@ -0,0 +1,470 @@
+use super::format::{escape_xml, find_list_items};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// Which listening data a feed follows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeedKind {
+    Recent,
+    Loved,
+    WeeklyArtists,
+}
+
+/// Syndication format, chosen by the feed's file extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+impl FeedKind {
+    /// Last.fm method whose cached response backs the feed
+    pub fn method(&self) -> &'static str {
+        match self {
+            Self::Recent => "user.getRecentTracks",
+            Self::Loved => "user.getLovedTracks",
+            Self::WeeklyArtists => "user.getWeeklyArtistChart",
+        }
+    }
+
+    fn slug(&self) -> &'static str {
+        match self {
+            Self::Recent => "recent",
+            Self::Loved => "loved",
+            Self::WeeklyArtists => "weekly-artists",
+        }
+    }
+
+    fn title(&self, user: &str) -> String {
+        match self {
+            Self::Recent => format!("{user}'s recent tracks"),
+            Self::Loved => format!("{user}'s loved tracks"),
+            Self::WeeklyArtists => format!("{user}'s top artists this week"),
+        }
+    }
+
+    fn link(&self, user: &str) -> String {
+        let profile = format!("https://www.last.fm/user/{user}");
+        match self {
+            Self::Recent => format!("{profile}/library"),
+            Self::Loved => format!("{profile}/loved"),
+            Self::WeeklyArtists => format!("{profile}/library/artists"),
+        }
+    }
+}
+
+impl FeedFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Atom => "application/atom+xml; charset=utf-8",
+            Self::Rss => "application/rss+xml; charset=utf-8",
+        }
+    }
+}
+
+/// Parse a feed name such as `recent.atom` or `loved.rss`
+pub fn parse_feed_name(name: &str) -> Option<(FeedKind, FeedFormat)> {
+    let (kind, format) = name.rsplit_once('.')?;
+    let kind = match kind {
+        "recent" => FeedKind::Recent,
+        "loved" => FeedKind::Loved,
+        "weekly-artists" => FeedKind::WeeklyArtists,
+        _ => return None,
+    };
+    let format = match format {
+        "atom" => FeedFormat::Atom,
+        "rss" => FeedFormat::Rss,
+        _ => return None,
+    };
+    Some((kind, format))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedEntry {
+    /// Stable across renders, so readers don't show an entry twice
+    pub guid: String,
+    pub title: String,
+    pub link: String,
+    pub updated: i64,
+    pub artwork: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feed {
+    pub id: String,
+    pub title: String,
+    pub link: String,
+    pub author: String,
+    pub updated: i64,
+    pub entries: Vec<FeedEntry>,
+}
+
+fn text(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Object(o)) => o
+            .get("#text")
+            .or_else(|| o.get("name"))
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        Some(Value::Number(n)) => n.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn number(value: Option<&Value>) -> Option<i64> {
+    match value? {
+        Value::String(s) => s.parse().ok(),
+        other => other.as_i64(),
+    }
+}
+
+// Largest non-empty image in a Last.fm `image` array (they are listed small to large)
+fn artwork(item: &Value) -> Option<String> {
+    item.get("image")?
+        .as_array()?
+        .iter()
+        .rev()
+        .map(|image| text(Some(image)))
+        .find(|url| !url.is_empty())
+}
+
+fn guid(user: &str, kind: FeedKind, parts: &[&str]) -> String {
+    let digest = Sha256::digest(parts.join("\u{1f}").as_bytes());
+    format!(
+        "urn:lastfm-proxy:{}:{}:{}",
+        user.to_lowercase(),
+        kind.slug(),
+        hex::encode(&digest[..8])
+    )
+}
+
+fn track_entries(kind: FeedKind, user: &str, document: &Value) -> Vec<FeedEntry> {
+    find_list_items(document)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|item| {
+            // The now-playing entry has no date and would change on every render
+            let played_at = number(item.get("date").and_then(|date| date.get("uts")))?;
+            let artist = text(item.get("artist"));
+            let name = text(item.get("name"));
+
+            Some(FeedEntry {
+                guid: guid(user, kind, &[&played_at.to_string(), &artist, &name]),
+                title: format!("{artist} – {name}"),
+                link: text(item.get("url")),
+                updated: played_at,
+                artwork: artwork(item),
+            })
+        })
+        .collect()
+}
+
+fn weekly_artist_entries(user: &str, document: &Value) -> Vec<FeedEntry> {
+    let attr = document
+        .get("weeklyartistchart")
+        .and_then(|c| c.get("@attr"));
+    let from = number(attr.and_then(|a| a.get("from"))).unwrap_or(0);
+    let to = number(attr.and_then(|a| a.get("to"))).unwrap_or(0);
+
+    find_list_items(document)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item| {
+            let artist = text(item.get("name"));
+            let rank = text(item.get("@attr").and_then(|a| a.get("rank")));
+            let plays = text(item.get("playcount"));
+
+            FeedEntry {
+                guid: guid(
+                    user,
+                    FeedKind::WeeklyArtists,
+                    &[&from.to_string(), &to.to_string(), &artist],
+                ),
+                title: format!("#{rank} {artist} ({plays} plays)"),
+                link: text(item.get("url")),
+                updated: to,
+                artwork: artwork(item),
+            }
+        })
+        .collect()
+}
+
+/// Build a feed from the Last.fm response for `kind`
+pub fn build_feed(kind: FeedKind, user: &str, document: &Value) -> Feed {
+    let entries = match kind {
+        FeedKind::Recent | FeedKind::Loved => track_entries(kind, user, document),
+        FeedKind::WeeklyArtists => weekly_artist_entries(user, document),
+    };
+
+    Feed {
+        id: format!("urn:lastfm-proxy:{}:{}", user.to_lowercase(), kind.slug()),
+        title: kind.title(user),
+        link: kind.link(user),
+        author: user.to_string(),
+        updated: entries.iter().map(|e| e.updated).max().unwrap_or(0),
+        entries,
+    }
+}
+
+fn timestamp(seconds: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(seconds, 0).unwrap_or_default()
+}
+
+/// `Last-Modified` / `If-Modified-Since` form of a unix timestamp
+pub fn http_date(seconds: i64) -> String {
+    timestamp(seconds)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+fn image_type(url: &str) -> &'static str {
+    let path = url
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    if path.ends_with(".png") {
+        "image/png"
+    } else if path.ends_with(".gif") {
+        "image/gif"
+    } else if path.ends_with(".webp") {
+        "image/webp"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// Render a feed as Atom 1.0 or RSS 2.0
+pub fn render(feed: &Feed, format: FeedFormat) -> String {
+    match format {
+        FeedFormat::Atom => render_atom(feed),
+        FeedFormat::Rss => render_rss(feed),
+    }
+}
+
+fn render_atom(feed: &Feed) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("<id>{}</id>\n", escape_xml(&feed.id)));
+    xml.push_str(&format!("<title>{}</title>\n", escape_xml(&feed.title)));
+    xml.push_str(&format!("<link href=\"{}\"/>\n", escape_xml(&feed.link)));
+    xml.push_str(&format!(
+        "<updated>{}</updated>\n",
+        timestamp(feed.updated).to_rfc3339()
+    ));
+    xml.push_str(&format!(
+        "<author><name>{}</name></author>\n",
+        escape_xml(&feed.author)
+    ));
+
+    for entry in &feed.entries {
+        xml.push_str("<entry>\n");
+        xml.push_str(&format!("<id>{}</id>\n", escape_xml(&entry.guid)));
+        xml.push_str(&format!("<title>{}</title>\n", escape_xml(&entry.title)));
+        if !entry.link.is_empty() {
+            xml.push_str(&format!("<link href=\"{}\"/>\n", escape_xml(&entry.link)));
+        }
+        xml.push_str(&format!(
+            "<updated>{}</updated>\n",
+            timestamp(entry.updated).to_rfc3339()
+        ));
+        if let Some(artwork) = &entry.artwork {
+            xml.push_str(&format!(
+                "<link rel=\"enclosure\" type=\"{}\" href=\"{}\"/>\n",
+                image_type(artwork),
+                escape_xml(artwork)
+            ));
+        }
+        xml.push_str("</entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn render_rss(feed: &Feed) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\">\n<channel>\n");
+    xml.push_str(&format!("<title>{}</title>\n", escape_xml(&feed.title)));
+    xml.push_str(&format!("<link>{}</link>\n", escape_xml(&feed.link)));
+    xml.push_str(&format!(
+        "<description>{}</description>\n",
+        escape_xml(&feed.title)
+    ));
+    xml.push_str(&format!(
+        "<lastBuildDate>{}</lastBuildDate>\n",
+        timestamp(feed.updated).to_rfc2822()
+    ));
+
+    for entry in &feed.entries {
+        xml.push_str("<item>\n");
+        xml.push_str(&format!(
+            "<guid isPermaLink=\"false\">{}</guid>\n",
+            escape_xml(&entry.guid)
+        ));
+        xml.push_str(&format!("<title>{}</title>\n", escape_xml(&entry.title)));
+        if !entry.link.is_empty() {
+            xml.push_str(&format!("<link>{}</link>\n", escape_xml(&entry.link)));
+        }
+        xml.push_str(&format!(
+            "<pubDate>{}</pubDate>\n",
+            timestamp(entry.updated).to_rfc2822()
+        ));
+        if let Some(artwork) = &entry.artwork {
+            // RSS requires a length; 0 means unknown
+            xml.push_str(&format!(
+                "<enclosure url=\"{}\" length=\"0\" type=\"{}\"/>\n",
+                escape_xml(artwork),
+                image_type(artwork)
+            ));
+        }
+        xml.push_str("</item>\n");
+    }
+
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+/// Strong validator for a rendered feed
+pub fn etag(body: &str) -> String {
+    format!(
+        "\"{}\"",
+        hex::encode(&Sha256::digest(body.as_bytes())[..12])
+    )
+}
+
+/// Whether a conditional GET can be answered with 304. `If-None-Match` takes
+/// precedence over `If-Modified-Since`, as RFC 9110 requires.
+pub fn is_not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    last_modified: i64,
+) -> bool {
+    if let Some(candidates) = if_none_match {
+        return candidates.split(',').any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*" || candidate.trim_start_matches("W/") == etag
+        });
+    }
+
+    if_modified_since
+        .and_then(|since| DateTime::parse_from_rfc2822(since.trim()).ok())
+        .map(|since| last_modified <= since.timestamp())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn recent_tracks() -> Value {
+        json!({"recenttracks": {
+            "track": [
+                {
+                    "artist": {"#text": "Radiohead"}, "name": "Airbag",
+                    "url": "https://www.last.fm/music/Radiohead/_/Airbag",
+                    "@attr": {"nowplaying": "true"}
+                },
+                {
+                    "artist": {"#text": "Radiohead"}, "name": "Lucky & Karma",
+                    "url": "https://www.last.fm/music/Radiohead/_/Lucky",
+                    "image": [
+                        {"#text": "https://img/small.png", "size": "small"},
+                        {"#text": "https://img/xl.png", "size": "extralarge"}
+                    ],
+                    "date": {"uts": "1700000000", "#text": "14 Nov 2023, 22:13"}
+                }
+            ],
+            "@attr": {"user": "rj"}
+        }})
+    }
+
+    #[test]
+    fn test_parse_feed_name() {
+        assert_eq!(
+            parse_feed_name("recent.atom"),
+            Some((FeedKind::Recent, FeedFormat::Atom))
+        );
+        assert_eq!(
+            parse_feed_name("weekly-artists.rss"),
+            Some((FeedKind::WeeklyArtists, FeedFormat::Rss))
+        );
+        assert_eq!(parse_feed_name("recent.json"), None);
+        assert_eq!(parse_feed_name("friends.rss"), None);
+    }
+
+    #[test]
+    fn test_recent_feed_skips_now_playing_and_keeps_guids_stable() {
+        let feed = build_feed(FeedKind::Recent, "RJ", &recent_tracks());
+        assert_eq!(feed.entries.len(), 1);
+        assert_eq!(feed.updated, 1_700_000_000);
+
+        let entry = &feed.entries[0];
+        assert_eq!(entry.title, "Radiohead – Lucky & Karma");
+        assert_eq!(entry.artwork.as_deref(), Some("https://img/xl.png"));
+        assert_eq!(
+            entry.guid,
+            build_feed(FeedKind::Recent, "rj", &recent_tracks()).entries[0].guid
+        );
+        assert!(entry.guid.starts_with("urn:lastfm-proxy:rj:recent:"));
+    }
+
+    #[test]
+    fn test_render_atom_and_rss() {
+        let feed = build_feed(FeedKind::Recent, "rj", &recent_tracks());
+
+        let atom = render(&feed, FeedFormat::Atom);
+        assert!(atom.contains("<title>Radiohead – Lucky &amp; Karma</title>"));
+        assert!(atom.contains("<updated>2023-11-14T22:13:20+00:00</updated>"));
+        assert!(atom
+            .contains("<link rel=\"enclosure\" type=\"image/png\" href=\"https://img/xl.png\"/>"));
+
+        let rss = render(&feed, FeedFormat::Rss);
+        assert!(rss.contains("<pubDate>Tue, 14 Nov 2023 22:13:20 +0000</pubDate>"));
+        assert!(rss.contains("<guid isPermaLink=\"false\">urn:lastfm-proxy:rj:recent:"));
+        assert!(rss.contains("<enclosure url=\"https://img/xl.png\" length=\"0\""));
+    }
+
+    #[test]
+    fn test_weekly_artist_feed() {
+        let document = json!({"weeklyartistchart": {
+            "artist": [{
+                "name": "Cher", "playcount": "42", "url": "https://www.last.fm/music/Cher",
+                "@attr": {"rank": "1"}
+            }],
+            "@attr": {"user": "rj", "from": "1699747200", "to": "1700352000"}
+        }});
+
+        let feed = build_feed(FeedKind::WeeklyArtists, "rj", &document);
+        assert_eq!(feed.entries[0].title, "#1 Cher (42 plays)");
+        assert_eq!(feed.updated, 1_700_352_000);
+    }
+
+    #[test]
+    fn test_conditional_get() {
+        let tag = etag("<feed/>");
+        assert!(is_not_modified(Some(&tag), None, &tag, 100));
+        assert!(is_not_modified(
+            Some(&format!("\"x\", W/{tag}")),
+            None,
+            &tag,
+            100
+        ));
+        assert!(!is_not_modified(
+            Some("\"other\""),
+            Some(&http_date(200)),
+            &tag,
+            100
+        ));
+
+        assert!(is_not_modified(None, Some(&http_date(100)), &tag, 100));
+        assert!(!is_not_modified(None, Some(&http_date(99)), &tag, 100));
+        assert!(!is_not_modified(None, Some("yesterday"), &tag, 100));
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+}
@@ -0,0 +1,235 @@
+use serde_json::{Map, Value};
+
+/// A set of dotted field paths such as `name,playcount,image.large`,
+/// stored as a tree so shared prefixes are walked once.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FieldSelection {
+    children: Vec<(String, FieldSelection)>,
+    terminal: bool,
+}
+
+impl FieldSelection {
+    /// Parse a comma-separated `fields=` value. Returns `None` when no field is selected.
+    pub fn parse(fields: &str) -> Option<Self> {
+        let mut root = Self::default();
+
+        for path in fields.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let mut node = &mut root;
+            for segment in path.split('.').filter(|s| !s.is_empty()) {
+                let index = match node.children.iter().position(|(name, _)| name == segment) {
+                    Some(index) => index,
+                    None => {
+                        node.children.push((segment.to_string(), Self::default()));
+                        node.children.len() - 1
+                    }
+                };
+                node = &mut node.children[index].1;
+            }
+            node.terminal = true;
+        }
+
+        if root.children.is_empty() {
+            None
+        } else {
+            Some(root)
+        }
+    }
+
+    fn child(&self, name: &str) -> Option<&Self> {
+        self.children
+            .iter()
+            .find(|(child, _)| child == name)
+            .map(|(_, selection)| selection)
+    }
+
+    /// Prune a Last.fm response document down to the selected fields.
+    /// Paths are relative to the entity (`artist.getInfo` -> the artist) or to each
+    /// item of a list response; list metadata in `@attr` is always kept.
+    pub fn apply(&self, document: &Value) -> Value {
+        let root = match document.as_object() {
+            Some(root) => root,
+            None => return document.clone(),
+        };
+
+        let mut pruned = Map::new();
+        for (key, wrapper) in root {
+            let value = match wrapper.as_object() {
+                Some(container) if is_list_container(container) => self.apply_list(container),
+                Some(container) if key == "results" => self.apply_search(container),
+                _ => self.project(wrapper).unwrap_or(Value::Null),
+            };
+            pruned.insert(key.clone(), value);
+        }
+
+        Value::Object(pruned)
+    }
+
+    fn apply_list(&self, container: &Map<String, Value>) -> Value {
+        let mut pruned = Map::new();
+        for (key, items) in container {
+            let value = if key == "@attr" {
+                items.clone()
+            } else {
+                match items {
+                    Value::Array(items) => {
+                        Value::Array(items.iter().filter_map(|i| self.project(i)).collect())
+                    }
+                    item => self.project(item).unwrap_or(Value::Null),
+                }
+            };
+            pruned.insert(key.clone(), value);
+        }
+        Value::Object(pruned)
+    }
+
+    // Search results keep their opensearch metadata and prune each *matches list
+    fn apply_search(&self, results: &Map<String, Value>) -> Value {
+        let mut pruned = Map::new();
+        for (key, value) in results {
+            let value = match value.as_object() {
+                Some(matches) if key.ends_with("matches") => self.apply_list(matches),
+                _ => value.clone(),
+            };
+            pruned.insert(key.clone(), value);
+        }
+        Value::Object(pruned)
+    }
+
+    fn project(&self, value: &Value) -> Option<Value> {
+        if self.terminal && self.children.is_empty() {
+            return Some(value.clone());
+        }
+
+        match value {
+            Value::Object(map) => {
+                let mut pruned = Map::new();
+                for (key, child) in map {
+                    if let Some(selection) = self.child(key) {
+                        if let Some(child) = selection.project(child) {
+                            pruned.insert(key.clone(), child);
+                        }
+                    }
+                }
+                (!pruned.is_empty()).then_some(Value::Object(pruned))
+            }
+            Value::Array(items) => {
+                // Image arrays select entries by size, e.g. image.large
+                let sized = !items.is_empty() && items.iter().all(|i| i.get("size").is_some());
+                if sized {
+                    let selected: Vec<Value> = items
+                        .iter()
+                        .filter(|item| {
+                            item["size"]
+                                .as_str()
+                                .and_then(|size| self.child(size))
+                                .is_some()
+                        })
+                        .cloned()
+                        .collect();
+                    if !selected.is_empty() {
+                        return Some(Value::Array(selected));
+                    }
+                }
+                let projected: Vec<Value> = items.iter().filter_map(|i| self.project(i)).collect();
+                (!projected.is_empty()).then_some(Value::Array(projected))
+            }
+            _ => self.terminal.then(|| value.clone()),
+        }
+    }
+}
+
+// A list container holds a single item key next to its `@attr` metadata
+fn is_list_container(container: &Map<String, Value>) -> bool {
+    let mut keys = container.iter().filter(|(key, _)| *key != "@attr");
+    matches!(
+        (keys.next(), keys.next()),
+        (Some((_, Value::Array(_) | Value::Object(_))), None)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_ignores_empty_paths() {
+        assert!(FieldSelection::parse(" , ").is_none());
+        assert!(FieldSelection::parse("name,,image.large").is_some());
+    }
+
+    #[test]
+    fn test_prunes_entity_document() {
+        let doc = json!({
+            "artist": {
+                "name": "Cher",
+                "image": [
+                    {"#text": "s.png", "size": "small"},
+                    {"#text": "l.png", "size": "large"}
+                ],
+                "stats": {"listeners": "1", "playcount": "2"},
+                "bio": {"content": "<a>long</a>"}
+            }
+        });
+
+        let fields = FieldSelection::parse("name,image.large,stats.playcount").unwrap();
+        assert_eq!(
+            fields.apply(&doc),
+            json!({
+                "artist": {
+                    "name": "Cher",
+                    "image": [{"#text": "l.png", "size": "large"}],
+                    "stats": {"playcount": "2"}
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_prunes_each_list_item_and_keeps_attr() {
+        let doc = json!({
+            "topartists": {
+                "artist": [
+                    {"name": "A", "playcount": "10", "url": "a"},
+                    {"name": "B", "playcount": "5", "url": "b"}
+                ],
+                "@attr": {"page": "1", "total": "2"}
+            }
+        });
+
+        let fields = FieldSelection::parse("name,playcount").unwrap();
+        assert_eq!(
+            fields.apply(&doc),
+            json!({
+                "topartists": {
+                    "artist": [
+                        {"name": "A", "playcount": "10"},
+                        {"name": "B", "playcount": "5"}
+                    ],
+                    "@attr": {"page": "1", "total": "2"}
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_prunes_search_matches() {
+        let doc = json!({
+            "results": {
+                "opensearch:totalResults": "1",
+                "trackmatches": {"track": [{"name": "Creep", "artist": "Radiohead", "url": "u"}]}
+            }
+        });
+
+        let fields = FieldSelection::parse("name").unwrap();
+        assert_eq!(
+            fields.apply(&doc),
+            json!({
+                "results": {
+                    "opensearch:totalResults": "1",
+                    "trackmatches": {"track": [{"name": "Creep"}]}
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use serde_json::{Map, Value};
+
+/// Response formats the worker can serve. The cache always holds canonical JSON
+/// and other formats are rendered from it on the way out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    Xml,
+    Csv,
+    Ndjson,
+}
+
+impl ResponseFormat {
+    /// Parse a `format=` query value
+    pub fn from_param(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "xml" => Some(Self::Xml),
+            "csv" => Some(Self::Csv),
+            "ndjson" | "jsonl" => Some(Self::Ndjson),
+            _ => None,
+        }
+    }
+
+    /// Parse a single media type from an `Accept` header
+    pub fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.to_ascii_lowercase().as_str() {
+            "application/json" | "text/json" => Some(Self::Json),
+            "application/xml" | "text/xml" => Some(Self::Xml),
+            "text/csv" => Some(Self::Csv),
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => {
+                Some(Self::Ndjson)
+            }
+            _ => None,
+        }
+    }
+
+    /// Pick a format from the `format=` parameter, falling back to the `Accept` header.
+    /// An explicit parameter always wins; unknown values fall back to JSON.
+    pub fn negotiate(format_param: Option<&str>, accept: Option<&str>) -> Self {
+        if let Some(format) = format_param {
+            return Self::from_param(format).unwrap_or(Self::Json);
+        }
+
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Self::Json,
+        };
+
+        // Highest q-value wins, ties keep header order
+        let mut best: Option<(Self, f32)> = None;
+        for part in accept.split(',') {
+            let mut pieces = part.split(';');
+            let media_type = pieces.next().unwrap_or("").trim();
+            let quality = pieces
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if let Some(format) = Self::from_media_type(media_type) {
+                if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                    best = Some((format, quality));
+                }
+            }
+        }
+
+        best.map(|(format, _)| format).unwrap_or(Self::Json)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Xml => "application/xml; charset=utf-8",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// Render a canonical JSON body in this format
+    pub fn render(&self, json_body: &str) -> Result<String, String> {
+        if *self == Self::Json {
+            return Ok(json_body.to_string());
+        }
+
+        let value: Value =
+            serde_json::from_str(json_body).map_err(|e| format!("Invalid JSON body: {e}"))?;
+
+        Ok(match self {
+            Self::Json => unreachable!(),
+            Self::Xml => to_xml(&value),
+            Self::Csv => to_csv(&value),
+            Self::Ndjson => to_ndjson(&value),
+        })
+    }
+}
+
+// Search responses use `opensearch:*` element names
+const OPENSEARCH_NAMESPACE: &str = "http://a9.com/-/spec/opensearch/1.1/";
+
+/// Rebuild Last.fm's native XML from its JSON form.
+/// Last.fm maps attributes to `@attr` and text nodes to `#text`.
+pub fn to_xml(value: &Value) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<lfm status=\"ok\" xmlns:opensearch=\"{OPENSEARCH_NAMESPACE}\">"
+    );
+    if let Value::Object(root) = value {
+        for (key, child) in root {
+            write_xml_element(&mut xml, key, child);
+        }
+    }
+    xml.push_str("</lfm>\n");
+    xml
+}
+
+fn write_xml_element(xml: &mut String, name: &str, value: &Value) {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                write_xml_element(xml, name, item);
+            }
+        }
+        Value::Object(map) => {
+            // Text nodes carry their attributes as sibling keys (e.g. image size, artist mbid)
+            let text = map.get("#text");
+            let is_attribute = |key: &str, value: &Value| {
+                text.is_some() && key != "#text" && !value.is_object() && !value.is_array()
+            };
+
+            xml.push('<');
+            xml.push_str(name);
+            let attrs = map
+                .get("@attr")
+                .and_then(|a| a.as_object())
+                .into_iter()
+                .flatten()
+                .chain(map.iter().filter(|(k, v)| is_attribute(k, v)));
+            for (attr, attr_value) in attrs {
+                xml.push(' ');
+                xml.push_str(attr);
+                xml.push_str("=\"");
+                xml.push_str(&escape_xml(&scalar_to_string(attr_value)));
+                xml.push('"');
+            }
+            xml.push('>');
+            if let Some(text) = text {
+                xml.push_str(&escape_xml(&scalar_to_string(text)));
+            }
+            for (key, child) in map {
+                if key != "@attr" && key != "#text" && !is_attribute(key, child) {
+                    write_xml_element(xml, key, child);
+                }
+            }
+            xml.push_str("</");
+            xml.push_str(name);
+            xml.push('>');
+        }
+        _ => {
+            xml.push('<');
+            xml.push_str(name);
+            xml.push('>');
+            xml.push_str(&escape_xml(&scalar_to_string(value)));
+            xml.push_str("</");
+            xml.push_str(name);
+            xml.push('>');
+        }
+    }
+}
+
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn scalar_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Find the list of items in a Last.fm list response,
+/// e.g. `topartists.artist`, `recenttracks.track` or `results.artistmatches.artist`.
+pub fn find_list_items(value: &Value) -> Option<Vec<&Value>> {
+    let root = value.as_object()?;
+    let container = root.values().next()?.as_object()?;
+
+    if let Some(items) = list_container_items(container) {
+        return Some(items);
+    }
+
+    // Search results nest the list one level deeper
+    container
+        .iter()
+        .filter(|(key, _)| key.ends_with("matches"))
+        .find_map(|(_, child)| child.as_object().and_then(list_container_items))
+}
+
+// A list container holds a single item key next to its `@attr` metadata.
+// Last.fm returns a bare object instead of an array when there is only one item.
+fn list_container_items(container: &Map<String, Value>) -> Option<Vec<&Value>> {
+    let mut keys = container.iter().filter(|(key, _)| *key != "@attr");
+    let (_, items) = keys.next()?;
+    if keys.next().is_some() {
+        return None;
+    }
+
+    match items {
+        Value::Array(items) => Some(items.iter().collect()),
+        Value::Object(_) => Some(vec![items]),
+        _ => None,
+    }
+}
+
+/// Flatten one list item into column/value pairs.
+/// Nested objects use dotted names, `#text` collapses into its parent,
+/// `@attr` fields become top-level columns and image arrays are keyed by size.
+pub fn flatten_item(value: &Value) -> Vec<(String, String)> {
+    let mut columns = Vec::new();
+    flatten_into(&mut columns, "", value);
+    columns
+}
+
+fn flatten_into(columns: &mut Vec<(String, String)>, prefix: &str, value: &Value) {
+    match value {
+        Value::Object(map) => flatten_object(columns, prefix, map),
+        Value::Array(items) => {
+            let sized = items
+                .iter()
+                .all(|item| item.get("size").is_some() && item.get("#text").is_some());
+            if sized && !items.is_empty() {
+                for item in items {
+                    let size = scalar_to_string(&item["size"]);
+                    let name = join_column(prefix, if size.is_empty() { "default" } else { &size });
+                    columns.push((name, scalar_to_string(&item["#text"])));
+                }
+            } else if items
+                .iter()
+                .all(|item| !item.is_object() && !item.is_array())
+            {
+                let joined = items.iter().map(scalar_to_string).collect::<Vec<_>>();
+                columns.push((prefix.to_string(), joined.join(";")));
+            } else {
+                columns.push((prefix.to_string(), value.to_string()));
+            }
+        }
+        _ => columns.push((prefix.to_string(), scalar_to_string(value))),
+    }
+}
+
+fn flatten_object(columns: &mut Vec<(String, String)>, prefix: &str, map: &Map<String, Value>) {
+    for (key, child) in map {
+        match key.as_str() {
+            "#text" => flatten_into(columns, prefix, child),
+            "@attr" => flatten_into(columns, prefix, child),
+            _ => flatten_into(columns, &join_column(prefix, key), child),
+        }
+    }
+}
+
+fn join_column(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{prefix}.{key}")
+    }
+}
+
+/// Render a list response as CSV with a header row.
+/// Non-list responses become a single flattened row.
+pub fn to_csv(value: &Value) -> String {
+    let rows: Vec<Vec<(String, String)>> = match find_list_items(value) {
+        Some(items) => items.into_iter().map(flatten_item).collect(),
+        None => vec![value
+            .as_object()
+            .and_then(|root| root.values().next())
+            .map(flatten_item)
+            .unwrap_or_default()],
+    };
+
+    // Union of columns in first-seen order
+    let mut header: Vec<String> = Vec::new();
+    for row in &rows {
+        for (column, _) in row {
+            if !header.contains(column) {
+                header.push(column.clone());
+            }
+        }
+    }
+
+    let mut csv = String::new();
+    csv.push_str(
+        &header
+            .iter()
+            .map(|c| escape_csv(c))
+            .collect::<Vec<_>>()
+            .join(","),
+    );
+    csv.push_str("\r\n");
+
+    for row in &rows {
+        let line = header
+            .iter()
+            .map(|column| {
+                row.iter()
+                    .find(|(c, _)| c == column)
+                    .map(|(_, v)| escape_csv(v))
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        csv.push_str(&line);
+        csv.push_str("\r\n");
+    }
+
+    csv
+}
+
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Render a list response as newline-delimited JSON, one item per line.
+/// Non-list responses become a single line.
+pub fn to_ndjson(value: &Value) -> String {
+    match find_list_items(value) {
+        Some(items) => items.into_iter().map(|item| format!("{item}\n")).collect(),
+        None => format!("{value}\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_negotiate_prefers_format_param() {
+        assert_eq!(
+            ResponseFormat::negotiate(Some("csv"), Some("application/xml")),
+            ResponseFormat::Csv
+        );
+        assert_eq!(
+            ResponseFormat::negotiate(Some("bogus"), Some("application/xml")),
+            ResponseFormat::Json
+        );
+    }
+
+    #[test]
+    fn test_negotiate_accept_header() {
+        assert_eq!(
+            ResponseFormat::negotiate(None, Some("text/html, text/xml;q=0.9, */*;q=0.8")),
+            ResponseFormat::Xml
+        );
+        assert_eq!(
+            ResponseFormat::negotiate(None, Some("text/csv;q=0.5, application/x-ndjson")),
+            ResponseFormat::Ndjson
+        );
+        assert_eq!(ResponseFormat::negotiate(None, None), ResponseFormat::Json);
+        assert_eq!(
+            ResponseFormat::negotiate(None, Some("*/*")),
+            ResponseFormat::Json
+        );
+    }
+
+    #[test]
+    fn test_xml_rebuilds_attributes_and_text() {
+        let value = json!({
+            "topartists": {
+                "artist": [
+                    {"name": "Tom & Jerry", "image": [{"#text": "http://img/s.png", "size": "small"}], "@attr": {"rank": "1"}}
+                ],
+                "@attr": {"page": "1"}
+            }
+        });
+
+        let xml = to_xml(&value);
+        assert!(xml.contains(&format!(
+            "<lfm status=\"ok\" xmlns:opensearch=\"{OPENSEARCH_NAMESPACE}\"><topartists page=\"1\">"
+        )));
+        assert!(xml.contains("<artist rank=\"1\"><name>Tom &amp; Jerry</name>"));
+        assert!(xml.contains("<image size=\"small\">http://img/s.png</image>"));
+    }
+
+    #[test]
+    fn test_xml_declares_opensearch_namespace() {
+        let value = json!({
+            "results": {
+                "opensearch:totalResults": "2",
+                "artistmatches": {"artist": [{"name": "Radiohead"}]},
+                "@attr": {"for": "radiohead"}
+            }
+        });
+
+        let xml = to_xml(&value);
+        assert!(xml.contains(
+            "<lfm status=\"ok\" xmlns:opensearch=\"http://a9.com/-/spec/opensearch/1.1/\">"
+        ));
+        assert!(xml.contains("<opensearch:totalResults>2</opensearch:totalResults>"));
+    }
+
+    #[test]
+    fn test_csv_flattens_list_items() {
+        let value = json!({
+            "recenttracks": {
+                "track": [
+                    {"artist": {"#text": "Radiohead"}, "name": "Creep, live", "image": [{"#text": "s.png", "size": "small"}], "@attr": {"nowplaying": "true"}},
+                    {"artist": {"#text": "Muse"}, "name": "Uprising", "date": {"uts": "1700000000", "#text": "14 Nov 2023"}}
+                ],
+                "@attr": {"user": "rj"}
+            }
+        });
+
+        let csv = to_csv(&value);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "artist,name,image.small,nowplaying,date.uts,date");
+        assert_eq!(lines[1], "Radiohead,\"Creep, live\",s.png,true,,");
+        assert_eq!(lines[2], "Muse,Uprising,,,1700000000,14 Nov 2023");
+    }
+
+    #[test]
+    fn test_find_list_items_ignores_info_documents() {
+        let info = json!({"artist": {"name": "A", "image": [], "similar": {"artist": []}}});
+        assert!(find_list_items(&info).is_none());
+
+        let search = json!({"results": {"opensearch:totalResults": "1", "artistmatches": {"artist": [{"name": "A"}]}, "@attr": {}}});
+        assert_eq!(find_list_items(&search).unwrap().len(), 1);
+
+        let single = json!({"tracks": {"track": {"name": "Only"}, "@attr": {"page": "1"}}});
+        assert_eq!(find_list_items(&single).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_ndjson_one_item_per_line() {
+        let value = json!({"artists": {"artist": [{"name": "A"}, {"name": "B"}]}});
+        assert_eq!(to_ndjson(&value), "{\"name\":\"A\"}\n{\"name\":\"B\"}\n");
+
+        let single = json!({"artist": {"name": "A"}});
+        assert_eq!(to_ndjson(&single), "{\"artist\":{\"name\":\"A\"}}\n");
+    }
+}
@@ -0,0 +1,345 @@
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// Listens per submit-listens request, as on ListenBrainz. The scrobble queue
+/// stores an import as one entry per 50 listens, so this is 20 KV writes.
+pub const MAX_LISTENS_PER_REQUEST: usize = 1000;
+
+/// The `listen_type` of a submit-listens body
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListenType {
+    Single,
+    PlayingNow,
+    Import,
+}
+
+impl ListenType {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "single" => Some(Self::Single),
+            "playing_now" => Some(Self::PlayingNow),
+            "import" => Some(Self::Import),
+            _ => None,
+        }
+    }
+}
+
+/// One listen, reduced to what Last.fm's track methods take
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listen {
+    pub artist: String,
+    pub track: String,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<String>,
+    /// Seconds
+    pub duration: Option<String>,
+    pub mbid: Option<String>,
+}
+
+impl Listen {
+    /// `track.updateNowPlaying` parameters (without `sk`)
+    pub fn now_playing_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("artist".to_string(), self.artist.clone());
+        params.insert("track".to_string(), self.track.clone());
+        for (name, value) in [
+            ("album", &self.album),
+            ("albumArtist", &self.album_artist),
+            ("trackNumber", &self.track_number),
+            ("duration", &self.duration),
+            ("mbid", &self.mbid),
+        ] {
+            if let Some(value) = value {
+                params.insert(name.to_string(), value.clone());
+            }
+        }
+        params
+    }
+}
+
+/// A listen that was played at a known time (`single` and `import`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedListen {
+    pub listened_at: i64,
+    pub listen: Listen,
+}
+
+/// A validated submit-listens body
+#[derive(Debug, Clone, PartialEq)]
+pub enum Submission {
+    /// The one listen of a `playing_now` body
+    PlayingNow(Listen),
+    /// The listens of a `single` or `import` body
+    Listens(ListenType, Vec<TimedListen>),
+}
+
+#[derive(Deserialize)]
+struct RawSubmission {
+    listen_type: Option<String>,
+    #[serde(default)]
+    payload: Vec<RawListen>,
+}
+
+#[derive(Deserialize)]
+struct RawListen {
+    listened_at: Option<i64>,
+    track_metadata: Option<TrackMetadata>,
+}
+
+#[derive(Deserialize)]
+struct TrackMetadata {
+    artist_name: Option<String>,
+    track_name: Option<String>,
+    release_name: Option<String>,
+    #[serde(default)]
+    additional_info: Map<String, Value>,
+}
+
+// additional_info values arrive as strings or numbers depending on the client
+fn info_text(info: &Map<String, Value>, key: &str) -> Option<String> {
+    match info.get(key)? {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn nonempty(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.trim().is_empty())
+}
+
+impl RawListen {
+    fn into_listen(self, index: usize) -> Result<(Option<i64>, Listen), String> {
+        let metadata = self
+            .track_metadata
+            .ok_or_else(|| format!("Listen {index} has no track_metadata"))?;
+        let artist = nonempty(metadata.artist_name)
+            .ok_or_else(|| format!("Listen {index} has no artist_name"))?;
+        let track = nonempty(metadata.track_name)
+            .ok_or_else(|| format!("Listen {index} has no track_name"))?;
+        let info = &metadata.additional_info;
+
+        // duration_ms is preferred; duration is already in seconds
+        let duration = info_text(info, "duration_ms")
+            .and_then(|ms| ms.parse::<u64>().ok())
+            .map(|ms| (ms / 1000).to_string())
+            .or_else(|| info_text(info, "duration"));
+
+        let listen = Listen {
+            artist,
+            track,
+            album: nonempty(metadata.release_name),
+            album_artist: info_text(info, "release_artist_name"),
+            track_number: info_text(info, "tracknumber"),
+            duration,
+            mbid: info_text(info, "recording_mbid").or_else(|| info_text(info, "track_mbid")),
+        };
+        Ok((self.listened_at, listen))
+    }
+}
+
+/// Parse and validate a submit-listens body with ListenBrainz's rules: one
+/// listen for `single` and `playing_now`, a `listened_at` on every listen
+/// except `playing_now`, which must not have one.
+pub fn parse_submission(body: &str) -> Result<Submission, String> {
+    let raw: RawSubmission =
+        serde_json::from_str(body).map_err(|e| format!("Invalid JSON document submitted: {e}"))?;
+
+    let listen_type = raw
+        .listen_type
+        .as_deref()
+        .and_then(ListenType::parse)
+        .ok_or_else(|| "JSON document must contain a valid listen_type".to_string())?;
+
+    match (listen_type, raw.payload.len()) {
+        (_, 0) => return Err("JSON document does not contain any listens".to_string()),
+        (ListenType::Single | ListenType::PlayingNow, n) if n > 1 => {
+            return Err("JSON document contains more than one listen".to_string())
+        }
+        (ListenType::Import, n) if n > MAX_LISTENS_PER_REQUEST => {
+            return Err(format!(
+                "Too many listens. You may not submit more than {MAX_LISTENS_PER_REQUEST} listens at once"
+            ))
+        }
+        _ => {}
+    }
+
+    let listens = raw
+        .payload
+        .into_iter()
+        .enumerate()
+        .map(|(index, listen)| listen.into_listen(index))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if listen_type == ListenType::PlayingNow {
+        let Some((None, listen)) = listens.into_iter().next() else {
+            return Err("playing_now listens must not contain listened_at".to_string());
+        };
+        return Ok(Submission::PlayingNow(listen));
+    }
+
+    let listens = listens
+        .into_iter()
+        .map(|(listened_at, listen)| {
+            listened_at
+                .map(|listened_at| TimedListen {
+                    listened_at,
+                    listen,
+                })
+                .ok_or_else(|| "JSON document must contain listened_at".to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Submission::Listens(listen_type, listens))
+}
+
+/// The token from an `Authorization: Token <token>` header
+pub fn parse_authorization(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("token") && !token.is_empty()).then_some(token)
+}
+
+/// Whether a value can be linked as a ListenBrainz user token. Proxy tokens
+/// already work as user tokens, so they are not linked.
+pub fn is_user_token(token: &str) -> bool {
+    (1..=256).contains(&token.len())
+        && !token.starts_with(crate::vault::TOKEN_PREFIX)
+        && !token.chars().any(char::is_whitespace)
+}
+
+/// ListenBrainz's error body; the HTTP status carries the same code
+pub fn error_body(code: u16, message: &str) -> Value {
+    json!({ "code": code, "error": message })
+}
+
+/// validate-token reply
+pub fn validation_body(user_name: Option<&str>) -> Value {
+    match user_name {
+        Some(user_name) => json!({
+            "code": 200,
+            "message": "Token valid.",
+            "valid": true,
+            "user_name": user_name,
+        }),
+        None => json!({
+            "code": 200,
+            "message": "Token invalid.",
+            "valid": false,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_listen() {
+        let submission = parse_submission(
+            r#"{"listen_type": "single", "payload": [{
+                "listened_at": 1443521965,
+                "track_metadata": {
+                    "artist_name": "Rick Astley",
+                    "track_name": "Never Gonna Give You Up",
+                    "release_name": "Whenever You Need Somebody",
+                    "additional_info": {
+                        "duration_ms": 213000,
+                        "tracknumber": 1,
+                        "recording_mbid": "8f3471b5-7e6a-48da-86a9-c1c07a0f47ae"
+                    }
+                }
+            }]}"#,
+        )
+        .unwrap();
+
+        let Submission::Listens(ListenType::Single, listens) = submission else {
+            panic!("expected a single listen, got {submission:?}");
+        };
+        assert_eq!(listens[0].listened_at, 1443521965);
+        let listen = &listens[0].listen;
+        assert_eq!(listen.album.as_deref(), Some("Whenever You Need Somebody"));
+        assert_eq!(listen.duration.as_deref(), Some("213"));
+        assert_eq!(listen.track_number.as_deref(), Some("1"));
+        assert_eq!(
+            listen.mbid.as_deref(),
+            Some("8f3471b5-7e6a-48da-86a9-c1c07a0f47ae")
+        );
+    }
+
+    #[test]
+    fn test_playing_now_params() {
+        let submission = parse_submission(
+            r#"{"listen_type": "playing_now", "payload": [{"track_metadata":
+                {"artist_name": "Cher", "track_name": "Believe",
+                 "additional_info": {"duration": 239}}}]}"#,
+        )
+        .unwrap();
+
+        let Submission::PlayingNow(listen) = submission else {
+            panic!("expected a playing_now listen, got {submission:?}");
+        };
+        let params = listen.now_playing_params();
+        assert_eq!(params["artist"], "Cher");
+        assert_eq!(params["duration"], "239");
+        assert!(!params.contains_key("album"));
+    }
+
+    #[test]
+    fn test_listen_type_rules() {
+        let listen = |at: &str| {
+            format!(
+                r#"{{{at}"track_metadata": {{"artist_name": "Cher", "track_name": "Believe"}}}}"#
+            )
+        };
+        let body = |listen_type: &str, listens: &[String]| {
+            format!(
+                r#"{{"listen_type": "{listen_type}", "payload": [{}]}}"#,
+                listens.join(",")
+            )
+        };
+        let timed = listen(r#""listened_at": 100, "#);
+        let untimed = listen("");
+
+        assert!(parse_submission(&body("import", &[timed.clone(), timed.clone()])).is_ok());
+        assert!(parse_submission(&body("single", &[timed.clone(), timed.clone()])).is_err());
+        assert!(parse_submission(&body("single", std::slice::from_ref(&untimed))).is_err());
+        assert!(parse_submission(&body("playing_now", std::slice::from_ref(&timed))).is_err());
+        assert!(parse_submission(&body("playing_now", &[untimed])).is_ok());
+        assert!(parse_submission(&body("import", &[])).is_err());
+        assert!(parse_submission(&body("bogus", std::slice::from_ref(&timed))).is_err());
+
+        let full = vec![timed; MAX_LISTENS_PER_REQUEST];
+        assert!(parse_submission(&body("import", &full)).is_ok());
+        let over = vec![full[0].clone(); MAX_LISTENS_PER_REQUEST + 1];
+        assert!(parse_submission(&body("import", &over)).is_err());
+    }
+
+    #[test]
+    fn test_missing_metadata() {
+        let err = parse_submission(
+            r#"{"listen_type": "single", "payload": [{"listened_at": 1,
+                "track_metadata": {"artist_name": "Cher", "track_name": " "}}]}"#,
+        )
+        .unwrap_err();
+        assert!(err.contains("track_name"));
+    }
+
+    #[test]
+    fn test_authorization_and_bodies() {
+        assert_eq!(parse_authorization("Token abc"), Some("abc"));
+        assert_eq!(parse_authorization("token  abc "), Some("abc"));
+        assert_eq!(parse_authorization("Bearer abc"), None);
+        assert_eq!(parse_authorization("Token "), None);
+
+        assert!(is_user_token("4d8a3d2e-6c1f-4f5b-9a7e-2b0c1d3e4f50"));
+        assert!(!is_user_token(""));
+        assert!(!is_user_token("lfpt_abc"));
+        assert!(!is_user_token("two words"));
+
+        assert_eq!(validation_body(Some("rj"))["user_name"], "rj");
+        assert_eq!(validation_body(None)["valid"], false);
+        assert_eq!(error_body(401, "Invalid authorization token.")["code"], 401);
+    }
+}
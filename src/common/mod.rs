@@ -1,3 +1,16 @@
+pub mod audioscrobbler;
+pub mod badge;
+pub mod batch;
+pub mod chartdiff;
+pub mod compare;
+pub mod envelope;
+pub mod feed;
+pub mod fields;
+pub mod format;
+pub mod listenbrainz;
+pub mod nowplaying;
+pub mod recommendations;
+pub mod search;
 pub mod signing;
 pub mod url;
 pub mod validation;
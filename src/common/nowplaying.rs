@@ -0,0 +1,259 @@
+use serde_json::{json, Value};
+
+/// A track from `user.getRecentTracks`, reduced to what widgets display
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentTrack {
+    pub artist: String,
+    pub name: String,
+    pub album: String,
+    pub url: String,
+    /// Scrobble time; absent for the now-playing entry
+    pub timestamp: Option<u64>,
+}
+
+impl RecentTrack {
+    fn from_value(track: &Value) -> Option<Self> {
+        let text = |value: Option<&Value>| match value {
+            Some(Value::String(s)) => s.clone(),
+            Some(Value::Object(o)) => o
+                .get("#text")
+                .or_else(|| o.get("name"))
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            _ => String::new(),
+        };
+
+        let name = text(track.get("name"));
+        if name.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            artist: text(track.get("artist")),
+            name,
+            album: text(track.get("album")),
+            url: text(track.get("url")),
+            timestamp: track.get("date").and_then(|date| date.get("uts")).and_then(
+                |uts| match uts {
+                    Value::String(s) => s.parse().ok(),
+                    other => other.as_u64(),
+                },
+            ),
+        })
+    }
+
+    fn same_track(&self, other: &Self) -> bool {
+        self.artist == other.artist && self.name == other.name && self.album == other.album
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({
+            "artist": self.artist,
+            "name": self.name,
+            "album": self.album,
+            "url": self.url,
+        });
+        if let Some(timestamp) = self.timestamp {
+            value["timestamp"] = timestamp.into();
+        }
+        value
+    }
+}
+
+/// What a user is playing and last scrobbled, per `@attr.nowplaying`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListeningState {
+    pub now_playing: Option<RecentTrack>,
+    pub last_scrobble: Option<RecentTrack>,
+}
+
+impl ListeningState {
+    /// Read the state from a `user.getRecentTracks` response
+    pub fn from_recent_tracks(document: &Value) -> Option<Self> {
+        let tracks = match document.get("recenttracks")?.get("track") {
+            Some(Value::Array(tracks)) => tracks.iter().collect(),
+            // A single track comes back as an object
+            Some(track @ Value::Object(_)) => vec![track],
+            _ => Vec::new(),
+        };
+
+        let mut state = Self::default();
+        for track in tracks {
+            let playing = track
+                .get("@attr")
+                .and_then(|attr| attr.get("nowplaying"))
+                .map(|flag| flag == "true" || flag == true)
+                .unwrap_or(false);
+
+            match RecentTrack::from_value(track) {
+                Some(track) if playing => state.now_playing = state.now_playing.or(Some(track)),
+                Some(track) if state.last_scrobble.is_none() => state.last_scrobble = Some(track),
+                _ => {}
+            }
+        }
+
+        Some(state)
+    }
+}
+
+/// A change worth telling subscribers about
+#[derive(Debug, Clone, PartialEq)]
+pub enum NowPlayingEvent {
+    NowPlaying(RecentTrack),
+    Stopped(RecentTrack),
+    Scrobble(RecentTrack),
+}
+
+impl NowPlayingEvent {
+    /// The event in `text/event-stream` framing
+    pub fn to_sse(&self) -> String {
+        let (name, track) = match self {
+            Self::NowPlaying(track) => ("nowplaying", track),
+            Self::Stopped(track) => ("stopped", track),
+            Self::Scrobble(track) => ("scrobble", track),
+        };
+        sse_event(name, &track.to_json())
+    }
+}
+
+/// Events between two polls. On the first poll (`previous` is `None`) only a
+/// track already playing is reported.
+pub fn changes(
+    previous: Option<&ListeningState>,
+    current: &ListeningState,
+) -> Vec<NowPlayingEvent> {
+    let mut events = Vec::new();
+
+    let Some(previous) = previous else {
+        if let Some(track) = &current.now_playing {
+            events.push(NowPlayingEvent::NowPlaying(track.clone()));
+        }
+        return events;
+    };
+
+    let is_new_scrobble = match (&previous.last_scrobble, &current.last_scrobble) {
+        (Some(before), Some(after)) => after.timestamp > before.timestamp,
+        (None, Some(_)) => true,
+        _ => false,
+    };
+    if is_new_scrobble {
+        if let Some(track) = &current.last_scrobble {
+            events.push(NowPlayingEvent::Scrobble(track.clone()));
+        }
+    }
+
+    match (&previous.now_playing, &current.now_playing) {
+        (Some(before), Some(after)) if before.same_track(after) => {}
+        (_, Some(after)) => events.push(NowPlayingEvent::NowPlaying(after.clone())),
+        (Some(before), None) => events.push(NowPlayingEvent::Stopped(before.clone())),
+        (None, None) => {}
+    }
+
+    events
+}
+
+/// A named SSE event with a JSON payload
+pub fn sse_event(name: &str, data: &Value) -> String {
+    format!("event: {name}\ndata: {data}\n\n")
+}
+
+/// An SSE comment line; keeps idle connections from being closed
+pub fn sse_heartbeat() -> String {
+    ": heartbeat\n\n".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recent_tracks(now_playing: Option<&str>, scrobbled: &str, uts: u64) -> Value {
+        let mut tracks = Vec::new();
+        if let Some(name) = now_playing {
+            tracks.push(json!({
+                "artist": {"#text": "Radiohead"},
+                "name": name,
+                "album": {"#text": "OK Computer"},
+                "url": "https://www.last.fm/music/Radiohead",
+                "@attr": {"nowplaying": "true"}
+            }));
+        }
+        tracks.push(json!({
+            "artist": {"#text": "Radiohead"},
+            "name": scrobbled,
+            "album": {"#text": "OK Computer"},
+            "date": {"uts": uts.to_string(), "#text": "..."}
+        }));
+        json!({"recenttracks": {"track": tracks, "@attr": {"user": "rj"}}})
+    }
+
+    fn state(document: &Value) -> ListeningState {
+        ListeningState::from_recent_tracks(document).unwrap()
+    }
+
+    #[test]
+    fn test_state_uses_nowplaying_flag() {
+        let listening = state(&recent_tracks(Some("Airbag"), "Lucky", 100));
+        assert_eq!(listening.now_playing.unwrap().name, "Airbag");
+        assert_eq!(listening.last_scrobble.unwrap().timestamp, Some(100));
+
+        let idle = state(&recent_tracks(None, "Lucky", 100));
+        assert_eq!(idle.now_playing, None);
+    }
+
+    #[test]
+    fn test_single_track_object() {
+        let document = json!({"recenttracks": {"track": {
+            "artist": {"#text": "Cher"}, "name": "Believe", "date": {"uts": "5"}
+        }}});
+        assert_eq!(state(&document).last_scrobble.unwrap().name, "Believe");
+    }
+
+    #[test]
+    fn test_first_poll_reports_only_a_playing_track() {
+        let playing = state(&recent_tracks(Some("Airbag"), "Lucky", 100));
+        assert!(matches!(
+            changes(None, &playing).as_slice(),
+            [NowPlayingEvent::NowPlaying(t)] if t.name == "Airbag"
+        ));
+
+        let idle = state(&recent_tracks(None, "Lucky", 100));
+        assert!(changes(None, &idle).is_empty());
+    }
+
+    #[test]
+    fn test_unchanged_state_emits_nothing() {
+        let playing = state(&recent_tracks(Some("Airbag"), "Lucky", 100));
+        assert!(changes(Some(&playing), &playing.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_track_change_scrobble_and_stop() {
+        let first = state(&recent_tracks(Some("Airbag"), "Lucky", 100));
+        let second = state(&recent_tracks(Some("Paranoid Android"), "Airbag", 200));
+        let events = changes(Some(&first), &second);
+        assert!(matches!(
+            events.as_slice(),
+            [NowPlayingEvent::Scrobble(s), NowPlayingEvent::NowPlaying(n)]
+                if s.name == "Airbag" && n.name == "Paranoid Android"
+        ));
+
+        let stopped = state(&recent_tracks(None, "Airbag", 200));
+        assert!(matches!(
+            changes(Some(&second), &stopped).as_slice(),
+            [NowPlayingEvent::Stopped(t)] if t.name == "Paranoid Android"
+        ));
+    }
+
+    #[test]
+    fn test_sse_framing() {
+        let track = state(&recent_tracks(Some("Airbag"), "Lucky", 100))
+            .now_playing
+            .unwrap();
+        let event = NowPlayingEvent::NowPlaying(track).to_sse();
+        assert!(event.starts_with("event: nowplaying\ndata: {"));
+        assert!(event.ends_with("}\n\n"));
+        assert!(!event["event: nowplaying\ndata: ".len()..event.len() - 2].contains('\n'));
+        assert_eq!(sse_heartbeat(), ": heartbeat\n\n");
+    }
+}
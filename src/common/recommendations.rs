@@ -0,0 +1,322 @@
+use super::format::find_list_items;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Suggestions returned when `limit` is not given
+pub const DEFAULT_RECOMMENDATIONS: usize = 20;
+
+/// Most suggestions one request may ask for
+pub const MAX_RECOMMENDATIONS: usize = 50;
+
+// Seed artists named in one explanation
+const MAX_REASONS: usize = 3;
+
+/// A top artist that similar artists are looked up for
+#[derive(Debug, Clone, PartialEq)]
+pub struct Seed {
+    pub name: String,
+    /// Share of the top artist's plays, 0..=1
+    pub weight: f64,
+}
+
+/// An `artist.getSimilar` entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimilarArtist {
+    pub name: String,
+    pub url: String,
+    /// Last.fm's similarity, 0..=1
+    pub similarity: f64,
+}
+
+/// A ranked suggestion and the seeds that contributed most to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recommendation {
+    pub name: String,
+    pub url: String,
+    pub score: f64,
+    pub because: Vec<String>,
+}
+
+fn text(value: Option<&Value>) -> String {
+    value
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+// Numbers come back as strings from Last.fm, but accept either
+fn number(value: Option<&Value>) -> Option<f64> {
+    match value? {
+        Value::String(s) => s.parse().ok(),
+        other => other.as_f64(),
+    }
+}
+
+/// Artist names from a list response (user.getTopArtists, library.getArtists, ...)
+pub fn artist_names(document: &Value) -> Vec<String> {
+    find_list_items(document)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|artist| text(artist.get("name")))
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// The first `count` artists of a `user.getTopArtists` response, weighted by
+/// their plays relative to the top artist (by rank when plays are missing)
+pub fn seeds(document: &Value, count: usize) -> Vec<Seed> {
+    let artists: Vec<(String, Option<f64>)> = find_list_items(document)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|artist| (text(artist.get("name")), number(artist.get("playcount"))))
+        .filter(|(name, _)| !name.is_empty())
+        .take(count)
+        .collect();
+
+    let top = artists
+        .iter()
+        .filter_map(|(_, plays)| *plays)
+        .fold(0.0, f64::max);
+
+    artists
+        .into_iter()
+        .enumerate()
+        .map(|(rank, (name, plays))| {
+            let weight = match plays {
+                Some(plays) if top > 0.0 => plays / top,
+                _ => 1.0 / (rank + 1) as f64,
+            };
+            Seed { name, weight }
+        })
+        .collect()
+}
+
+/// Entries of an `artist.getSimilar` response
+pub fn similar_artists(document: &Value) -> Vec<SimilarArtist> {
+    find_list_items(document)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|artist| {
+            let name = text(artist.get("name"));
+            let similarity = number(artist.get("match"))?;
+            (!name.is_empty()).then(|| SimilarArtist {
+                name,
+                url: text(artist.get("url")),
+                similarity,
+            })
+        })
+        .collect()
+}
+
+// A similar artist and its (score, seed index) contributions
+struct Candidate<'a> {
+    artist: &'a SimilarArtist,
+    contributions: Vec<(f64, usize)>,
+}
+
+/// Score every similar artist by the sum of seed weight × similarity over the
+/// seeds it was found for, skipping names in `known` (compared
+/// case-insensitively), and return the best `limit`. `similar[i]` holds the
+/// similar artists of `seeds[i]`.
+pub fn rank(
+    seeds: &[Seed],
+    similar: &[Vec<SimilarArtist>],
+    known: &HashSet<String>,
+    limit: usize,
+) -> Vec<Recommendation> {
+    let known: HashSet<String> = known.iter().map(|name| name.to_lowercase()).collect();
+
+    // Keyed by lowercase name
+    let mut candidates: HashMap<String, Candidate> = HashMap::new();
+    for (seed_index, (seed, artists)) in seeds.iter().zip(similar).enumerate() {
+        for artist in artists {
+            let key = artist.name.to_lowercase();
+            if known.contains(&key) {
+                continue;
+            }
+            candidates
+                .entry(key)
+                .or_insert_with(|| Candidate {
+                    artist,
+                    contributions: Vec::new(),
+                })
+                .contributions
+                .push((seed.weight * artist.similarity, seed_index));
+        }
+    }
+
+    let mut ranked: Vec<Recommendation> = candidates
+        .into_values()
+        .map(|mut candidate| {
+            let contributions = &mut candidate.contributions;
+            contributions.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+            Recommendation {
+                name: candidate.artist.name.clone(),
+                url: candidate.artist.url.clone(),
+                score: contributions.iter().map(|(score, _)| score).sum(),
+                because: contributions
+                    .iter()
+                    .take(MAX_REASONS)
+                    .map(|(_, seed)| seeds[*seed].name.clone())
+                    .collect(),
+            }
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    ranked.truncate(limit);
+    ranked
+}
+
+/// "Because you listen to A, B and C"
+pub fn explanation(because: &[String]) -> String {
+    let names = match because {
+        [] => return String::new(),
+        [only] => only.clone(),
+        [init @ .., last] => format!("{} and {last}", init.join(", ")),
+    };
+    format!("Because you listen to {names}")
+}
+
+/// Response body in the style of Last.fm's list responses
+pub fn to_json(user: &str, period: &str, seeds: &[Seed], ranked: &[Recommendation]) -> Value {
+    let artists: Vec<Value> = ranked
+        .iter()
+        .enumerate()
+        .map(|(index, recommendation)| {
+            json!({
+                "name": recommendation.name,
+                "url": recommendation.url,
+                "score": (recommendation.score * 1000.0).round() / 1000.0,
+                "because": recommendation.because,
+                "explanation": explanation(&recommendation.because),
+                "@attr": { "rank": (index + 1).to_string() },
+            })
+        })
+        .collect();
+
+    json!({
+        "recommendations": {
+            "artist": artists,
+            "@attr": {
+                "user": user,
+                "period": period,
+                "seeds": seeds.iter().map(|seed| seed.name.clone()).collect::<Vec<_>>(),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn similar(pairs: &[(&str, f64)]) -> Vec<SimilarArtist> {
+        pairs
+            .iter()
+            .map(|(name, similarity)| SimilarArtist {
+                name: name.to_string(),
+                url: format!("https://www.last.fm/music/{name}"),
+                similarity: *similarity,
+            })
+            .collect()
+    }
+
+    fn seed(name: &str, weight: f64) -> Seed {
+        Seed {
+            name: name.to_string(),
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_seed_weights() {
+        let document = json!({"topartists": {"artist": [
+            {"name": "Radiohead", "playcount": "200"},
+            {"name": "Portishead", "playcount": "50"},
+            {"name": "Massive Attack", "playcount": "10"}
+        ], "@attr": {"user": "rj"}}});
+
+        let seeds = seeds(&document, 2);
+        assert_eq!(seeds.len(), 2);
+        assert_eq!(seeds[0].weight, 1.0);
+        assert_eq!(seeds[1].weight, 0.25);
+
+        let unplayed = json!({"topartists": {"artist": [{"name": "A"}, {"name": "B"}]}});
+        let by_rank = super::seeds(&unplayed, 5);
+        assert_eq!(by_rank[1].weight, 0.5);
+    }
+
+    #[test]
+    fn test_similar_artists_parse_match() {
+        let document = json!({"similarartists": {"artist": [
+            {"name": "Thom Yorke", "match": "0.9", "url": "u1"},
+            {"name": "No match"},
+            {"name": "Atoms for Peace", "match": 0.5, "url": "u2"}
+        ]}});
+        let artists = similar_artists(&document);
+        assert_eq!(artists.len(), 2);
+        assert_eq!(artists[0].similarity, 0.9);
+        assert_eq!(artists[1].name, "Atoms for Peace");
+    }
+
+    #[test]
+    fn test_rank_sums_weighted_similarity_and_skips_known() {
+        let seeds = vec![seed("Radiohead", 1.0), seed("Portishead", 0.5)];
+        let found = vec![
+            similar(&[
+                ("Thom Yorke", 0.95),
+                ("Massive Attack", 0.4),
+                ("Portishead", 0.6),
+            ]),
+            similar(&[("Massive Attack", 1.0), ("Tricky", 0.8)]),
+        ];
+        let known: HashSet<String> = ["radiohead", "PORTISHEAD", "Tricky"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        let ranked = rank(&seeds, &found, &known, 10);
+        let names: Vec<&str> = ranked.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["Thom Yorke", "Massive Attack"]);
+        assert!((ranked[1].score - 0.9).abs() < 1e-9);
+        assert_eq!(ranked[1].because, ["Portishead", "Radiohead"]);
+
+        assert_eq!(rank(&seeds, &found, &known, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_explanation() {
+        let names = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(explanation(&[]), "");
+        assert_eq!(
+            explanation(&names(&["Radiohead"])),
+            "Because you listen to Radiohead"
+        );
+        assert_eq!(
+            explanation(&names(&["A", "B", "C"])),
+            "Because you listen to A, B and C"
+        );
+    }
+
+    #[test]
+    fn test_json_shape() {
+        let seeds = vec![seed("Radiohead", 1.0)];
+        let ranked = rank(
+            &seeds,
+            &[similar(&[("Thom Yorke", 0.91234)])],
+            &HashSet::new(),
+            5,
+        );
+        let body = to_json("rj", "3month", &seeds, &ranked);
+        let artist = &body["recommendations"]["artist"][0];
+        assert_eq!(artist["score"], 0.912);
+        assert_eq!(artist["@attr"]["rank"], "1");
+        assert_eq!(artist["explanation"], "Because you listen to Radiohead");
+        assert_eq!(body["recommendations"]["@attr"]["period"], "3month");
+    }
+}
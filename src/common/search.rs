@@ -0,0 +1,348 @@
+use super::format::find_list_items;
+use serde_json::{json, Value};
+
+/// Results per type when neither `limit` nor a per-type limit is given
+pub const DEFAULT_LIMIT: usize = 10;
+
+/// Most results one type may ask for
+pub const MAX_LIMIT: usize = 50;
+
+/// One of the three Last.fm search methods
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchType {
+    Artist,
+    Album,
+    Track,
+}
+
+impl SearchType {
+    /// Every type, in the order results of equal relevance are listed
+    pub const ALL: [SearchType; 3] = [SearchType::Artist, SearchType::Album, SearchType::Track];
+
+    pub fn slug(&self) -> &'static str {
+        match self {
+            SearchType::Artist => "artist",
+            SearchType::Album => "album",
+            SearchType::Track => "track",
+        }
+    }
+
+    pub fn method(&self) -> &'static str {
+        match self {
+            SearchType::Artist => "artist.search",
+            SearchType::Album => "album.search",
+            SearchType::Track => "track.search",
+        }
+    }
+
+    /// Query parameter overriding `limit` for this type, e.g. `album_limit`
+    pub fn limit_param(&self) -> &'static str {
+        match self {
+            SearchType::Artist => "artist_limit",
+            SearchType::Album => "album_limit",
+            SearchType::Track => "track_limit",
+        }
+    }
+}
+
+/// A search hit with the fields all three result shapes can provide
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub kind: SearchType,
+    pub name: String,
+    /// Performing artist of an album or track
+    pub artist: Option<String>,
+    pub url: String,
+    pub mbid: Option<String>,
+    /// Not reported for albums
+    pub listeners: Option<i64>,
+    pub image: Option<String>,
+    /// Zero-based position in Last.fm's own ordering for this type
+    pub position: usize,
+}
+
+fn text(value: Option<&Value>) -> Option<String> {
+    let text = match value? {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        // Nested `{"#text": ...}` objects, as in image entries
+        Value::Object(object) => return text(object.get("#text")),
+        _ => return None,
+    };
+    (!text.is_empty()).then_some(text)
+}
+
+fn number(value: Option<&Value>) -> Option<i64> {
+    match value? {
+        Value::String(s) => s.parse().ok(),
+        other => other.as_i64(),
+    }
+}
+
+// Largest non-empty image (Last.fm lists them small to large)
+fn image(item: &Value) -> Option<String> {
+    item.get("image")?
+        .as_array()?
+        .iter()
+        .rev()
+        .find_map(|image| text(Some(image)))
+}
+
+/// The trimmed `q` parameter, which is required and must not be blank
+pub fn parse_query(q: Option<&str>) -> Result<String, String> {
+    let q = q.ok_or_else(|| "Missing required parameter: q".to_string())?;
+    match q.trim() {
+        "" => Err("Invalid q - must not be empty".to_string()),
+        q => Ok(q.to_string()),
+    }
+}
+
+/// Per-type limit from its own parameter, then `limit`, then the default.
+/// Zero skips the type; `None` means the value is not a number in range.
+pub fn parse_limit(own: Option<&str>, shared: Option<&str>) -> Option<usize> {
+    match own.or(shared) {
+        None => Some(DEFAULT_LIMIT),
+        Some(value) => value.parse().ok().filter(|limit| *limit <= MAX_LIMIT),
+    }
+}
+
+/// Hits of an `artist.search`, `album.search` or `track.search` response
+pub fn results(kind: SearchType, document: &Value) -> Vec<SearchResult> {
+    find_list_items(document)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|item| Some((text(item.get("name"))?, item)))
+        .enumerate()
+        .map(|(position, (name, item))| SearchResult {
+            kind,
+            name,
+            artist: match kind {
+                SearchType::Artist => None,
+                _ => text(item.get("artist")),
+            },
+            url: text(item.get("url")).unwrap_or_default(),
+            mbid: text(item.get("mbid")),
+            listeners: number(item.get("listeners")),
+            image: image(item),
+            position,
+        })
+        .collect()
+}
+
+/// `opensearch:totalResults` of a search response
+pub fn total_results(document: &Value) -> Option<i64> {
+    number(document.get("results")?.get("opensearch:totalResults"))
+}
+
+/// How closely a hit's name matches the query: 3 for an exact match (also
+/// "Artist - Name" for albums and tracks), 2 for a prefix, 1 for a substring
+pub fn match_quality(query: &str, result: &SearchResult) -> u8 {
+    let query = query.trim().to_lowercase();
+    let name = result.name.to_lowercase();
+    let qualified = result
+        .artist
+        .as_ref()
+        .map(|artist| format!("{} - {}", artist.to_lowercase(), name));
+
+    if name == query || qualified.as_deref() == Some(query.as_str()) {
+        3
+    } else if name.starts_with(&query) {
+        2
+    } else if name.contains(&query) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Merge the per-type lists into one: best name match first, then Last.fm's
+/// own relevance order, interleaving the types at each position
+pub fn rank(query: &str, lists: Vec<Vec<SearchResult>>) -> Vec<SearchResult> {
+    let type_order = |kind: SearchType| SearchType::ALL.iter().position(|k| *k == kind);
+    let mut merged: Vec<(u8, SearchResult)> = lists
+        .into_iter()
+        .flatten()
+        .map(|result| (match_quality(query, &result), result))
+        .collect();
+    merged.sort_by(|(a_quality, a), (b_quality, b)| {
+        b_quality
+            .cmp(a_quality)
+            .then(a.position.cmp(&b.position))
+            .then_with(|| type_order(a.kind).cmp(&type_order(b.kind)))
+    });
+    merged.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Response body; `totals` holds `opensearch:totalResults` for each type
+/// that was searched (`None` when its lookup failed)
+pub fn to_json(
+    query: &str,
+    ranked: &[SearchResult],
+    totals: &[(SearchType, Option<i64>)],
+) -> Value {
+    let results: Vec<Value> = ranked
+        .iter()
+        .enumerate()
+        .map(|(index, result)| {
+            json!({
+                "type": result.kind.slug(),
+                "name": result.name,
+                "artist": result.artist,
+                "url": result.url,
+                "mbid": result.mbid,
+                "listeners": result.listeners,
+                "image": result.image,
+                "@attr": { "rank": (index + 1).to_string() },
+            })
+        })
+        .collect();
+    let totals: serde_json::Map<String, Value> = totals
+        .iter()
+        .map(|(kind, total)| (kind.slug().to_string(), json!(total)))
+        .collect();
+
+    json!({
+        "search": {
+            "result": results,
+            "@attr": { "query": query, "totals": totals },
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artist_document() -> Value {
+        json!({"results": {
+            "opensearch:totalResults": "1200",
+            "artistmatches": {"artist": [
+                {"name": "Radiohead", "listeners": "5000000", "mbid": "a74b1b7f",
+                 "url": "https://www.last.fm/music/Radiohead",
+                 "image": [{"#text": "s.png", "size": "small"}, {"#text": "", "size": "mega"}]},
+                {"name": "Radiohead Tribute", "listeners": "120", "mbid": "", "url": "u2"}
+            ]},
+            "@attr": {"for": "radiohead"}
+        }})
+    }
+
+    fn hit(kind: SearchType, name: &str, artist: Option<&str>, position: usize) -> SearchResult {
+        SearchResult {
+            kind,
+            name: name.to_string(),
+            artist: artist.map(str::to_string),
+            url: String::new(),
+            mbid: None,
+            listeners: None,
+            image: None,
+            position,
+        }
+    }
+
+    #[test]
+    fn test_results_normalize_each_shape() {
+        let artists = results(SearchType::Artist, &artist_document());
+        assert_eq!(artists.len(), 2);
+        assert_eq!(artists[0].listeners, Some(5_000_000));
+        assert_eq!(artists[0].image.as_deref(), Some("s.png"));
+        assert_eq!(artists[0].artist, None);
+        assert_eq!(artists[1].mbid, None);
+        assert_eq!(artists[1].position, 1);
+        assert_eq!(total_results(&artist_document()), Some(1200));
+
+        // A single album comes back as a bare object
+        let albums = json!({"results": {
+            "opensearch:totalResults": "1",
+            "albummatches": {"album":
+                {"name": "OK Computer", "artist": "Radiohead", "url": "u3"}
+            }
+        }});
+        let albums = results(SearchType::Album, &albums);
+        assert_eq!(albums[0].artist.as_deref(), Some("Radiohead"));
+        assert_eq!(albums[0].listeners, None);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(parse_query(Some(" radiohead ")).unwrap(), "radiohead");
+        assert_eq!(
+            parse_query(None).unwrap_err(),
+            "Missing required parameter: q"
+        );
+        assert_eq!(
+            parse_query(Some("  ")).unwrap_err(),
+            "Invalid q - must not be empty"
+        );
+    }
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(parse_limit(None, None), Some(DEFAULT_LIMIT));
+        assert_eq!(parse_limit(None, Some("5")), Some(5));
+        assert_eq!(parse_limit(Some("0"), Some("5")), Some(0));
+        assert_eq!(parse_limit(Some("51"), None), None);
+        assert_eq!(parse_limit(Some("many"), None), None);
+    }
+
+    #[test]
+    fn test_match_quality() {
+        let track = hit(SearchType::Track, "Creep", Some("Radiohead"), 0);
+        assert_eq!(match_quality(" CREEP ", &track), 3);
+        assert_eq!(match_quality("radiohead - creep", &track), 3);
+        assert_eq!(match_quality("cre", &track), 2);
+        assert_eq!(match_quality("ee", &track), 1);
+        assert_eq!(match_quality("radiohead", &track), 0);
+    }
+
+    #[test]
+    fn test_rank_prefers_matches_then_interleaves() {
+        let ranked = rank(
+            "radiohead",
+            vec![
+                vec![
+                    hit(SearchType::Artist, "Radiohead", None, 0),
+                    hit(SearchType::Artist, "Radiohead Tribute", None, 1),
+                ],
+                vec![hit(SearchType::Album, "OK Computer", Some("Radiohead"), 0)],
+                vec![
+                    hit(SearchType::Track, "Creep", Some("Radiohead"), 0),
+                    hit(SearchType::Track, "Radiohead", Some("Cover Band"), 1),
+                ],
+            ],
+        );
+        let order: Vec<(&str, &str)> = ranked
+            .iter()
+            .map(|r| (r.kind.slug(), r.name.as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                ("artist", "Radiohead"),
+                ("track", "Radiohead"),
+                ("artist", "Radiohead Tribute"),
+                ("album", "OK Computer"),
+                ("track", "Creep"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_shape() {
+        let ranked = rank(
+            "radiohead",
+            vec![results(SearchType::Artist, &artist_document())],
+        );
+        let body = to_json(
+            "radiohead",
+            &ranked,
+            &[(SearchType::Artist, Some(1200)), (SearchType::Track, None)],
+        );
+        let first = &body["search"]["result"][0];
+        assert_eq!(first["type"], "artist");
+        assert_eq!(first["artist"], Value::Null);
+        assert_eq!(first["listeners"], 5_000_000);
+        assert_eq!(first["@attr"]["rank"], "1");
+        assert_eq!(body["search"]["@attr"]["totals"]["artist"], 1200);
+        assert_eq!(body["search"]["@attr"]["totals"]["track"], Value::Null);
+    }
+}
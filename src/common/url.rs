@@ -21,10 +21,112 @@ pub fn build_lastfm_url(
 
     // Add other parameters
     for (key, value) in params {
-        if key != "method" && key != "api_key" && key != "format" {
+        if !matches!(key.as_str(), "method" | "api_key" | "format" | "fields") {
             url.query_pairs_mut().append_pair(key, value);
         }
     }
 
     Ok(url)
 }
+
+/// Build a form-encoded Last.fm API request body for POST methods
+pub fn build_lastfm_form_body(
+    method: &str,
+    params: &HashMap<String, String>,
+    api_key: &str,
+) -> String {
+    let mut body = url::form_urlencoded::Serializer::new(String::new());
+
+    body.append_pair("method", method);
+    body.append_pair("api_key", api_key);
+    body.append_pair("format", "json");
+
+    for (key, value) in params {
+        if !matches!(key.as_str(), "method" | "api_key" | "format" | "fields") {
+            body.append_pair(key, value);
+        }
+    }
+
+    body.finish()
+}
+
+/// Parse a request body into parameters based on its content type.
+/// Supports `application/x-www-form-urlencoded` and flat JSON objects.
+pub fn parse_body_params(
+    content_type: &str,
+    body: &str,
+) -> Result<HashMap<String, String>, String> {
+    let mut params = HashMap::new();
+
+    if body.trim().is_empty() {
+        return Ok(params);
+    }
+
+    if content_type.starts_with("application/json") {
+        let value: serde_json::Value =
+            serde_json::from_str(body).map_err(|e| format!("Invalid JSON body: {e}"))?;
+        let object = value
+            .as_object()
+            .ok_or_else(|| "JSON body must be an object".to_string())?;
+
+        for (key, value) in object {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Number(n) => n.to_string(),
+                serde_json::Value::Bool(b) => b.to_string(),
+                _ => return Err(format!("Unsupported value for parameter: {key}")),
+            };
+            params.insert(key.clone(), value);
+        }
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        for (key, value) in url::form_urlencoded::parse(body.as_bytes()) {
+            params.insert(key.to_string(), value.to_string());
+        }
+    } else {
+        return Err(format!("Unsupported content type: {content_type}"));
+    }
+
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_form_body() {
+        let params = parse_body_params(
+            "application/x-www-form-urlencoded; charset=utf-8",
+            "username=rj&password=p%40ss+word",
+        )
+        .unwrap();
+
+        assert_eq!(params.get("username").unwrap(), "rj");
+        assert_eq!(params.get("password").unwrap(), "p@ss word");
+    }
+
+    #[test]
+    fn test_parse_json_body() {
+        let params =
+            parse_body_params("application/json", r#"{"username":"rj","limit":5}"#).unwrap();
+
+        assert_eq!(params.get("username").unwrap(), "rj");
+        assert_eq!(params.get("limit").unwrap(), "5");
+        assert!(parse_body_params("application/json", "[1, 2]").is_err());
+        assert!(parse_body_params("text/plain", "username=rj").is_err());
+    }
+
+    #[test]
+    fn test_form_body_keeps_credentials_out_of_url() {
+        let mut params = HashMap::new();
+        params.insert("password".to_string(), "secret pass".to_string());
+        params.insert("api_key".to_string(), "client_key".to_string());
+
+        let body = build_lastfm_form_body("auth.getMobileSession", &params, "worker_key");
+
+        assert!(body.contains("method=auth.getMobileSession"));
+        assert!(body.contains("api_key=worker_key"));
+        assert!(!body.contains("client_key"));
+        assert!(body.contains("password=secret+pass"));
+    }
+}
@@ -1,5 +1,21 @@
 use std::collections::HashMap;
 
+/// Periods accepted by the user.getTop* methods
+pub const PERIODS: &[&str] = &["overall", "7day", "1month", "3month", "6month", "12month"];
+
+/// The `period` parameter, or `default` when it is absent
+pub fn period_param(params: &HashMap<String, String>, default: &str) -> Result<String, String> {
+    let period = params.get("period").map_or(default, String::as_str);
+    if PERIODS.contains(&period) {
+        Ok(period.to_string())
+    } else {
+        Err(format!(
+            "Invalid period - must be one of {}",
+            PERIODS.join(", ")
+        ))
+    }
+}
+
 /// Validates parameters for Last.fm API methods
 /// Returns Ok(()) if valid, Err(String) with error message if invalid
 pub fn validate_method_params(
@@ -141,3 +157,19 @@ pub fn validate_method_params(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period_param() {
+        let params = |period: &str| HashMap::from([("period".to_string(), period.to_string())]);
+
+        assert_eq!(period_param(&HashMap::new(), "7day").unwrap(), "7day");
+        assert_eq!(period_param(&params("3month"), "7day").unwrap(), "3month");
+        assert!(period_param(&params("weekly"), "7day")
+            .unwrap_err()
+            .starts_with("Invalid period"));
+    }
+}
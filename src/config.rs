@@ -0,0 +1,304 @@
+// Per-environment defaults selected by the ENVIRONMENT var. Every setting can
+// be overridden by its own var, e.g. CACHE_MODE=bypass on a preview deploy.
+
+use worker::Env;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Environment {
+    Production,
+    Staging,
+    Preview,
+}
+
+impl Environment {
+    // Unknown or missing values get production's conservative defaults
+    pub fn parse(value: &str) -> Self {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "staging" => Self::Staging,
+            "preview" | "development" | "dev" => Self::Preview,
+            _ => Self::Production,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Production => "production",
+            Self::Staging => "staging",
+            Self::Preview => "preview",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheMode {
+    // Keys are used as-is (production)
+    Shared,
+    // Keys are prefixed with the environment's namespace
+    Namespaced,
+    // Nothing is read from or written to the response cache
+    Bypass,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorsPolicy {
+    AnyOrigin,
+    // Only these origins get an Access-Control-Allow-Origin header
+    AllowList(Vec<String>),
+}
+
+impl CorsPolicy {
+    fn parse(value: &str) -> Self {
+        let origins: Vec<String> = value
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        // An empty list allows no origins; only `*` opens the API to all of them
+        if origins.iter().any(|origin| origin == "*") {
+            Self::AnyOrigin
+        } else {
+            Self::AllowList(origins)
+        }
+    }
+
+    // Access-Control-Allow-Origin value for a request's Origin header
+    pub fn allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        match self {
+            Self::AnyOrigin => Some("*".to_string()),
+            Self::AllowList(origins) => {
+                let origin = origin?.trim_end_matches('/');
+                origins
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+                    .then(|| origin.to_string())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub environment: Environment,
+    pub cache_mode: CacheMode,
+    pub cache_namespace: String,
+    pub rate_limit_per_minute: u32,
+    pub cors: CorsPolicy,
+    pub verbose_logs: bool,
+    // Strip credentials and parameter values from logs
+    pub redact_logs: bool,
+    // Add X-Environment / X-Cache-Key headers to proxied responses
+    pub debug_headers: bool,
+    // Forward a caller's own api_key (and signing secret) instead of ours
+    pub allow_client_api_keys: bool,
+}
+
+impl Config {
+    pub fn defaults(environment: Environment) -> Self {
+        match environment {
+            Environment::Production => Self {
+                environment,
+                cache_mode: CacheMode::Shared,
+                cache_namespace: String::new(),
+                rate_limit_per_minute: 100,
+                // Strict: only origins listed in CORS_ALLOWED_ORIGINS
+                cors: CorsPolicy::AllowList(Vec::new()),
+                verbose_logs: false,
+                redact_logs: true,
+                debug_headers: false,
+                allow_client_api_keys: false,
+            },
+            Environment::Staging => Self {
+                environment,
+                cache_mode: CacheMode::Namespaced,
+                cache_namespace: "staging".to_string(),
+                rate_limit_per_minute: 300,
+                cors: CorsPolicy::AnyOrigin,
+                verbose_logs: false,
+                redact_logs: true,
+                debug_headers: false,
+                allow_client_api_keys: false,
+            },
+            Environment::Preview => Self {
+                environment,
+                cache_mode: CacheMode::Namespaced,
+                cache_namespace: "preview".to_string(),
+                rate_limit_per_minute: 100,
+                cors: CorsPolicy::AnyOrigin,
+                verbose_logs: true,
+                redact_logs: false,
+                debug_headers: true,
+                allow_client_api_keys: false,
+            },
+        }
+    }
+
+    // Build from a var lookup so the override rules can be tested without an Env
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let environment = Environment::parse(&var("ENVIRONMENT").unwrap_or_default());
+        let mut config = Self::defaults(environment);
+        let flag = |name: &str| var(name).and_then(|value| parse_bool(&value));
+
+        if let Some(namespace) = var("CACHE_NAMESPACE") {
+            config.cache_namespace = namespace.trim().to_string();
+        }
+        match var("CACHE_MODE").as_deref().map(str::trim) {
+            Some("shared") => config.cache_mode = CacheMode::Shared,
+            Some("namespaced") => config.cache_mode = CacheMode::Namespaced,
+            Some("bypass") => config.cache_mode = CacheMode::Bypass,
+            _ => {}
+        }
+        if let Some(limit) = var("RATE_LIMIT_PER_MINUTE").and_then(|v| v.trim().parse().ok()) {
+            config.rate_limit_per_minute = limit;
+        }
+        if let Some(origins) = var("CORS_ALLOWED_ORIGINS") {
+            config.cors = CorsPolicy::parse(&origins);
+        }
+        if let Some(verbose) = flag("VERBOSE_LOGS") {
+            config.verbose_logs = verbose;
+        }
+        if let Some(redact) = flag("REDACT_LOGS") {
+            config.redact_logs = redact;
+        }
+        if let Some(debug) = flag("DEBUG_HEADERS") {
+            config.debug_headers = debug;
+        }
+        if let Some(allow) = flag("ALLOW_CLIENT_API_KEYS") {
+            config.allow_client_api_keys = allow;
+        }
+
+        config
+    }
+
+    pub fn from_env(env: &Env) -> Self {
+        Self::from_vars(|name| env.var(name).ok().map(|value| value.to_string()))
+    }
+
+    // KV key for a response cache entry, or None when the cache is bypassed
+    pub fn cache_key(&self, key: &str) -> Option<String> {
+        match self.cache_mode {
+            CacheMode::Bypass => None,
+            CacheMode::Namespaced if !self.cache_namespace.is_empty() => {
+                Some(format!("{}:{key}", self.cache_namespace))
+            }
+            _ => Some(key.to_string()),
+        }
+    }
+
+    // Rate limit counters are kept apart per environment, except production's
+    pub fn rate_limit_key(&self, key: &str) -> String {
+        match self.environment {
+            Environment::Production => key.to_string(),
+            other => format!("{}:{key}", other.as_str()),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> Config {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_environment_defaults() {
+        let production = config(&[("ENVIRONMENT", "production")]);
+        assert_eq!(
+            production.cache_key("lastfm:a").as_deref(),
+            Some("lastfm:a")
+        );
+        assert_eq!(production.cors.allow_origin(Some("https://x.dev")), None);
+        assert!(production.redact_logs && !production.debug_headers);
+        assert!(!production.allow_client_api_keys);
+
+        let preview = config(&[("ENVIRONMENT", "preview")]);
+        assert_eq!(
+            preview.cache_key("lastfm:a").as_deref(),
+            Some("preview:lastfm:a")
+        );
+        assert!(preview.verbose_logs && preview.debug_headers && !preview.redact_logs);
+
+        let staging = config(&[("ENVIRONMENT", "staging")]);
+        assert_eq!(staging.rate_limit_per_minute, 300);
+        assert_eq!(
+            staging.rate_limit_key("rate_limit:1.2.3.4"),
+            "staging:rate_limit:1.2.3.4"
+        );
+    }
+
+    #[test]
+    fn test_unknown_environment_is_production() {
+        assert_eq!(config(&[]).environment, Environment::Production);
+        assert_eq!(
+            config(&[("ENVIRONMENT", "qa")]).environment,
+            Environment::Production
+        );
+    }
+
+    #[test]
+    fn test_vars_override_defaults() {
+        let preview = config(&[
+            ("ENVIRONMENT", "preview"),
+            ("CACHE_MODE", "bypass"),
+            ("RATE_LIMIT_PER_MINUTE", "10"),
+            ("DEBUG_HEADERS", "false"),
+            ("ALLOW_CLIENT_API_KEYS", "true"),
+        ]);
+        assert_eq!(preview.cache_key("lastfm:a"), None);
+        assert_eq!(preview.rate_limit_per_minute, 10);
+        assert!(!preview.debug_headers);
+        assert!(preview.allow_client_api_keys);
+
+        let production = config(&[
+            ("CACHE_MODE", "namespaced"),
+            ("CACHE_NAMESPACE", "canary"),
+            ("REDACT_LOGS", "no"),
+        ]);
+        assert_eq!(
+            production.cache_key("lastfm:a").as_deref(),
+            Some("canary:lastfm:a")
+        );
+        assert!(!production.redact_logs);
+    }
+
+    #[test]
+    fn test_cors_allow_list() {
+        let production = config(&[(
+            "CORS_ALLOWED_ORIGINS",
+            "https://app.example.com/, https://admin.example.com",
+        )]);
+        assert_eq!(
+            production
+                .cors
+                .allow_origin(Some("https://app.example.com"))
+                .as_deref(),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            production.cors.allow_origin(Some("https://evil.test")),
+            None
+        );
+        assert_eq!(production.cors.allow_origin(None), None);
+
+        let open = config(&[("CORS_ALLOWED_ORIGINS", "*")]);
+        assert_eq!(open.cors.allow_origin(None).as_deref(), Some("*"));
+
+        let blank = config(&[("ENVIRONMENT", "preview"), ("CORS_ALLOWED_ORIGINS", " , ")]);
+        assert_eq!(blank.cors, CorsPolicy::AllowList(Vec::new()));
+        assert_eq!(blank.cors.allow_origin(Some("https://x.dev")), None);
+    }
+}
@@ -0,0 +1,229 @@
+// Diagnostic response headers, collected while a request is handled and
+// applied once by `Diagnostics::finish`:
+//
+//   X-Request-Id, X-Cache, X-Cache-Age, X-Cache-TTL, X-Upstream-Time,
+//   X-RateLimit-Limit/Remaining/Reset, Server-Timing and, when DEBUG_HEADERS
+//   is on, X-Environment and X-Cache-Key.
+
+use crate::config::Config;
+use crate::error::ApiResult;
+use crate::middleware::{self, RateLimitStatus};
+use crate::utils::CacheMetadata;
+use chrono::{DateTime, Utc};
+use worker::{Env, Request, Response};
+
+// Longest caller-supplied request id we echo back
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+pub struct Diagnostics {
+    pub request_id: String,
+    started: DateTime<Utc>,
+    config: Config,
+    pub rate_limit: Option<RateLimitStatus>,
+    // "HIT", "MISS", "STALE" or "NEGATIVE-HIT"
+    pub cache_status: Option<&'static str>,
+    pub cache: Option<CacheMetadata>,
+    pub cache_key: Option<String>,
+    pub upstream_ms: Option<i64>,
+}
+
+// Reuse a well-formed X-Request-Id from the caller, then Cloudflare's ray id,
+// otherwise make one up
+fn request_id(incoming: Option<&str>, ray: Option<&str>) -> String {
+    let well_formed = |id: &&str| {
+        !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+
+    if let Some(id) = incoming.filter(well_formed).or(ray.filter(well_formed)) {
+        return id.to_string();
+    }
+
+    let mut bytes = [0u8; 16];
+    let _ = getrandom::getrandom(&mut bytes);
+    hex::encode(bytes)
+}
+
+impl Diagnostics {
+    pub fn start(req: &Request, env: &Env) -> Self {
+        let header = |name: &str| req.headers().get(name).ok().flatten();
+
+        Self {
+            request_id: request_id(
+                header("X-Request-Id").as_deref(),
+                header("CF-Ray").as_deref(),
+            ),
+            started: Utc::now(),
+            config: Config::from_env(env),
+            rate_limit: None,
+            cache_status: None,
+            cache: None,
+            cache_key: None,
+            upstream_ms: None,
+        }
+    }
+
+    // Apply the per-IP rate limit, keeping the remaining budget for the headers
+    pub async fn rate_limit(&mut self, req: &Request, env: &Env) -> ApiResult<()> {
+        match middleware::rate_limit(req, env).await {
+            Ok(status) => {
+                self.rate_limit = Some(status);
+                Ok(())
+            }
+            Err(e) => {
+                if e.error == 29 {
+                    self.rate_limit = Some(RateLimitStatus::exhausted(
+                        self.config.rate_limit_per_minute,
+                    ));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Header name/value pairs for what was recorded, as of `now`
+    fn headers(&self, now: DateTime<Utc>) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("X-Request-Id", self.request_id.clone())];
+        let mut timing = Vec::new();
+
+        if let Some(status) = self.cache_status {
+            headers.push(("X-Cache", status.to_string()));
+            timing.push(format!("cache;desc=\"{status}\""));
+        }
+        if let Some(cache) = self.cache {
+            let age = cache.age(now.timestamp());
+            headers.push(("X-Cache-Age", age.to_string()));
+            headers.push(("X-Cache-TTL", (cache.ttl as i64 - age).max(0).to_string()));
+        }
+        if let Some(upstream_ms) = self.upstream_ms {
+            headers.push(("X-Upstream-Time", upstream_ms.to_string()));
+            timing.push(format!("upstream;dur={upstream_ms}"));
+        }
+        if let Some(status) = self.rate_limit {
+            headers.push(("X-RateLimit-Limit", status.limit.to_string()));
+            headers.push(("X-RateLimit-Remaining", status.remaining.to_string()));
+            headers.push(("X-RateLimit-Reset", status.reset_secs.to_string()));
+        }
+
+        timing.push(format!(
+            "total;dur={}",
+            (now - self.started).num_milliseconds()
+        ));
+        headers.push(("Server-Timing", timing.join(", ")));
+
+        if self.config.debug_headers {
+            headers.push((
+                "X-Environment",
+                self.config.environment.as_str().to_string(),
+            ));
+            if let Some(key) = self
+                .cache_key
+                .as_deref()
+                .and_then(|key| self.config.cache_key(key))
+            {
+                headers.push(("X-Cache-Key", key));
+            }
+        }
+
+        headers
+    }
+
+    // Add the diagnostic headers to a finished response
+    pub fn finish(
+        &self,
+        response: Result<Response, worker::Error>,
+    ) -> Result<Response, worker::Error> {
+        let mut response = response?;
+        let headers = response.headers_mut();
+        for (name, value) in self.headers(Utc::now()) {
+            headers.set(name, &value)?;
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Environment;
+
+    fn diagnostics(config: Config) -> Diagnostics {
+        Diagnostics {
+            request_id: "req-1".to_string(),
+            started: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            config,
+            rate_limit: None,
+            cache_status: None,
+            cache: None,
+            cache_key: None,
+            upstream_ms: None,
+        }
+    }
+
+    fn header<'a>(headers: &'a [(&str, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn test_request_id_prefers_caller_then_ray() {
+        assert_eq!(request_id(Some("abc-123"), Some("ray")), "abc-123");
+        assert_eq!(
+            request_id(None, Some("8a1b2c3d4e5f-LHR")),
+            "8a1b2c3d4e5f-LHR"
+        );
+        assert_eq!(request_id(Some("bad id\r\n"), Some("ray-1")), "ray-1");
+        assert_eq!(request_id(Some("bad id\r\n"), None).len(), 32);
+        assert_eq!(request_id(Some(&"x".repeat(65)), None).len(), 32);
+    }
+
+    #[test]
+    fn test_cache_miss_headers() {
+        let mut diagnostics = diagnostics(Config::defaults(Environment::Production));
+        diagnostics.cache_status = Some("MISS");
+        diagnostics.upstream_ms = Some(182);
+        diagnostics.rate_limit = Some(RateLimitStatus {
+            limit: 100,
+            remaining: 97,
+            reset_secs: 60,
+        });
+
+        let now = diagnostics.started + chrono::Duration::milliseconds(250);
+        let headers = diagnostics.headers(now);
+        assert_eq!(header(&headers, "X-Request-Id"), Some("req-1"));
+        assert_eq!(header(&headers, "X-Upstream-Time"), Some("182"));
+        assert_eq!(header(&headers, "X-RateLimit-Remaining"), Some("97"));
+        assert_eq!(
+            header(&headers, "Server-Timing"),
+            Some("cache;desc=\"MISS\", upstream;dur=182, total;dur=250")
+        );
+        assert_eq!(header(&headers, "X-Cache-Age"), None);
+        assert_eq!(header(&headers, "X-Environment"), None);
+    }
+
+    #[test]
+    fn test_cache_hit_age_and_remaining_ttl() {
+        let mut diagnostics = diagnostics(Config::defaults(Environment::Preview));
+        diagnostics.cache_status = Some("HIT");
+        diagnostics.cache_key = Some("lastfm:artist.getInfo:artist=cher".to_string());
+        diagnostics.cache = Some(CacheMetadata {
+            cached_at: 1_700_000_000 - 600,
+            ttl: 3600,
+            negative: false,
+        });
+
+        let headers = diagnostics.headers(diagnostics.started);
+        assert_eq!(header(&headers, "X-Cache-Age"), Some("600"));
+        assert_eq!(header(&headers, "X-Cache-TTL"), Some("3000"));
+        assert_eq!(header(&headers, "X-Environment"), Some("preview"));
+        assert_eq!(
+            header(&headers, "X-Cache-Key"),
+            Some("preview:lastfm:artist.getInfo:artist=cher")
+        );
+    }
+}
@@ -5,6 +5,10 @@ use worker::{Error, Response};
 pub struct ApiError {
     pub error: u32,
     pub message: String,
+    // Set when the proxy's own upstream budget ran out, which shares error 29
+    // with per-client limits and Last.fm's own rate limiting
+    #[serde(skip)]
+    upstream_throttled: bool,
 }
 
 impl ApiError {
@@ -12,6 +16,7 @@ impl ApiError {
         Self {
             error: code,
             message: message.into(),
+            upstream_throttled: false,
         }
     }
 
@@ -26,6 +31,10 @@ impl ApiError {
         )
     }
 
+    pub fn invalid_session_key() -> Self {
+        Self::new(9, "Invalid session key - Please re-authenticate")
+    }
+
     pub fn service_offline() -> Self {
         Self::new(
             11,
@@ -51,6 +60,27 @@ impl ApiError {
         )
     }
 
+    pub fn upstream_throttled() -> Self {
+        Self {
+            upstream_throttled: true,
+            ..Self::new(
+                29,
+                "Rate limit exceeded - The proxy has used its Last.fm request budget. Try again shortly.",
+            )
+        }
+    }
+
+    // Distinguishes the proxy's own upstream budget from per-client rate limits
+    pub fn is_upstream_throttled(&self) -> bool {
+        self.upstream_throttled
+    }
+
+    // Errors that will repeat for the same request (unknown artist, bad
+    // parameters) and can be cached briefly. Transient errors never qualify.
+    pub fn is_deterministic(&self) -> bool {
+        matches!(self.error, 6 | 7)
+    }
+
     pub fn to_response(&self) -> Result<Response, Error> {
         Response::ok(serde_json::to_string(self).unwrap()).map(|mut resp| {
             resp.headers_mut()
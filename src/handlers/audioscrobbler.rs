@@ -0,0 +1,153 @@
+// Audioscrobbler 1.2 compatibility for players that predate the 2.0 API.
+// The handshake password is a proxy token from the session vault; now-playing
+// notifications and submissions become signed track.updateNowPlaying and
+// track.scrobble calls for the session behind it.
+
+use crate::common::audioscrobbler::{
+    handshake_ok, now_playing_params, submission_params, Handshake, Reply,
+};
+use crate::common::url::parse_body_params;
+use crate::middleware::rate_limit;
+use crate::utils::{
+    parse_lastfm_error, parse_query_params, proxy_post_to_lastfm, sign_lastfm_params,
+};
+use crate::vault::SessionVault;
+use std::collections::HashMap;
+use worker::{console_error, console_log, Env, Request, Response, RouteContext};
+
+// Invalid session key
+const LASTFM_INVALID_SESSION: u32 = 9;
+
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+// Maps protocol fields to Last.fm parameters
+type Translate = fn(&HashMap<String, String>) -> Result<HashMap<String, String>, String>;
+
+fn plain(body: String) -> Result<Response, worker::Error> {
+    let mut response = Response::ok(body)?;
+    response
+        .headers_mut()
+        .set("Content-Type", "text/plain; charset=utf-8")?;
+    Ok(response)
+}
+
+fn vault(env: &Env) -> Result<SessionVault, Reply> {
+    SessionVault::from_env(env).ok_or_else(|| {
+        console_log!("Session vault is not configured");
+        Reply::failed("Legacy submissions are not enabled")
+    })
+}
+
+// GET /audioscrobbler/?hs=true&p=1.2.1&c=...&v=...&u=...&t=...&a=...
+pub async fn handshake(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+
+    if let Err(e) = rate_limit(&req, &env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return plain(Reply::failed(e.message).body());
+    }
+
+    let params = parse_query_params(&req)?;
+    let handshake = match Handshake::from_params(&params) {
+        Ok(handshake) => handshake,
+        Err(reply) => return plain(reply.body()),
+    };
+    if !handshake.is_timely(chrono::Utc::now().timestamp()) {
+        return plain(Reply::BadTime.body());
+    }
+    let vault = match vault(&env) {
+        Ok(vault) => vault,
+        Err(reply) => return plain(reply.body()),
+    };
+
+    let session = match vault.resolve_handshake(&handshake).await {
+        Ok(Some(resolved)) => vault.open_legacy_session(&resolved.id).await,
+        Ok(None) => return plain(Reply::BadAuth.body()),
+        Err(e) => Err(e),
+    };
+    let session = match session {
+        Ok(session) => session,
+        Err(e) => {
+            console_error!("Legacy handshake failed: {}", e);
+            return plain(Reply::failed("Temporary error").body());
+        }
+    };
+
+    console_log!(
+        "Legacy handshake for {} (client {})",
+        handshake.user,
+        handshake.client
+    );
+
+    let origin = req.url()?.origin().ascii_serialization();
+    plain(handshake_ok(
+        &session,
+        &format!("{origin}/audioscrobbler/nowplaying"),
+        &format!("{origin}/audioscrobbler/submission"),
+    ))
+}
+
+// Resolve the `s` session, map the fields and make the signed call
+async fn forward(mut req: Request, env: &Env, method: &str, translate: Translate) -> Reply {
+    if let Err(e) = rate_limit(&req, env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return Reply::failed(e.message);
+    }
+
+    // The protocol body is always form encoded, whatever the client labels it
+    let params = match req.text().await {
+        Ok(body) => parse_body_params(FORM_CONTENT_TYPE, &body).unwrap_or_default(),
+        Err(_) => return Reply::failed("Unreadable request body"),
+    };
+    let vault = match vault(env) {
+        Ok(vault) => vault,
+        Err(reply) => return reply,
+    };
+    let session = match params.get("s") {
+        Some(id) => vault.legacy_session(id).await,
+        None => Ok(None),
+    };
+    let session = match session {
+        Ok(Some(session)) => session,
+        Ok(None) => return Reply::BadSession,
+        Err(e) => {
+            console_error!("Failed to read legacy session: {}", e);
+            return Reply::failed("Temporary error");
+        }
+    };
+
+    let mut call = match translate(&params) {
+        Ok(call) => call,
+        Err(message) => return Reply::failed(message),
+    };
+    call.insert("sk".to_string(), session.session_key);
+    let key_id = session.api_key_id.as_deref();
+    if let Err(e) = sign_lastfm_params(env, method, &mut call, key_id).await {
+        return Reply::failed(e.message);
+    }
+
+    let body = match proxy_post_to_lastfm(env, method, call).await {
+        Ok(mut response) => match response.text().await {
+            Ok(body) => body,
+            Err(_) => return Reply::failed("Temporary error"),
+        },
+        Err(e) => return Reply::failed(e.message),
+    };
+    match parse_lastfm_error(&body) {
+        Some(e) if e.error == LASTFM_INVALID_SESSION => Reply::BadSession,
+        Some(e) => Reply::failed(e.message),
+        None => Reply::Ok,
+    }
+}
+
+// POST /audioscrobbler/nowplaying with s, a, t, b, l, n, m
+pub async fn now_playing(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let reply = forward(req, &ctx.env, "track.updateNowPlaying", now_playing_params).await;
+    plain(reply.body())
+}
+
+// POST /audioscrobbler/submission with s and a[i], t[i], i[i], o[i], r[i], l[i], b[i], n[i], m[i]
+pub async fn submission(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let reply = forward(req, &ctx.env, "track.scrobble", submission_params).await;
+    plain(reply.body())
+}
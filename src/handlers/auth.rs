@@ -1,6 +1,8 @@
 // Authentication handlers for Last.fm auth methods
 
+use crate::diagnostics::Diagnostics;
 use serde_json::json;
+use url::Url;
 use worker::{Request, Response, RouteContext};
 
 pub async fn get_session(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
@@ -14,22 +16,47 @@ pub async fn get_mobile_session(
     super::handle_auth_request(req, ctx, "auth.getMobileSession").await
 }
 
-pub async fn get_auth_url(_req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
-    // Get API key from environment
-    let api_key = ctx
-        .env
-        .secret("LASTFM_API_KEY")
-        .map_err(|_| worker::Error::from("LASTFM_API_KEY not configured"))?
-        .to_string();
+// Credentials must not travel in query strings, so the GET variant is rejected
+pub async fn get_mobile_session_via_query(
+    _req: Request,
+    _ctx: RouteContext<()>,
+) -> Result<Response, worker::Error> {
+    let error = json!({
+        "error": 3,
+        "message": "Invalid Method - auth.getMobileSession requires POST with a form or JSON body"
+    });
 
-    let callback = "http://localhost:41419/auth/callback";
-    let auth_url = format!("https://www.last.fm/api/auth/?api_key={api_key}&cb={callback}");
+    let mut resp = Response::error(error.to_string(), 405)?;
+    resp.headers_mut().set("Content-Type", "application/json")?;
+    resp.headers_mut().set("Allow", "POST")?;
+    super::add_cors_headers(resp)
+}
+
+pub async fn get_auth_url(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let diagnostics = Diagnostics::start(&req, &ctx.env);
+
+    // Get an API key from the pool
+    let credentials = match crate::key_pool::credentials(&ctx.env, None).await {
+        Ok(credentials) => credentials,
+        Err(e) => return diagnostics.finish(e.to_response()),
+    };
+
+    // The token Last.fm hands back only works with this key, so the callback
+    // carries its id for the auth.getSession call (as `key_id`)
+    let key_id = credentials.id();
+    let callback = format!("http://localhost:41419/auth/callback?key_id={key_id}");
+    let auth_url = Url::parse_with_params(
+        "https://www.last.fm/api/auth/",
+        [("api_key", credentials.api_key.as_str()), ("cb", &callback)],
+    )
+    .map_err(|e| worker::Error::RustError(e.to_string()))?;
 
     let response = json!({
-        "auth_url": auth_url
+        "auth_url": auth_url.as_str(),
+        "key_id": key_id
     });
 
     let mut resp = Response::ok(response.to_string())?;
     resp.headers_mut().set("Content-Type", "application/json")?;
-    super::add_cors_headers(resp)
+    diagnostics.finish(super::add_cors_headers(resp))
 }
@@ -0,0 +1,112 @@
+// Embeddable SVG badges: /badge/:user/nowplaying.svg and
+// /badge/:user/top-artists.svg?period=7day&count=5&theme=dark&size=small
+
+use crate::common::badge::{
+    now_playing_badge, parse_badge_name, placeholder_badge, placeholder_message, top_artists_badge,
+    BadgeKind, BadgeOptions,
+};
+use crate::common::format::find_list_items;
+use crate::common::validation::period_param;
+use crate::error::{ApiError, ApiResult};
+use crate::middleware::{add_cors_headers, rate_limit};
+use crate::models::CacheKey;
+use crate::utils::parse_query_params;
+use serde_json::Value;
+use std::collections::HashMap;
+use worker::{console_log, Env, Request, Response, RouteContext};
+
+// Now playing goes stale quickly; top artists follow the 1 hour response cache
+const NOW_PLAYING_MAX_AGE_SECS: u32 = 30;
+const TOP_ARTISTS_MAX_AGE_SECS: u32 = 1800;
+const PLACEHOLDER_MAX_AGE_SECS: u32 = 300;
+
+fn svg_response(svg: String, max_age: u32) -> Result<Response, worker::Error> {
+    let mut response = Response::ok(svg)?;
+    let headers = response.headers_mut();
+    headers.set("Content-Type", "image/svg+xml; charset=utf-8")?;
+    headers.set("Cache-Control", &format!("public, max-age={max_age}"))?;
+    add_cors_headers(response)
+}
+
+// Ranked (artist, playcount) pairs from the cached user.getTopArtists response
+async fn top_artists(
+    env: &Env,
+    user: &str,
+    period: &str,
+    count: usize,
+) -> ApiResult<Vec<(String, String)>> {
+    let mut params = HashMap::new();
+    params.insert("user".to_string(), user.to_string());
+    params.insert("period".to_string(), period.to_string());
+    params.insert("limit".to_string(), count.to_string());
+    let cache_key = params.cache_key("user.getTopArtists");
+
+    let outcome = super::fetch_cached(env, "user.getTopArtists", params, &cache_key).await?;
+    if let Some(e) = outcome.negative_error() {
+        return Err(e);
+    }
+    let document: Value =
+        serde_json::from_str(&outcome.body).map_err(|_| ApiError::temporary_error())?;
+
+    let text = |value: Option<&Value>| {
+        value
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    Ok(find_list_items(&document)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|artist| (text(artist.get("name")), text(artist.get("playcount"))))
+        .collect())
+}
+
+pub async fn handle(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+    let user = ctx.param("user").cloned().unwrap_or_default();
+    let Some(kind) = ctx.param("badge").and_then(|name| parse_badge_name(name)) else {
+        return Response::error("Not Found", 404);
+    };
+
+    let params = parse_query_params(&req)?;
+    let options = BadgeOptions::from_params(&params);
+
+    if let Err(e) = rate_limit(&req, &env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return svg_response(placeholder_badge(&user, &e.message, &options), 60);
+    }
+
+    let badge = match kind {
+        BadgeKind::NowPlaying => super::stream::listening_state(&env, &user)
+            .await
+            .map(|state| {
+                (
+                    now_playing_badge(&user, &state, &options),
+                    NOW_PLAYING_MAX_AGE_SECS,
+                )
+            }),
+        BadgeKind::TopArtists => {
+            // An unknown period falls back to the default so the badge still renders
+            let period = period_param(&params, "7day").unwrap_or_else(|_| "7day".to_string());
+            top_artists(&env, &user, &period, options.count)
+                .await
+                .map(|artists| {
+                    let svg = top_artists_badge(&user, &period, &artists, &options);
+                    (svg, TOP_ARTISTS_MAX_AGE_SECS)
+                })
+        }
+    };
+
+    // Private or unknown users still get an image, so embeds never show as broken
+    match badge {
+        Ok((svg, max_age)) => svg_response(svg, max_age),
+        Err(e) => {
+            console_log!("Badge for {} unavailable: {:?}", user, e);
+            let message = placeholder_message(e.error);
+            svg_response(
+                placeholder_badge(&user, message, &options),
+                PLACEHOLDER_MAX_AGE_SECS,
+            )
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// POST /batch: several public Last.fm reads in one request, run a few at a time
+// through the same validation, cache and rate limit as single requests. An
+// X-Request-Signature covers the query parameters plus the body's digest.
+
+use super::v2::V2_METHODS;
+use crate::common::batch::{self, BatchItem, BODY_DIGEST_PARAM, MAX_CONCURRENT_ITEMS};
+use crate::common::envelope::error_status;
+use crate::common::fields::FieldSelection;
+use crate::config::Config;
+use crate::diagnostics::Diagnostics;
+use crate::error::ApiError;
+use crate::middleware::{
+    add_cors_headers, rate_limit_many, validate_method_params, RateLimitStatus,
+};
+use crate::models::CacheKey;
+use crate::utils::{parse_query_params, validate_signature};
+use futures::future::{select, Either};
+use futures::stream::{self, StreamExt};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::time::Duration;
+use worker::{console_log, Delay, Env, Request, Response, RouteContext};
+
+// Total time the batch may spend; unfinished items are reported as timed out
+const BATCH_TIMEOUT_MS: u64 = 10_000;
+
+pub async fn handle(mut req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+    let mut diagnostics = Diagnostics::start(&req, &env);
+    let response = batch_response(&mut req, &env, &mut diagnostics).await;
+    diagnostics.finish(response)
+}
+
+async fn batch_response(
+    req: &mut Request,
+    env: &Env,
+    diagnostics: &mut Diagnostics,
+) -> Result<Response, worker::Error> {
+    // A signed batch is checked once, so the signature must cover the items
+    let body = req.text().await.unwrap_or_default();
+    let mut signed = parse_query_params(req)?;
+    signed.insert(BODY_DIGEST_PARAM.to_string(), batch::body_digest(&body));
+    if let Err(e) = validate_signature(req, env, &signed) {
+        console_log!("Validation error: {:?}", e);
+        return e.to_response();
+    }
+
+    let items = match batch::parse_batch(&body) {
+        Ok(items) => items,
+        Err(msg) => return ApiError::invalid_parameters(msg).to_response(),
+    };
+
+    // Every item counts against the caller's quota; items past the budget are
+    // answered with a rate limit error instead of failing the whole batch
+    let admitted = match rate_limit_many(req, env, items.len() as u32).await {
+        Ok((admitted, status)) => {
+            diagnostics.rate_limit = Some(status);
+            admitted as usize
+        }
+        Err(e) => {
+            console_log!("Rate limit error: {:?}", e);
+            if e.error == 29 {
+                let limit = Config::from_env(env).rate_limit_per_minute;
+                diagnostics.rate_limit = Some(RateLimitStatus::exhausted(limit));
+            }
+            return e.to_response();
+        }
+    };
+    console_log!("Running batch of {} ({} admitted)", items.len(), admitted);
+
+    // Items fill their slot as they finish, so a timeout keeps finished results
+    let methods: Vec<String> = items.iter().map(|item| item.method.clone()).collect();
+    let slots = RefCell::new(vec![None; items.len()]);
+    let runs = items.into_iter().enumerate().map(|(index, item)| {
+        let slots = &slots;
+        async move {
+            let result = if index >= admitted {
+                let e = ApiError::rate_limit_exceeded();
+                batch::error_result(&item.method, 429, e.error, &e.message)
+            } else {
+                run_item(env, item).await
+            };
+            slots.borrow_mut()[index] = Some(result);
+        }
+    });
+
+    let all = stream::iter(runs).for_each_concurrent(MAX_CONCURRENT_ITEMS, |run| run);
+    let timeout = Delay::from(Duration::from_millis(BATCH_TIMEOUT_MS));
+    if let Either::Right(_) = select(Box::pin(all), timeout).await {
+        console_log!("Batch timed out after {}ms", BATCH_TIMEOUT_MS);
+    }
+    let results: Vec<Value> = slots
+        .into_inner()
+        .into_iter()
+        .zip(&methods)
+        .map(|(result, method)| {
+            result.unwrap_or_else(|| {
+                let e = ApiError::temporary_error();
+                batch::error_result(method, 504, e.error, "Timed out waiting for Last.fm")
+            })
+        })
+        .collect();
+
+    let mut response = Response::ok(json!({ "results": results }).to_string())?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/json")?;
+    add_cors_headers(response)
+}
+
+fn failed(method: &str, e: &ApiError) -> Value {
+    let status = if e.is_upstream_throttled() {
+        503
+    } else {
+        error_status(e.error)
+    };
+    batch::error_result(method, status, e.error, &e.message)
+}
+
+// One sub-request, handled like a GET to the method's own route
+async fn run_item(env: &Env, item: BatchItem) -> Value {
+    let method = item.method.as_str();
+    if !V2_METHODS.contains(&method) {
+        return failed(
+            method,
+            &ApiError::new(
+                3,
+                "Invalid Method - No method with that name in this package",
+            ),
+        );
+    }
+
+    let mut params = item.string_params();
+    params.remove("format");
+    params.remove("callback");
+    if let Err(e) = validate_method_params(method, &params) {
+        return failed(method, &e);
+    }
+
+    let fields = params.get("fields").and_then(|f| FieldSelection::parse(f));
+    let cache_key = params.cache_key(method);
+
+    let outcome = match super::fetch_cached(env, method, params, &cache_key).await {
+        Ok(outcome) => outcome,
+        Err(e) => return failed(method, &e),
+    };
+    if let Some(e) = outcome.negative_error() {
+        return failed(method, &e);
+    }
+
+    let document: Value = match serde_json::from_str(&outcome.body) {
+        Ok(document) => document,
+        Err(_) => return failed(method, &ApiError::temporary_error()),
+    };
+    let document = match fields {
+        Some(fields) => fields.apply(&document),
+        None => document,
+    };
+
+    batch::success_result(method, outcome.cache_status, document)
+}
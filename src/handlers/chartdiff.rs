@@ -0,0 +1,113 @@
+// GET /user/weeklyChartDiff?user=rj&type=artist[&from=...]: one week's chart
+// annotated against the week before it. Each week is fetched with the same
+// params as /user/getWeekly*Chart, so the response cache is shared.
+
+use crate::common::chartdiff::{
+    chart_entries, chart_weeks, diff, select_weeks, to_json, ChartEntry, ChartType, ChartWeek,
+};
+use crate::diagnostics::Diagnostics;
+use crate::error::{ApiError, ApiResult};
+use crate::middleware::{add_cors_headers, validate_method_params};
+use crate::utils::parse_query_params;
+use futures::future::join_all;
+use std::collections::HashMap;
+use worker::{console_log, Env, Request, Response, RouteContext};
+
+pub async fn handle(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+    let mut diagnostics = Diagnostics::start(&req, &env);
+    let response = chart_diff_response(&req, &env, &mut diagnostics).await;
+    diagnostics.finish(response)
+}
+
+async fn week_entries(
+    env: &Env,
+    user: &str,
+    chart: ChartType,
+    week: ChartWeek,
+) -> ApiResult<Vec<ChartEntry>> {
+    let mut params = HashMap::new();
+    params.insert("user".to_string(), user.to_string());
+    params.insert("from".to_string(), week.from.to_string());
+    params.insert("to".to_string(), week.to.to_string());
+    let document = super::fetch_cached_json(env, chart.method(), params).await?;
+    Ok(chart_entries(&document))
+}
+
+async fn chart_diff_response(
+    req: &Request,
+    env: &Env,
+    diagnostics: &mut Diagnostics,
+) -> Result<Response, worker::Error> {
+    if let Err(e) = diagnostics.rate_limit(req, env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return e.to_response();
+    }
+
+    let params = parse_query_params(req)?;
+    if let Err(e) = validate_method_params("user.getWeeklyChartList", &params) {
+        return e.to_response();
+    }
+    let user = params["user"].clone();
+    let Some(chart) = ChartType::parse(params.get("type").map_or("artist", String::as_str)) else {
+        return ApiError::invalid_parameters("Invalid type - must be one of artist, album, track")
+            .to_response();
+    };
+    let from = match params.get("from").map(|from| from.parse::<i64>()) {
+        None => None,
+        Some(Ok(from)) => Some(from),
+        Some(Err(_)) => {
+            return ApiError::invalid_parameters("Invalid from - must be a UNIX timestamp")
+                .to_response()
+        }
+    };
+
+    let mut list_params = HashMap::new();
+    list_params.insert("user".to_string(), user.clone());
+    let weeks = match super::fetch_cached_json(env, "user.getWeeklyChartList", list_params).await {
+        Ok(document) => chart_weeks(&document),
+        Err(e) => return e.to_response(),
+    };
+    let Some((week, earlier)) = select_weeks(&weeks, from) else {
+        return ApiError::invalid_parameters(
+            "No chart week starts at from - see user.getWeeklyChartList",
+        )
+        .to_response();
+    };
+
+    let current = week_entries(env, &user, chart, week);
+    let earlier_charts = join_all(
+        earlier
+            .iter()
+            .map(|earlier_week| week_entries(env, &user, chart, *earlier_week)),
+    );
+    let (current, earlier_charts) = futures::join!(current, earlier_charts);
+
+    let current = match current {
+        Ok(current) => current,
+        Err(e) => return e.to_response(),
+    };
+    let mut earlier_charts = earlier_charts.into_iter();
+    let previous = match earlier_charts.next() {
+        Some(Ok(previous)) => previous,
+        Some(Err(e)) => return e.to_response(),
+        None => Vec::new(),
+    };
+    // Older weeks only decide re-entries, so a failed one is skipped
+    let older: Vec<Vec<ChartEntry>> = earlier_charts.filter_map(Result::ok).collect();
+
+    let (annotated, dropped) = diff(&current, &previous, &older);
+    let body = to_json(
+        &user,
+        chart,
+        week,
+        earlier.first().copied(),
+        &annotated,
+        &dropped,
+    );
+    let mut response = Response::ok(body.to_string())?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/json")?;
+    add_cors_headers(response)
+}
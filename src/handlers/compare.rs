@@ -0,0 +1,97 @@
+// GET /user/compare?user1=rj&user2=joanofarctan&period=3month: a taste
+// compatibility score in the spirit of the retired Tasteometer, from both
+// users' cached top artists and top tags
+
+use crate::common::compare::{to_json, Profile};
+use crate::common::validation::period_param;
+use crate::diagnostics::Diagnostics;
+use crate::error::{ApiError, ApiResult};
+use crate::middleware::add_cors_headers;
+use crate::utils::parse_query_params;
+use std::collections::HashMap;
+use worker::{console_log, Env, Request, Response, RouteContext};
+
+const TOP_ARTISTS_LIMIT: &str = "100";
+const TOP_TAGS_LIMIT: &str = "50";
+
+pub async fn handle(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+    let mut diagnostics = Diagnostics::start(&req, &env);
+    let response = compare_response(&req, &env, &mut diagnostics).await;
+    diagnostics.finish(response)
+}
+
+// Top artists for the period and top tags (user.getTopTags takes no period)
+async fn profiles(env: &Env, user: &str, period: &str) -> ApiResult<(Profile, Profile)> {
+    let mut artist_params = HashMap::new();
+    artist_params.insert("user".to_string(), user.to_string());
+    artist_params.insert("period".to_string(), period.to_string());
+    artist_params.insert("limit".to_string(), TOP_ARTISTS_LIMIT.to_string());
+
+    let mut tag_params = HashMap::new();
+    tag_params.insert("user".to_string(), user.to_string());
+    tag_params.insert("limit".to_string(), TOP_TAGS_LIMIT.to_string());
+
+    let (artists, tags) = futures::join!(
+        super::fetch_cached_json(env, "user.getTopArtists", artist_params),
+        super::fetch_cached_json(env, "user.getTopTags", tag_params),
+    );
+    let artists = Profile::from_document(&artists?, "playcount");
+
+    // Tags only refine the score, so a user without them is still comparable
+    let tags = match tags {
+        Ok(document) => Profile::from_document(&document, "count"),
+        Err(e) => {
+            console_log!("Top tags unavailable for {}: {:?}", user, e);
+            Profile::default()
+        }
+    };
+    Ok((artists, tags))
+}
+
+async fn compare_response(
+    req: &Request,
+    env: &Env,
+    diagnostics: &mut Diagnostics,
+) -> Result<Response, worker::Error> {
+    if let Err(e) = diagnostics.rate_limit(req, env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return e.to_response();
+    }
+
+    let params = parse_query_params(req)?;
+    let user = |key: &str| params.get(key).filter(|user| !user.is_empty()).cloned();
+    let (Some(user1), Some(user2)) = (user("user1"), user("user2")) else {
+        return ApiError::invalid_parameters("Missing required parameters: user1 and user2")
+            .to_response();
+    };
+    if user1.eq_ignore_ascii_case(&user2) {
+        return ApiError::invalid_parameters("user1 and user2 must be different users")
+            .to_response();
+    }
+    let period = match period_param(&params, "overall") {
+        Ok(period) => period,
+        Err(message) => return ApiError::invalid_parameters(message).to_response(),
+    };
+
+    let (first, second) = futures::join!(
+        profiles(env, &user1, &period),
+        profiles(env, &user2, &period),
+    );
+    let ((artists1, tags1), (artists2, tags2)) = match (first, second) {
+        (Ok(first), Ok(second)) => (first, second),
+        (Err(e), _) | (_, Err(e)) => return e.to_response(),
+    };
+
+    let body = to_json(
+        (&user1, &user2),
+        &period,
+        (&artists1, &artists2),
+        (&tags1, &tags2),
+    );
+    let mut response = Response::ok(body.to_string())?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/json")?;
+    add_cors_headers(response)
+}
@@ -0,0 +1,86 @@
+// Atom and RSS feeds rendered from the cached user.* responses:
+// /feed/:user/recent.atom, /feed/:user/loved.rss, /feed/:user/weekly-artists.atom, ...
+
+use crate::common::feed::{
+    self, build_feed, http_date, is_not_modified, parse_feed_name, FeedKind,
+};
+use crate::error::ApiError;
+use crate::middleware::{add_cors_headers, rate_limit};
+use crate::models::CacheKey;
+use serde_json::Value;
+use std::collections::HashMap;
+use worker::{console_error, console_log, Request, Response, RouteContext};
+
+// Entries per track feed
+const FEED_LIMIT: &str = "50";
+
+// Feed readers poll; let them and intermediaries reuse a copy for a while
+const FEED_MAX_AGE_SECS: u32 = 300;
+
+pub async fn handle(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+    let user = ctx.param("user").cloned().unwrap_or_default();
+    let Some((kind, format)) = ctx.param("feed").and_then(|name| parse_feed_name(name)) else {
+        return Response::error("Not Found", 404);
+    };
+
+    if let Err(e) = rate_limit(&req, &env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return e.to_response();
+    }
+
+    // Same params as the equivalent /user/* request, so the cache entry is shared
+    let mut params = HashMap::new();
+    params.insert("user".to_string(), user.clone());
+    if kind != FeedKind::WeeklyArtists {
+        params.insert("limit".to_string(), FEED_LIMIT.to_string());
+    }
+    let method = kind.method();
+    let cache_key = params.cache_key(method);
+
+    let outcome = match super::fetch_cached(&env, method, params, &cache_key).await {
+        Ok(outcome) => outcome,
+        Err(e) => return e.to_response(),
+    };
+    if let Some(e) = outcome.negative_error() {
+        return e.to_response();
+    }
+    let document: Value = match serde_json::from_str(&outcome.body) {
+        Ok(document) => document,
+        Err(e) => {
+            console_error!("Upstream returned invalid JSON: {}", e);
+            return ApiError::temporary_error().to_response();
+        }
+    };
+
+    let feed = build_feed(kind, &user, &document);
+    let body = feed::render(&feed, format);
+    let etag = feed::etag(&body);
+
+    let header = |name: &str| req.headers().get(name).ok().flatten();
+    let not_modified = is_not_modified(
+        header("If-None-Match").as_deref(),
+        header("If-Modified-Since").as_deref(),
+        &etag,
+        feed.updated,
+    );
+
+    let mut response = if not_modified {
+        Response::empty()?.with_status(304)
+    } else {
+        let mut response = Response::ok(body)?;
+        response
+            .headers_mut()
+            .set("Content-Type", format.content_type())?;
+        response
+    };
+    let headers = response.headers_mut();
+    headers.set("ETag", &etag)?;
+    headers.set("Last-Modified", &http_date(feed.updated))?;
+    headers.set(
+        "Cache-Control",
+        &format!("public, max-age={FEED_MAX_AGE_SECS}"),
+    )?;
+    headers.set("X-Cache", outcome.cache_status)?;
+    add_cors_headers(response)
+}
@@ -0,0 +1,205 @@
+// Deep health check: KV bindings, secrets and (optionally) Last.fm itself.
+// Anyone may run the read-only checks; write probes and the upstream call,
+// which spends Last.fm quota, need the admin token. Anonymous callers are
+// rate limited, but a broken limiter only fails its own check.
+
+use super::tokens::is_admin;
+use crate::middleware::{add_cors_headers, rate_limit, RATE_LIMIT_WINDOW_SECS};
+use crate::utils::{parse_lastfm_error, parse_query_params, proxy_to_lastfm};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use worker::{console_error, Env, Request, Response, RouteContext};
+
+// Write probes get a key of their own per request, so concurrent checks
+// never read each other's value
+const PROBE_PREFIX: &str = "health:probe:";
+
+// KV rejects expiration TTLs under a minute
+const PROBE_TTL: u64 = 60;
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    duration_ms: i64,
+}
+
+impl Check {
+    fn new(name: &'static str, started: chrono::DateTime<Utc>, result: Result<(), String>) -> Self {
+        let duration_ms = (Utc::now() - started).num_milliseconds();
+        match result {
+            Ok(()) => Self {
+                name,
+                ok: true,
+                detail: None,
+                duration_ms,
+            },
+            Err(detail) => {
+                console_error!("Health check {} failed: {}", name, detail);
+                Self {
+                    name,
+                    ok: false,
+                    detail: Some(detail),
+                    duration_ms,
+                }
+            }
+        }
+    }
+}
+
+// Read a key that need not exist, or with `write` also write a probe value,
+// read it back and remove it
+async fn check_kv(env: &Env, binding: &'static str, write: bool) -> Check {
+    let started = Utc::now();
+    let result = async {
+        let kv = env.kv(binding).map_err(|e| e.to_string())?;
+        if !write {
+            return kv
+                .get(PROBE_PREFIX)
+                .text()
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("read failed: {e:?}"));
+        }
+
+        let nonce = rand_u64()?;
+        let key = format!("{PROBE_PREFIX}{nonce:016x}");
+        let value = started.timestamp_millis().to_string();
+
+        kv.put(&key, value.clone())
+            .map_err(|e| format!("{e:?}"))?
+            .expiration_ttl(PROBE_TTL)
+            .execute()
+            .await
+            .map_err(|e| format!("write failed: {e:?}"))?;
+
+        let read = kv.get(&key).text().await;
+        if let Err(e) = kv.delete(&key).await {
+            console_error!("Failed to remove health probe {}: {:?}", key, e);
+        }
+        match read {
+            Ok(Some(read)) if read == value => Ok(()),
+            Ok(_) => Err("read back a different value".to_string()),
+            Err(e) => Err(format!("read failed: {e:?}")),
+        }
+    }
+    .await;
+
+    Check::new(binding, started, result)
+}
+
+fn rand_u64() -> Result<u64, String> {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// A key pool counts as configured credentials
+fn check_secrets(env: &Env) -> Check {
+    let started = Utc::now();
+    let result = if env.secret("LASTFM_API_KEYS").is_ok() {
+        Ok(())
+    } else {
+        let missing: Vec<&str> = ["LASTFM_API_KEY", "LASTFM_API_SECRET"]
+            .into_iter()
+            .filter(|name| env.secret(name).is_err())
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("missing {}", missing.join(", ")))
+        }
+    };
+
+    Check::new("secrets", started, result)
+}
+
+// A cheap, unauthenticated call that exercises the whole upstream path
+async fn check_upstream(env: &Env) -> Check {
+    let started = Utc::now();
+    let params = HashMap::from([("limit".to_string(), "1".to_string())]);
+
+    let result = async {
+        let mut response = proxy_to_lastfm(env, "chart.getTopTags", params)
+            .await
+            .map_err(|e| e.message)?;
+        let body = response.text().await.map_err(|e| e.to_string())?;
+        match parse_lastfm_error(&body) {
+            Some(error) => Err(format!("error {}: {}", error.error, error.message)),
+            None => Ok(()),
+        }
+    }
+    .await;
+
+    Check::new("upstream", started, result)
+}
+
+fn error_response(status: u16, message: &str) -> Result<Response, worker::Error> {
+    let body = json!({ "error": status, "message": message });
+    let mut resp = Response::ok(body.to_string())?.with_status(status);
+    resp.headers_mut().set("Content-Type", "application/json")?;
+    resp.headers_mut().set("Cache-Control", "no-store")?;
+    add_cors_headers(resp)
+}
+
+// GET /health/deep[?upstream=true] - 200 when every check passes, 503 otherwise
+pub async fn deep(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = &ctx.env;
+    let params = parse_query_params(&req)?;
+    let ping_upstream = matches!(
+        params.get("upstream").map(String::as_str),
+        Some("1" | "true")
+    );
+    let admin = is_admin(&req, env);
+    if ping_upstream && !admin {
+        return error_response(401, "upstream=true requires the admin token");
+    }
+
+    let mut checks = Vec::new();
+    if !admin {
+        let started = Utc::now();
+        match rate_limit(&req, env).await {
+            Ok(_) => {}
+            // Error 29 is a real limit hit; anything else means the limiter is down
+            Err(e) if e.error == 29 => {
+                let mut resp = error_response(429, &e.message)?;
+                resp.headers_mut()
+                    .set("Retry-After", &RATE_LIMIT_WINDOW_SECS.to_string())?;
+                return Ok(resp);
+            }
+            Err(e) => checks.push(Check::new("rate_limit", started, Err(e.message))),
+        }
+    }
+
+    checks.push(check_kv(env, "CACHE", admin).await);
+    checks.push(check_kv(env, "RATE_LIMIT", admin).await);
+    checks.push(check_secrets(env));
+    if ping_upstream {
+        checks.push(check_upstream(env).await);
+    }
+
+    let healthy = checks.iter().all(|check| check.ok);
+    let environment = env
+        .var("ENVIRONMENT")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    let body = json!({
+        "status": if healthy { "ok" } else { "fail" },
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": env!("GIT_SHA"),
+        "environment": environment,
+        "timestamp": Utc::now().to_rfc3339(),
+        "checks": checks,
+    });
+
+    let mut resp = Response::ok(body.to_string())?.with_status(if healthy { 200 } else { 503 });
+    resp.headers_mut().set("Content-Type", "application/json")?;
+    // Load balancers must always see the live result
+    resp.headers_mut().set("Cache-Control", "no-store")?;
+    add_cors_headers(resp)
+}
@@ -0,0 +1,183 @@
+// ListenBrainz-compatible facade, so players that submit to a ListenBrainz URL
+// can scrobble through the worker. The ListenBrainz user token is a proxy token
+// from the session vault or a token linked to one in the vault's ListenBrainz
+// table; playing_now becomes track.updateNowPlaying and single/import listens
+// go through the scrobble queue.
+
+use crate::common::listenbrainz::{
+    error_body, parse_authorization, parse_submission, validation_body, Listen, Submission,
+};
+use crate::middleware::{add_cors_headers, rate_limit};
+use crate::scrobble::{enqueue, KvScrobbleStore, Scrobble, ScrobbleSubmission};
+use crate::utils::{
+    parse_lastfm_error, parse_query_params, proxy_post_to_lastfm, sign_lastfm_params,
+};
+use crate::vault::{is_proxy_token, ResolvedSession, SessionVault};
+use serde_json::{json, Value};
+use worker::{console_error, console_log, Env, Request, Response, RouteContext};
+
+// Invalid session key
+const LASTFM_INVALID_SESSION: u32 = 9;
+
+fn json_response(status: u16, body: &Value) -> Result<Response, worker::Error> {
+    let mut response = Response::ok(body.to_string())?.with_status(status);
+    response
+        .headers_mut()
+        .set("Content-Type", "application/json")?;
+    add_cors_headers(response)
+}
+
+fn error(status: u16, message: &str) -> Result<Response, worker::Error> {
+    json_response(status, &error_body(status, message))
+}
+
+fn header_token(req: &Request) -> Option<String> {
+    let header = req.headers().get("Authorization").ok().flatten()?;
+    parse_authorization(&header).map(str::to_string)
+}
+
+// The vault entry behind a proxy token or a linked ListenBrainz token; Err is
+// the message for a 503
+async fn resolve_token(env: &Env, token: &str) -> Result<Option<ResolvedSession>, &'static str> {
+    let Some(vault) = SessionVault::from_env(env) else {
+        console_log!("Session vault is not configured");
+        return Err("ListenBrainz submissions are not enabled");
+    };
+    let resolved = if is_proxy_token(token) {
+        vault.resolve(token).await
+    } else {
+        vault.resolve_listenbrainz(token).await
+    };
+    resolved.map_err(|e| {
+        console_error!("Failed to resolve ListenBrainz token: {}", e);
+        "Temporary error"
+    })
+}
+
+// GET /1/validate-token with an Authorization header or ?token=
+pub async fn validate_token(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+
+    if let Err(e) = rate_limit(&req, &env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return error(429, &e.message);
+    }
+
+    let token = match header_token(&req) {
+        Some(token) => token,
+        None => match parse_query_params(&req)?.remove("token") {
+            Some(token) if !token.is_empty() => token,
+            _ => return error(400, "You need to provide an Authorization token."),
+        },
+    };
+
+    match resolve_token(&env, &token).await {
+        Ok(session) => json_response(
+            200,
+            &validation_body(session.as_ref().map(|s| s.username.as_str())),
+        ),
+        Err(message) => error(503, message),
+    }
+}
+
+// Send a playing_now listen straight to Last.fm
+async fn update_now_playing(
+    env: &Env,
+    session: ResolvedSession,
+    listen: &Listen,
+) -> Result<Response, worker::Error> {
+    let method = "track.updateNowPlaying";
+    let mut params = listen.now_playing_params();
+    params.insert("sk".to_string(), session.session_key);
+    let key_id = session.api_key_id.as_deref();
+    if let Err(e) = sign_lastfm_params(env, method, &mut params, key_id).await {
+        return error(503, &e.message);
+    }
+
+    let body = match proxy_post_to_lastfm(env, method, params).await {
+        Ok(mut response) => response.text().await?,
+        Err(e) => return error(503, &e.message),
+    };
+    match parse_lastfm_error(&body) {
+        Some(e) if e.error == LASTFM_INVALID_SESSION => error(401, "Invalid authorization token."),
+        Some(e) => error(400, &e.message),
+        None => json_response(200, &json!({ "status": "ok" })),
+    }
+}
+
+// POST /1/submit-listens with `Authorization: Token <proxy or linked token>`
+pub async fn submit_listens(
+    mut req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+
+    if let Err(e) = rate_limit(&req, &env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return error(429, &e.message);
+    }
+
+    let Some(token) = header_token(&req) else {
+        return error(401, "You need to provide an Authorization header.");
+    };
+    let session = match resolve_token(&env, &token).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return error(401, "Invalid authorization token."),
+        Err(message) => return error(503, message),
+    };
+
+    let body = req.text().await?;
+    let listens = match parse_submission(&body) {
+        Ok(Submission::PlayingNow(listen)) => {
+            return update_now_playing(&env, session, &listen).await
+        }
+        Ok(Submission::Listens(_, listens)) => listens,
+        Err(message) => return error(400, &message),
+    };
+
+    // Proxy tokens are queued as they are rather than as the session key, so
+    // revoking the token also stops listens that have not been submitted yet.
+    // Linked tokens mean nothing to the queue, so those carry the session key
+    // (sealed at rest) and the key it was issued for.
+    let (session_key, api_key_id) = if is_proxy_token(&token) {
+        (token, None)
+    } else {
+        (session.session_key.clone(), session.api_key_id.clone())
+    };
+    let submission = ScrobbleSubmission {
+        session_key,
+        api_key_id,
+        scrobbles: listens
+            .into_iter()
+            .map(|timed| {
+                let listen = timed.listen;
+                Scrobble {
+                    artist: listen.artist,
+                    track: listen.track,
+                    timestamp: timed.listened_at,
+                    album: listen.album,
+                    album_artist: listen.album_artist,
+                    track_number: listen.track_number,
+                    duration: listen.duration,
+                    mbid: listen.mbid,
+                }
+            })
+            .collect(),
+    };
+
+    let store = KvScrobbleStore::new(&env)?;
+    let now = chrono::Utc::now().timestamp();
+    match enqueue(&store, submission, now).await {
+        Ok(queued) => {
+            console_log!("Queued {} listens for {}", queued, session.username);
+            json_response(200, &json!({ "status": "ok" }))
+        }
+        Err(e) => {
+            console_error!("Failed to queue listens: {}", e);
+            error(503, "Temporary error")
+        }
+    }
+}
@@ -1,21 +1,45 @@
 pub mod album;
 pub mod artist;
+pub mod audioscrobbler;
 pub mod auth;
+pub mod badge;
+pub mod batch;
 pub mod chart;
+pub mod chartdiff;
+pub mod compare;
+pub mod feed;
 pub mod geo;
+pub mod health;
 pub mod library;
+pub mod listenbrainz;
+pub mod recommendations;
+pub mod scrobble;
+pub mod search;
+pub mod stream;
 pub mod tag;
+pub mod tokens;
 pub mod track;
 pub mod user;
+pub mod v2;
 
-use crate::error::ApiError;
+use crate::common::fields::FieldSelection;
+use crate::common::format::ResponseFormat;
+use crate::config::Config;
+use crate::diagnostics::Diagnostics;
+use crate::error::{ApiError, ApiResult};
+use crate::key_pool::{key_id, requested_key_id};
 use crate::middleware::add_cors_headers;
-use crate::middleware::{rate_limit, validate_request};
+use crate::middleware::validate_request;
 use crate::models::CacheKey;
+use crate::throttle::RETRY_AFTER_SECS;
 use crate::utils::{
-    cache_response, get_cached_response, parse_lastfm_error, parse_query_params, proxy_to_lastfm,
+    cache_negative_response, cache_response, client_signing_credentials, get_cached_entry,
+    parse_body_params, parse_lastfm_error, parse_query_params, proxy_post_to_lastfm,
+    proxy_to_lastfm, sign_lastfm_params, sign_lastfm_params_with, CacheMetadata,
 };
-use worker::{console_error, console_log, Request, Response, RouteContext};
+use crate::vault::{is_proxy_token, SessionVault};
+use std::collections::HashMap;
+use worker::{console_error, console_log, Env, Method, Request, Response, RouteContext};
 
 // Common handler function for all endpoints
 pub async fn handle_request(
@@ -25,11 +49,12 @@ pub async fn handle_request(
 ) -> Result<Response, worker::Error> {
     console_log!("Handling request for method: {}", method_name);
     let env = ctx.env.clone();
+    let mut diagnostics = Diagnostics::start(&req, &env);
 
     // Apply rate limiting
-    if let Err(e) = rate_limit(&req, &env).await {
+    if let Err(e) = diagnostics.rate_limit(&req, &env).await {
         console_log!("Rate limit error: {:?}", e);
-        return e.to_response();
+        return diagnostics.finish(e.to_response());
     }
 
     // Parse query parameters
@@ -41,29 +66,91 @@ pub async fn handle_request(
             return Err(e);
         }
     };
-    console_log!("Parsed params: {:?}", params);
+    let config = Config::from_env(&env);
+    if config.redact_logs {
+        console_log!("Parsed params: {:?}", params.keys().collect::<Vec<_>>());
+    } else {
+        console_log!("Parsed params: {:?}", params);
+    }
 
     // Validate request
     if let Err(e) = validate_request(&req, &env, &params, method_name).await {
         console_log!("Validation error: {:?}", e);
-        return e.to_response();
+        return diagnostics.finish(e.to_response());
     }
 
+    // Negotiate output format (the cache always stores canonical JSON)
+    let accept = req.headers().get("Accept").ok().flatten();
+    let format =
+        ResponseFormat::negotiate(params.get("format").map(|f| f.as_str()), accept.as_deref());
+
+    // Field projection is applied after the cache step so it never splits the cache key
+    let fields = params.get("fields").and_then(|f| FieldSelection::parse(f));
+
     // Generate cache key
     let cache_key = params.cache_key(method_name);
     console_log!("Generated cache key: {}", cache_key);
+    diagnostics.cache_key = Some(cache_key.clone());
+
+    let response = match fetch_cached(&env, method_name, params, &cache_key).await {
+        Ok(outcome) => {
+            diagnostics.cache_status = Some(outcome.cache_status);
+            diagnostics.cache = outcome.cache;
+            diagnostics.upstream_ms = outcome.upstream_ms;
+            match outcome.negative_error() {
+                Some(e) => {
+                    console_log!("Negative cache hit for key: {}", cache_key);
+                    e.to_response()
+                }
+                None => formatted_response(outcome.body, fields.as_ref(), format),
+            }
+        }
+        Err(e) if e.is_upstream_throttled() => throttled_response(&e),
+        Err(e) => e.to_response(),
+    };
+
+    diagnostics.finish(response)
+}
+
+// Where a proxied body came from, for response metadata
+pub struct ProxyOutcome {
+    pub body: String,
+    // "HIT", "MISS", "STALE" or "NEGATIVE-HIT"
+    pub cache_status: &'static str,
+    pub cache: Option<CacheMetadata>,
+    pub upstream_ms: Option<i64>,
+}
 
+impl ProxyOutcome {
+    // The cached Last.fm error for a negative cache hit
+    pub fn negative_error(&self) -> Option<ApiError> {
+        if self.cache_status != "NEGATIVE-HIT" {
+            return None;
+        }
+        Some(parse_lastfm_error(&self.body).unwrap_or_else(ApiError::temporary_error))
+    }
+}
+
+// Serve from cache, otherwise from Last.fm. When the upstream budget is spent
+// a stale copy is served if there is one.
+pub async fn fetch_cached(
+    env: &Env,
+    method_name: &str,
+    params: HashMap<String, String>,
+    cache_key: &str,
+) -> ApiResult<ProxyOutcome> {
     // Check cache
     console_log!("Checking cache...");
-    match get_cached_response(&env, &cache_key).await {
-        Ok(Some(cached)) => {
+    match get_cached_entry(env, cache_key).await {
+        Ok(Some((body, cache))) => {
             console_log!("Cache hit for key: {}", cache_key);
-            let mut response = Response::ok(cached)?;
-            response
-                .headers_mut()
-                .set("Content-Type", "application/json")?;
-            response.headers_mut().set("X-Cache", "HIT")?;
-            return add_cors_headers(response);
+            let negative = cache.map(|c| c.negative).unwrap_or(false);
+            return Ok(ProxyOutcome {
+                body,
+                cache_status: if negative { "NEGATIVE-HIT" } else { "HIT" },
+                cache,
+                upstream_ms: None,
+            });
         }
         Ok(None) => {
             console_log!("Cache miss for key: {}", cache_key);
@@ -73,105 +160,256 @@ pub async fn handle_request(
         }
     }
 
-    // Proxy to Last.fm API
+    // Proxy to Last.fm API and cache the result
+    match fetch_and_cache(env, method_name, params, cache_key).await {
+        Ok((body, upstream_ms)) => Ok(ProxyOutcome {
+            body,
+            cache_status: "MISS",
+            cache: None,
+            upstream_ms: Some(upstream_ms),
+        }),
+        Err(e) if e.is_upstream_throttled() => {
+            match get_cached_entry(env, &stale_cache_key(cache_key)).await {
+                Ok(Some((body, cache))) => {
+                    console_log!("Upstream throttled, serving stale copy of {}", cache_key);
+                    Ok(ProxyOutcome {
+                        body,
+                        cache_status: "STALE",
+                        cache,
+                        upstream_ms: None,
+                    })
+                }
+                _ => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// fetch_cached for endpoints built from other methods' responses: the parsed
+// document, with a cached Last.fm error returned as Err
+pub async fn fetch_cached_json(
+    env: &Env,
+    method_name: &str,
+    params: HashMap<String, String>,
+) -> ApiResult<serde_json::Value> {
+    let cache_key = params.cache_key(method_name);
+    let outcome = fetch_cached(env, method_name, params, &cache_key).await?;
+    if let Some(e) = outcome.negative_error() {
+        return Err(e);
+    }
+    serde_json::from_str(&outcome.body).map_err(|_| ApiError::temporary_error())
+}
+
+// Fetch a method from Last.fm and cache successful responses for 1 hour,
+// returning the body and the milliseconds spent waiting on Last.fm.
+// Shared by the request path and the scheduled cache warmer.
+pub async fn fetch_and_cache(
+    env: &Env,
+    method_name: &str,
+    params: HashMap<String, String>,
+    cache_key: &str,
+) -> ApiResult<(String, i64)> {
     console_log!("Proxying to Last.fm API...");
-    let mut response = match proxy_to_lastfm(&env, method_name, params).await {
+    let started = chrono::Utc::now();
+    let mut response = match proxy_to_lastfm(env, method_name, params).await {
         Ok(resp) => {
             console_log!("Got response from Last.fm");
             resp
         }
         Err(e) => {
             console_log!("Error from proxy_to_lastfm: {:?}", e);
-            return e.to_response();
+            return Err(e);
         }
     };
 
     // Get response body
     let response_body = response.text().await?;
+    let upstream_ms = (chrono::Utc::now() - started).num_milliseconds();
 
-    // Check for Last.fm API errors
+    // Check for Last.fm API errors, briefly caching the ones that would repeat
     if let Some(api_error) = parse_lastfm_error(&response_body) {
-        return api_error.to_response();
+        if api_error.is_deterministic() {
+            let _ = cache_negative_response(env, cache_key, &response_body, NEGATIVE_TTL).await;
+        }
+        return Err(api_error);
     }
 
-    // Cache successful responses for 1 hour
+    // Cache successful responses for 1 hour, keeping a stale copy for when
+    // the upstream budget runs out
     if response.status_code() == 200 {
         let cache_ttl = 3600; // 1 hour
-        let _ = cache_response(&env, &cache_key, &response_body, cache_ttl).await;
+        if let Err(e) = cache_response(env, cache_key, &response_body, cache_ttl).await {
+            console_error!("Failed to cache {}: {:?}", cache_key, e);
+        }
+        let _ = cache_response(env, &stale_cache_key(cache_key), &response_body, STALE_TTL).await;
     }
 
-    // Return response
-    let mut response = Response::ok(response_body)?;
+    Ok((response_body, upstream_ms))
+}
+
+// Deterministic errors are cached briefly (KV's minimum TTL is a minute)
+const NEGATIVE_TTL: u64 = 300;
+
+// Stale copies outlive fresh entries by a day
+const STALE_TTL: u64 = 24 * 3600;
+
+fn stale_cache_key(cache_key: &str) -> String {
+    format!("stale:{cache_key}")
+}
+
+// 503 for when the upstream budget is spent and there is no stale copy
+fn throttled_response(error: &ApiError) -> Result<Response, worker::Error> {
+    let mut response = Response::error(serde_json::to_string(error)?, 503)?;
     response
         .headers_mut()
         .set("Content-Type", "application/json")?;
-    response.headers_mut().set("X-Cache", "MISS")?;
+    response
+        .headers_mut()
+        .set("Retry-After", &RETRY_AFTER_SECS.to_string())?;
+    add_cors_headers(response)
+}
+
+// Prune a canonical JSON body to the requested fields and render it in the negotiated format
+fn formatted_response(
+    json_body: String,
+    fields: Option<&FieldSelection>,
+    format: ResponseFormat,
+) -> Result<Response, worker::Error> {
+    let json_body = match fields {
+        Some(fields) => match serde_json::from_str(&json_body) {
+            Ok(document) => fields.apply(&document).to_string(),
+            Err(e) => {
+                console_error!("Failed to apply field projection: {}", e);
+                json_body
+            }
+        },
+        None => json_body,
+    };
+
+    let (body, format) = match format.render(&json_body) {
+        Ok(body) => (body, format),
+        Err(e) => {
+            console_error!("Failed to render {:?} response: {}", format, e);
+            (json_body, ResponseFormat::Json)
+        }
+    };
+
+    let mut response = Response::ok(body)?;
+    response
+        .headers_mut()
+        .set("Content-Type", format.content_type())?;
+    response.headers_mut().set("Vary", "Accept")?;
     add_cors_headers(response)
 }
 
 // Handler for authenticated requests that require API signature
 pub async fn handle_auth_request(
-    req: Request,
+    mut req: Request,
     ctx: RouteContext<()>,
     method_name: &str,
 ) -> Result<Response, worker::Error> {
     console_log!("Handling authenticated request for method: {}", method_name);
     let env = ctx.env.clone();
+    let mut diagnostics = Diagnostics::start(&req, &env);
+    let response = authenticated_response(&mut req, &env, method_name, &mut diagnostics).await;
+    diagnostics.finish(response)
+}
 
+async fn authenticated_response(
+    req: &mut Request,
+    env: &Env,
+    method_name: &str,
+    diagnostics: &mut Diagnostics,
+) -> Result<Response, worker::Error> {
     // Apply rate limiting
-    if let Err(e) = rate_limit(&req, &env).await {
+    if let Err(e) = diagnostics.rate_limit(req, env).await {
         console_log!("Rate limit error: {:?}", e);
         return e.to_response();
     }
 
     // Parse query parameters
     console_log!("Parsing query parameters...");
-    let mut params = match parse_query_params(&req) {
+    let mut params = match parse_query_params(req) {
         Ok(p) => p,
         Err(e) => {
             console_log!("Error parsing query params: {:?}", e);
             return Err(e);
         }
     };
-    console_log!("Parsed params: {:?}", params);
+
+    // Merge body parameters for POST requests (body wins over query)
+    let is_post = req.method() == Method::Post;
+    if is_post {
+        match parse_body_params(req).await {
+            Ok(body_params) => params.extend(body_params),
+            Err(e) => {
+                console_log!("Error parsing body params: {:?}", e);
+                return e.to_response();
+            }
+        }
+    }
+    // Only log parameter names - values may contain credentials
+    console_log!("Parsed params: {:?}", params.keys().collect::<Vec<_>>());
 
     // Validate request
-    if let Err(e) = validate_request(&req, &env, &params, method_name).await {
+    if let Err(e) = validate_request(req, env, &params, method_name).await {
         console_log!("Validation error: {:?}", e);
         return e.to_response();
     }
 
-    // Check if request already has a signature
-    if !params.contains_key("api_sig") {
-        // Get API key and secret for signing
-        let api_key = match env.secret("LASTFM_API_KEY") {
-            Ok(key) => key.to_string(),
+    // The pooled key the session (or, for auth.getSession, the auth token)
+    // was issued for; `key_id` is ours and not forwarded to Last.fm
+    let mut session_key_id = requested_key_id(&params);
+    params.remove("key_id");
+
+    // Swap a proxy token for the real session key held in the vault
+    let vault = SessionVault::from_env(env);
+    if let Some(token) = params.get("sk").filter(|sk| is_proxy_token(sk)).cloned() {
+        let resolved = match &vault {
+            Some(vault) => vault.resolve(&token).await,
+            None => Ok(None),
+        };
+        match resolved {
+            Ok(Some(session)) => {
+                params.insert("sk".to_string(), session.session_key);
+                session_key_id = session.api_key_id.or(session_key_id);
+                // A signature over the token is meaningless upstream
+                params.remove("api_sig");
+            }
+            Ok(None) => return ApiError::invalid_session_key().to_response(),
             Err(e) => {
-                console_error!("Failed to get LASTFM_API_KEY: {:?}", e);
+                console_error!("Failed to resolve proxy token: {}", e);
                 return ApiError::temporary_error().to_response();
             }
-        };
+        }
+    }
 
-        let api_secret = match env.secret("LASTFM_API_SECRET") {
-            Ok(secret) => secret.to_string(),
-            Err(e) => {
-                console_error!("Failed to get LASTFM_API_SECRET: {:?}", e);
-                return ApiError::temporary_error().to_response();
+    // Check if request already has a signature; a caller that brought its own
+    // key and secret is signed for with those, everyone else with ours
+    if !params.contains_key("api_sig") {
+        let signed = match client_signing_credentials(req, env, &params) {
+            Some(credentials) => sign_lastfm_params_with(method_name, &mut params, credentials),
+            None => {
+                let key_id = session_key_id.as_deref();
+                sign_lastfm_params(env, method_name, &mut params, key_id).await
             }
         };
-
-        // Add required params for signature calculation
-        params.insert("method".to_string(), method_name.to_string());
-        params.insert("api_key".to_string(), api_key);
-
-        // Sign the request with MD5
-        let api_sig = crate::utils::sign_request_md5(&params, &api_secret);
-        params.insert("api_sig".to_string(), api_sig);
+        if let Err(e) = signed {
+            return e.to_response();
+        }
     }
+    let signed_key_id = params.get("api_key").map(|api_key| key_id(api_key));
 
     // Proxy to Last.fm API
     console_log!("Proxying authenticated request to Last.fm API...");
-    let mut response = match proxy_to_lastfm(&env, method_name, params).await {
+    let started = chrono::Utc::now();
+    let upstream = if is_post {
+        proxy_post_to_lastfm(env, method_name, params).await
+    } else {
+        proxy_to_lastfm(env, method_name, params).await
+    };
+    let mut response = match upstream {
         Ok(resp) => {
             console_log!("Got response from Last.fm");
             resp
@@ -184,6 +422,7 @@ pub async fn handle_auth_request(
 
     // Get response body
     let response_body = response.text().await?;
+    diagnostics.upstream_ms = Some((chrono::Utc::now() - started).num_milliseconds());
 
     // Check for Last.fm API errors
     if let Some(api_error) = parse_lastfm_error(&response_body) {
@@ -192,6 +431,25 @@ pub async fn handle_auth_request(
 
     // Don't cache authenticated responses
 
+    // Hand out a proxy token instead of the real session key, and tell the
+    // client which key the session belongs to
+    let response_body = if matches!(method_name, "auth.getSession" | "auth.getMobileSession") {
+        let label = req.headers().get("User-Agent").ok().flatten();
+        match bind_session(vault.as_ref(), &response_body, signed_key_id, label).await {
+            Ok(body) => body,
+            Err(e) if vault.is_some() => {
+                console_error!("Failed to store session in vault: {}", e);
+                return ApiError::temporary_error().to_response();
+            }
+            Err(e) => {
+                console_error!("Failed to annotate session: {}", e);
+                response_body
+            }
+        }
+    } else {
+        response_body
+    };
+
     // Return response
     let mut response = Response::ok(response_body)?;
     response
@@ -199,3 +457,42 @@ pub async fn handle_auth_request(
         .set("Content-Type", "application/json")?;
     add_cors_headers(response)
 }
+
+// Add the signing key's id to an auth.getSession response as `session.key_id`
+// (sent back with raw session keys), and with a vault store the session key
+// and replace it with a proxy token
+async fn bind_session(
+    vault: Option<&SessionVault>,
+    response_body: &str,
+    key_id: Option<String>,
+    label: Option<String>,
+) -> Result<String, String> {
+    let mut document: serde_json::Value =
+        serde_json::from_str(response_body).map_err(|e| e.to_string())?;
+    let session = document
+        .get_mut("session")
+        .and_then(|s| s.as_object_mut())
+        .ok_or("Response has no session")?;
+
+    let username = session
+        .get("name")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    let session_key = session
+        .get("key")
+        .and_then(|v| v.as_str())
+        .ok_or("Response has no session key")?;
+
+    if let Some(vault) = vault {
+        let (token, info) = vault
+            .issue(username, session_key, key_id.clone(), label)
+            .await?;
+        console_log!("Issued proxy token {} for {}", info.id, info.username);
+        session.insert("key".to_string(), token.into());
+    }
+    if let Some(key_id) = key_id {
+        session.insert("key_id".to_string(), key_id.into());
+    }
+
+    Ok(document.to_string())
+}
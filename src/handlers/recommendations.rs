@@ -0,0 +1,125 @@
+// GET /user/recommendations?user=rj&period=3month&limit=20: artists similar to
+// a user's top artists that they don't already listen to. Every lookup goes
+// through the response cache, so repeated requests rarely reach Last.fm.
+
+use crate::common::recommendations::{
+    artist_names, rank, seeds, similar_artists, to_json, DEFAULT_RECOMMENDATIONS,
+    MAX_RECOMMENDATIONS,
+};
+use crate::common::validation::period_param;
+use crate::diagnostics::Diagnostics;
+use crate::error::ApiError;
+use crate::middleware::{add_cors_headers, validate_method_params};
+use crate::utils::parse_query_params;
+use futures::future::join_all;
+use std::collections::{HashMap, HashSet};
+use worker::{console_log, Env, Request, Response, RouteContext};
+
+// Top artists whose similar artists are looked up
+const SEED_ARTISTS: usize = 10;
+
+// Top artists fetched; all of them count as already known
+const TOP_ARTISTS_LIMIT: &str = "50";
+
+const SIMILAR_LIMIT: &str = "50";
+
+// One page of the library is enough to filter out the artists that matter
+const LIBRARY_LIMIT: &str = "1000";
+
+pub async fn handle(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+    let mut diagnostics = Diagnostics::start(&req, &env);
+    let response = recommendations_response(&req, &env, &mut diagnostics).await;
+    diagnostics.finish(response)
+}
+
+fn user_params(user: &str, pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    params.insert("user".to_string(), user.to_string());
+    for (key, value) in pairs {
+        params.insert(key.to_string(), value.to_string());
+    }
+    params
+}
+
+async fn recommendations_response(
+    req: &Request,
+    env: &Env,
+    diagnostics: &mut Diagnostics,
+) -> Result<Response, worker::Error> {
+    if let Err(e) = diagnostics.rate_limit(req, env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return e.to_response();
+    }
+
+    let params = parse_query_params(req)?;
+    if let Err(e) = validate_method_params("user.getTopArtists", &params) {
+        return e.to_response();
+    }
+    let user = params["user"].clone();
+    let period = match period_param(&params, "overall") {
+        Ok(period) => period,
+        Err(message) => return ApiError::invalid_parameters(message).to_response(),
+    };
+    let limit = match params.get("limit").map(|limit| limit.parse::<usize>()) {
+        None => DEFAULT_RECOMMENDATIONS,
+        Some(Ok(limit)) if (1..=MAX_RECOMMENDATIONS).contains(&limit) => limit,
+        Some(_) => {
+            return ApiError::invalid_parameters(format!(
+                "Invalid limit - must be between 1 and {MAX_RECOMMENDATIONS}"
+            ))
+            .to_response()
+        }
+    };
+
+    let top_artists = super::fetch_cached_json(
+        env,
+        "user.getTopArtists",
+        user_params(&user, &[("period", &period), ("limit", TOP_ARTISTS_LIMIT)]),
+    );
+    let library = super::fetch_cached_json(
+        env,
+        "library.getArtists",
+        user_params(&user, &[("limit", LIBRARY_LIMIT)]),
+    );
+    let (top_artists, library) = futures::join!(top_artists, library);
+
+    let top_artists = match top_artists {
+        Ok(document) => document,
+        Err(e) => return e.to_response(),
+    };
+    let seeds = seeds(&top_artists, SEED_ARTISTS);
+
+    let mut known: HashSet<String> = artist_names(&top_artists).into_iter().collect();
+    match library {
+        Ok(document) => known.extend(artist_names(&document)),
+        // Still worth answering; top artists are filtered either way
+        Err(e) => console_log!("Library unavailable for recommendations: {:?}", e),
+    }
+
+    // A seed whose lookup fails just contributes nothing
+    let lookups = seeds.iter().map(|seed| {
+        let mut params = HashMap::new();
+        params.insert("artist".to_string(), seed.name.clone());
+        params.insert("limit".to_string(), SIMILAR_LIMIT.to_string());
+        super::fetch_cached_json(env, "artist.getSimilar", params)
+    });
+    let similar = join_all(lookups)
+        .await
+        .into_iter()
+        .map(|result| match result {
+            Ok(document) => similar_artists(&document),
+            Err(e) => {
+                console_log!("Similar artists lookup failed: {:?}", e);
+                Vec::new()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let ranked = rank(&seeds, &similar, &known, limit);
+    let mut response = Response::ok(to_json(&user, &period, &seeds, &ranked).to_string())?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/json")?;
+    add_cors_headers(response)
+}
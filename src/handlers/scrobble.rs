@@ -0,0 +1,90 @@
+// Scrobble queue handlers: accept track.scrobble submissions and report queue status
+
+use crate::error::ApiError;
+use crate::middleware::{add_cors_headers, rate_limit};
+use crate::scrobble::{enqueue, session_id, KvScrobbleStore, ScrobbleStore, ScrobbleSubmission};
+use crate::utils::{parse_body_params, parse_query_params};
+use serde_json::json;
+use worker::{console_error, console_log, Request, Response, RouteContext};
+
+// Queue scrobbles and acknowledge immediately; the scheduled handler submits them
+pub async fn submit(mut req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+
+    // Apply rate limiting
+    if let Err(e) = rate_limit(&req, &env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return e.to_response();
+    }
+
+    // Last.fm-style parameters, from the query and the form or JSON body
+    let mut params = parse_query_params(&req)?;
+    match parse_body_params(&mut req).await {
+        Ok(body_params) => params.extend(body_params),
+        Err(e) => return e.to_response(),
+    }
+
+    let submission = match ScrobbleSubmission::from_params(&params) {
+        Ok(submission) => submission,
+        Err(msg) => return ApiError::invalid_parameters(msg).to_response(),
+    };
+    let session = session_id(&submission.session_key);
+
+    let store = KvScrobbleStore::new(&env)?;
+    let now = chrono::Utc::now().timestamp();
+    let queued = match enqueue(&store, submission, now).await {
+        Ok(queued) => queued,
+        Err(e) => {
+            console_error!("Failed to queue scrobbles: {}", e);
+            return ApiError::temporary_error().to_response();
+        }
+    };
+
+    console_log!("Queued {} scrobbles for session {}", queued, session);
+
+    let body = json!({
+        "scrobbles": {
+            "@attr": { "queued": queued }
+        }
+    });
+
+    let mut resp = Response::ok(body.to_string())?.with_status(202);
+    resp.headers_mut().set("Content-Type", "application/json")?;
+    add_cors_headers(resp)
+}
+
+// Report queued, accepted, ignored and failed counts for a session. The session
+// key comes in the POST body so it stays out of URLs, logs and caches.
+pub async fn status(mut req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+
+    // Apply rate limiting
+    if let Err(e) = rate_limit(&req, &env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return e.to_response();
+    }
+
+    let params = match parse_body_params(&mut req).await {
+        Ok(params) => params,
+        Err(e) => return e.to_response(),
+    };
+    let session_key = match params.get("sk").filter(|sk| !sk.is_empty()) {
+        Some(sk) => sk,
+        None => {
+            return ApiError::invalid_parameters("Missing required parameter: sk").to_response()
+        }
+    };
+
+    let store = KvScrobbleStore::new(&env)?;
+    let stats = match store.stats(&session_id(session_key)).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            console_error!("Failed to read scrobble stats: {}", e);
+            return ApiError::temporary_error().to_response();
+        }
+    };
+
+    let mut resp = Response::ok(json!({ "status": stats }).to_string())?;
+    resp.headers_mut().set("Content-Type", "application/json")?;
+    add_cors_headers(resp)
+}
@@ -0,0 +1,113 @@
+// GET /search?q=radiohead[&limit=10][&artist_limit=..&album_limit=..&track_limit=..]:
+// artist.search, album.search and track.search run together and merged into
+// one ranked list. Each sub-query uses the same params as /artist/search etc.,
+// so the response cache is shared with them.
+
+use crate::common::search::{
+    parse_limit, parse_query, rank, results, to_json, total_results, SearchType, MAX_LIMIT,
+};
+use crate::diagnostics::Diagnostics;
+use crate::error::{ApiError, ApiResult};
+use crate::middleware::add_cors_headers;
+use crate::utils::parse_query_params;
+use futures::future::join_all;
+use std::collections::HashMap;
+use worker::{console_log, Env, Request, Response, RouteContext};
+
+pub async fn handle(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+    let mut diagnostics = Diagnostics::start(&req, &env);
+    let response = search_response(&req, &env, &mut diagnostics).await;
+    diagnostics.finish(response)
+}
+
+async fn search_type(
+    env: &Env,
+    kind: SearchType,
+    query: &str,
+    limit: usize,
+) -> ApiResult<serde_json::Value> {
+    let mut params = HashMap::new();
+    params.insert(kind.slug().to_string(), query.to_string());
+    params.insert("limit".to_string(), limit.to_string());
+    super::fetch_cached_json(env, kind.method(), params).await
+}
+
+async fn search_response(
+    req: &Request,
+    env: &Env,
+    diagnostics: &mut Diagnostics,
+) -> Result<Response, worker::Error> {
+    if let Err(e) = diagnostics.rate_limit(req, env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return e.to_response();
+    }
+
+    let params = parse_query_params(req)?;
+    // /search is served by the worker, so it is not in the Last.fm method table
+    let query = match parse_query(params.get("q").map(String::as_str)) {
+        Ok(query) => query,
+        Err(message) => return ApiError::invalid_parameters(message).to_response(),
+    };
+
+    let mut searches = Vec::new();
+    for kind in SearchType::ALL {
+        let own = params.get(kind.limit_param()).map(String::as_str);
+        match parse_limit(own, params.get("limit").map(String::as_str)) {
+            Some(0) => {}
+            Some(limit) => searches.push((kind, limit)),
+            None => {
+                return ApiError::invalid_parameters(format!(
+                    "Invalid {} - must be between 0 and {MAX_LIMIT}",
+                    if own.is_some() {
+                        kind.limit_param()
+                    } else {
+                        "limit"
+                    }
+                ))
+                .to_response()
+            }
+        }
+    }
+    if searches.is_empty() {
+        return ApiError::invalid_parameters("At least one result type needs a limit above 0")
+            .to_response();
+    }
+
+    let documents = join_all(
+        searches
+            .iter()
+            .map(|(kind, limit)| search_type(env, *kind, &query, *limit)),
+    )
+    .await;
+
+    // A failed type is left out as long as another one answered
+    let mut lists = Vec::new();
+    let mut totals = Vec::new();
+    let mut first_error = None;
+    for ((kind, _), document) in searches.iter().zip(documents) {
+        match document {
+            Ok(document) => {
+                totals.push((*kind, total_results(&document)));
+                lists.push(results(*kind, &document));
+            }
+            Err(e) => {
+                console_log!("{} failed for search: {:?}", kind.method(), e);
+                totals.push((*kind, None));
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if lists.is_empty() {
+        if let Some(e) = first_error {
+            return e.to_response();
+        }
+    }
+
+    let ranked = rank(&query, lists);
+    let mut response = Response::ok(to_json(&query, &ranked, &totals).to_string())?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/json")?;
+    add_cors_headers(response)
+}
@@ -0,0 +1,146 @@
+// Server-Sent Events for a user's now-playing track. Every stream polls one
+// short-lived snapshot per user in the cache, so subscribers to the same user
+// share a single Last.fm call per poll interval.
+
+use crate::common::nowplaying::{changes, sse_event, sse_heartbeat, ListeningState};
+use crate::error::{ApiError, ApiResult};
+use crate::middleware::{add_cors_headers, rate_limit, validate_method_params};
+use crate::utils::{
+    cache_response, get_cached_entry, parse_lastfm_error, parse_query_params, proxy_to_lastfm,
+};
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+use worker::{console_error, console_log, Delay, Env, Request, Response, RouteContext};
+
+const POLL_INTERVAL_SECS: u64 = 15;
+
+// A snapshot younger than this is reused instead of calling Last.fm
+const SNAPSHOT_FRESH_SECS: i64 = 15;
+
+// KV rejects expiration TTLs under a minute
+const SNAPSHOT_TTL: u64 = 60;
+
+// Streams end after this long; EventSource clients reconnect by themselves
+const MAX_STREAM_SECS: i64 = 30 * 60;
+const RECONNECT_MS: u64 = 5000;
+
+fn snapshot_key(user: &str) -> String {
+    format!("nowplaying:{}", user.to_lowercase())
+}
+
+fn parse_state(body: &str) -> Option<ListeningState> {
+    let document = serde_json::from_str(body).ok()?;
+    ListeningState::from_recent_tracks(&document)
+}
+
+// Current state from the shared snapshot, refreshed from Last.fm when stale.
+// Also backs the now-playing badge.
+pub async fn listening_state(env: &Env, user: &str) -> ApiResult<ListeningState> {
+    let key = snapshot_key(user);
+    let now = Utc::now().timestamp();
+
+    if let Ok(Some((body, Some(cache)))) = get_cached_entry(env, &key).await {
+        if cache.age(now) < SNAPSHOT_FRESH_SECS {
+            if let Some(state) = parse_state(&body) {
+                return Ok(state);
+            }
+        }
+    }
+
+    // The now-playing entry comes on top of `limit`, so 2 also covers the last scrobble
+    let mut params = HashMap::new();
+    params.insert("user".to_string(), user.to_string());
+    params.insert("limit".to_string(), "2".to_string());
+
+    let mut response = proxy_to_lastfm(env, "user.getRecentTracks", params).await?;
+    let body = response
+        .text()
+        .await
+        .map_err(|_| ApiError::temporary_error())?;
+    if let Some(error) = parse_lastfm_error(&body) {
+        return Err(error);
+    }
+    let state = parse_state(&body).ok_or_else(ApiError::temporary_error)?;
+
+    if let Err(e) = cache_response(env, &key, &body, SNAPSHOT_TTL).await {
+        console_error!("Failed to store now-playing snapshot: {:?}", e);
+    }
+    Ok(state)
+}
+
+struct Subscription {
+    env: Env,
+    user: String,
+    state: ListeningState,
+    started: i64,
+}
+
+// Wait one interval, then emit the changes since the last poll or a heartbeat
+async fn next_chunk(mut subscription: Subscription) -> Option<(String, Subscription)> {
+    if Utc::now().timestamp() - subscription.started >= MAX_STREAM_SECS {
+        return None;
+    }
+    Delay::from(Duration::from_secs(POLL_INTERVAL_SECS)).await;
+
+    let chunk = match listening_state(&subscription.env, &subscription.user).await {
+        Ok(current) => {
+            let events = changes(Some(&subscription.state), &current);
+            subscription.state = current;
+            if events.is_empty() {
+                sse_heartbeat()
+            } else {
+                events.iter().map(|event| event.to_sse()).collect()
+            }
+        }
+        Err(e) => sse_event("error", &json!({ "error": e.error, "message": e.message })),
+    };
+
+    Some((chunk, subscription))
+}
+
+// GET /stream/nowplaying?user=rj
+pub async fn now_playing(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+
+    // Opening a stream costs one request; the polls behind it are shared
+    if let Err(e) = rate_limit(&req, &env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return e.to_response();
+    }
+
+    let params = parse_query_params(&req)?;
+    if let Err(e) = validate_method_params("user.getRecentTracks", &params) {
+        return e.to_response();
+    }
+    let user = params.get("user").cloned().unwrap_or_default();
+
+    // Fail fast (e.g. unknown user) before committing to a stream
+    let state = match listening_state(&env, &user).await {
+        Ok(state) => state,
+        Err(e) => return e.to_response(),
+    };
+
+    let mut first = format!("retry: {RECONNECT_MS}\n\n");
+    for event in changes(None, &state) {
+        first.push_str(&event.to_sse());
+    }
+
+    let subscription = Subscription {
+        env,
+        user,
+        state,
+        started: Utc::now().timestamp(),
+    };
+    let body = stream::once(async move { first })
+        .chain(stream::unfold(subscription, next_chunk))
+        .map(|chunk| Ok::<_, worker::Error>(chunk.into_bytes()));
+
+    let mut response = Response::from_stream(body)?;
+    let headers = response.headers_mut();
+    headers.set("Content-Type", "text/event-stream")?;
+    headers.set("Cache-Control", "no-cache")?;
+    add_cors_headers(response)
+}
@@ -0,0 +1,241 @@
+// Proxy token management: users list and revoke their own tokens and link
+// ListenBrainz user tokens to them, admins list and revoke any token
+
+use crate::common::listenbrainz::is_user_token;
+use crate::middleware::{add_cors_headers, rate_limit};
+use crate::utils::parse_body_params;
+use crate::vault::{ResolvedSession, SessionVault};
+use serde_json::{json, Value};
+use worker::{console_error, console_log, Env, Request, Response, RouteContext};
+
+fn json_response(body: Value, status: u16) -> Result<Response, worker::Error> {
+    let mut resp = Response::ok(body.to_string())?.with_status(status);
+    resp.headers_mut().set("Content-Type", "application/json")?;
+    add_cors_headers(resp)
+}
+
+fn json_error(status: u16, message: &str) -> Result<Response, worker::Error> {
+    json_response(json!({ "error": status, "message": message }), status)
+}
+
+fn bearer_token(req: &Request) -> Option<String> {
+    req.headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .and_then(|value| value.strip_prefix("Bearer ").map(|t| t.trim().to_string()))
+        .filter(|token| !token.is_empty())
+}
+
+// Compare without short-circuiting on the first differing byte
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+// Whether the request carries ADMIN_TOKEN as a bearer token
+pub(super) fn is_admin(req: &Request, env: &Env) -> bool {
+    match (env.secret("ADMIN_TOKEN"), bearer_token(req)) {
+        (Ok(expected), Some(token)) => constant_time_eq(&expected.to_string(), &token),
+        _ => false,
+    }
+}
+
+fn vault(env: &Env) -> Option<SessionVault> {
+    let vault = SessionVault::from_env(env);
+    if vault.is_none() {
+        console_log!("Session vault is not configured");
+    }
+    vault
+}
+
+// The caller's own session, identified by the proxy token in the Authorization header
+async fn caller(req: &Request, vault: &SessionVault) -> Result<Option<ResolvedSession>, String> {
+    match bearer_token(req) {
+        Some(token) => vault.resolve(&token).await,
+        None => Ok(None),
+    }
+}
+
+// List the active tokens of the caller's Last.fm user
+pub async fn list_own(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    if let Err(e) = rate_limit(&req, &ctx.env).await {
+        return e.to_response();
+    }
+    let Some(vault) = vault(&ctx.env) else {
+        return json_error(503, "Session vault is not enabled");
+    };
+
+    let session = match caller(&req, &vault).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return json_error(401, "A valid proxy token is required"),
+        Err(e) => {
+            console_error!("Failed to resolve proxy token: {}", e);
+            return json_error(503, "Session vault unavailable");
+        }
+    };
+
+    match vault.list(Some(&session.username)).await {
+        Ok(tokens) => json_response(json!({ "tokens": tokens, "current": session.id }), 200),
+        Err(e) => {
+            console_error!("Failed to list proxy tokens: {}", e);
+            json_error(503, "Session vault unavailable")
+        }
+    }
+}
+
+// Revoke one of the caller's tokens (including the one in use)
+pub async fn revoke_own(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    if let Err(e) = rate_limit(&req, &ctx.env).await {
+        return e.to_response();
+    }
+    let Some(vault) = vault(&ctx.env) else {
+        return json_error(503, "Session vault is not enabled");
+    };
+    let id = ctx.param("id").cloned().unwrap_or_default();
+
+    let session = match caller(&req, &vault).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return json_error(401, "A valid proxy token is required"),
+        Err(e) => {
+            console_error!("Failed to resolve proxy token: {}", e);
+            return json_error(503, "Session vault unavailable");
+        }
+    };
+
+    // Other users' tokens are reported as missing rather than forbidden
+    match vault.get(&id).await {
+        Ok(Some(entry)) if entry.username == session.username => {}
+        Ok(_) => return json_error(404, "Token not found"),
+        Err(e) => {
+            console_error!("Failed to read proxy token: {}", e);
+            return json_error(503, "Session vault unavailable");
+        }
+    }
+
+    revoke(&vault, &id).await
+}
+
+// Link a ListenBrainz user token (`token` in the body) to the proxy token in use
+pub async fn link_listenbrainz(
+    mut req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response, worker::Error> {
+    if let Err(e) = rate_limit(&req, &ctx.env).await {
+        return e.to_response();
+    }
+    let Some(vault) = vault(&ctx.env) else {
+        return json_error(503, "Session vault is not enabled");
+    };
+
+    let session = match caller(&req, &vault).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return json_error(401, "A valid proxy token is required"),
+        Err(e) => {
+            console_error!("Failed to resolve proxy token: {}", e);
+            return json_error(503, "Session vault unavailable");
+        }
+    };
+
+    let token = match parse_body_params(&mut req).await {
+        Ok(mut params) => params.remove("token").unwrap_or_default(),
+        Err(e) => return e.to_response(),
+    };
+    if !is_user_token(&token) {
+        return json_error(400, "Invalid token - expected a ListenBrainz user token");
+    }
+
+    match vault.link_listenbrainz(&session.id, &token).await {
+        Ok(true) => json_response(json!({ "linked": session.id }), 200),
+        Ok(false) => json_error(409, "Token is linked to another session"),
+        Err(e) => {
+            console_error!("Failed to link ListenBrainz token: {}", e);
+            json_error(503, "Session vault unavailable")
+        }
+    }
+}
+
+// Unlink the ListenBrainz user token from the proxy token in use
+pub async fn unlink_listenbrainz(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> Result<Response, worker::Error> {
+    if let Err(e) = rate_limit(&req, &ctx.env).await {
+        return e.to_response();
+    }
+    let Some(vault) = vault(&ctx.env) else {
+        return json_error(503, "Session vault is not enabled");
+    };
+
+    let session = match caller(&req, &vault).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return json_error(401, "A valid proxy token is required"),
+        Err(e) => {
+            console_error!("Failed to resolve proxy token: {}", e);
+            return json_error(503, "Session vault unavailable");
+        }
+    };
+
+    match vault.unlink_listenbrainz(&session.id).await {
+        Ok(true) => json_response(json!({ "unlinked": session.id }), 200),
+        Ok(false) => json_error(404, "No ListenBrainz token is linked"),
+        Err(e) => {
+            console_error!("Failed to unlink ListenBrainz token: {}", e);
+            json_error(503, "Session vault unavailable")
+        }
+    }
+}
+
+// List active tokens for every user, or one user with ?user=
+pub async fn admin_list(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    if !is_admin(&req, &ctx.env) {
+        return json_error(401, "Admin token required");
+    }
+    let Some(vault) = vault(&ctx.env) else {
+        return json_error(503, "Session vault is not enabled");
+    };
+
+    let user = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "user")
+        .map(|(_, v)| v.to_string());
+
+    match vault.list(user.as_deref()).await {
+        Ok(tokens) => json_response(json!({ "tokens": tokens }), 200),
+        Err(e) => {
+            console_error!("Failed to list proxy tokens: {}", e);
+            json_error(503, "Session vault unavailable")
+        }
+    }
+}
+
+// Revoke any token by id
+pub async fn admin_revoke(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    if !is_admin(&req, &ctx.env) {
+        return json_error(401, "Admin token required");
+    }
+    let Some(vault) = vault(&ctx.env) else {
+        return json_error(503, "Session vault is not enabled");
+    };
+    let id = ctx.param("id").cloned().unwrap_or_default();
+
+    revoke(&vault, &id).await
+}
+
+async fn revoke(vault: &SessionVault, id: &str) -> Result<Response, worker::Error> {
+    match vault.revoke(id).await {
+        Ok(true) => {
+            console_log!("Revoked proxy token {}", id);
+            json_response(json!({ "revoked": id }), 200)
+        }
+        Ok(false) => json_error(404, "Token not found"),
+        Err(e) => {
+            console_error!("Failed to revoke proxy token: {}", e);
+            json_error(503, "Session vault unavailable")
+        }
+    }
+}
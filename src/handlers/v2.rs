@@ -0,0 +1,171 @@
+// Versioned v2 API: the same proxied methods as v1, wrapped in a
+// {data, meta, error} envelope with normalized pagination
+
+use crate::common::envelope::{self, Meta};
+use crate::common::fields::FieldSelection;
+use crate::error::ApiError;
+use crate::middleware::{add_cors_headers, rate_limit, validate_request};
+use crate::models::CacheKey;
+use crate::throttle::RETRY_AFTER_SECS;
+use crate::utils::parse_query_params;
+use serde_json::Value;
+use worker::{console_error, console_log, Request, Response, RouteContext};
+
+// Public methods proxied by both v1 and v2 (and accepted by /batch)
+pub const V2_METHODS: &[&str] = &[
+    "artist.getCorrection",
+    "artist.getInfo",
+    "artist.getSimilar",
+    "artist.getTopAlbums",
+    "artist.getTopTags",
+    "artist.getTopTracks",
+    "artist.search",
+    "album.getInfo",
+    "album.getTopTags",
+    "album.search",
+    "track.getCorrection",
+    "track.getInfo",
+    "track.getSimilar",
+    "track.getTopTags",
+    "track.search",
+    "chart.getTopArtists",
+    "chart.getTopTags",
+    "chart.getTopTracks",
+    "geo.getTopArtists",
+    "geo.getTopTracks",
+    "tag.getInfo",
+    "tag.getSimilar",
+    "tag.getTopAlbums",
+    "tag.getTopArtists",
+    "tag.getTopTags",
+    "tag.getTopTracks",
+    "tag.getWeeklyChartList",
+    "user.getFriends",
+    "user.getInfo",
+    "user.getLovedTracks",
+    "user.getPersonalTags",
+    "user.getRecentTracks",
+    "user.getTopAlbums",
+    "user.getTopArtists",
+    "user.getTopTags",
+    "user.getTopTracks",
+    "user.getWeeklyAlbumChart",
+    "user.getWeeklyArtistChart",
+    "user.getWeeklyChartList",
+    "user.getWeeklyTrackChart",
+    "library.getArtists",
+];
+
+fn envelope_response(body: Value, status: u16) -> Result<Response, worker::Error> {
+    let mut response = Response::ok(body.to_string())?.with_status(status);
+    response
+        .headers_mut()
+        .set("Content-Type", "application/json")?;
+    add_cors_headers(response)
+}
+
+fn error_response(error: &ApiError, meta: &Meta) -> Result<Response, worker::Error> {
+    let (code, status) = if error.is_upstream_throttled() {
+        ("upstream_throttled", 503)
+    } else {
+        (
+            envelope::error_code(error.error),
+            envelope::error_status(error.error),
+        )
+    };
+
+    let body = envelope::failure(code, &error.message, Some(error.error), meta);
+    let mut response = envelope_response(body, status)?;
+    if matches!(status, 429 | 503) {
+        response
+            .headers_mut()
+            .set("Retry-After", &RETRY_AFTER_SECS.to_string())?;
+    }
+    Ok(response)
+}
+
+// GET /v2/:category/:method, e.g. /v2/artist/getInfo?artist=Cher
+pub async fn handle(req: Request, ctx: RouteContext<()>) -> Result<Response, worker::Error> {
+    let env = ctx.env.clone();
+    let method_name = format!(
+        "{}.{}",
+        ctx.param("category")
+            .map(String::as_str)
+            .unwrap_or_default(),
+        ctx.param("method").map(String::as_str).unwrap_or_default()
+    );
+    let meta = Meta {
+        method: method_name.clone(),
+        ..Meta::default()
+    };
+
+    if !V2_METHODS.contains(&method_name.as_str()) {
+        let body = envelope::failure("not_found", "Unknown method", None, &meta);
+        return envelope_response(body, 404);
+    }
+
+    // Apply rate limiting
+    if let Err(e) = rate_limit(&req, &env).await {
+        console_log!("Rate limit error: {:?}", e);
+        return error_response(&e, &meta);
+    }
+
+    // v2 always answers with a JSON envelope; `per_page` is an alias for `limit`
+    let mut params = parse_query_params(&req)?;
+    params.remove("format");
+    params.remove("callback");
+    if let Some(per_page) = params.remove("per_page") {
+        params.insert("limit".to_string(), per_page);
+    }
+
+    // Validate request
+    if let Err(e) = validate_request(&req, &env, &params, &method_name).await {
+        console_log!("Validation error: {:?}", e);
+        return error_response(&e, &meta);
+    }
+
+    let fields = params.get("fields").and_then(|f| FieldSelection::parse(f));
+    let cache_key = params.cache_key(&method_name);
+
+    let outcome = match super::fetch_cached(&env, &method_name, params, &cache_key).await {
+        Ok(outcome) => outcome,
+        Err(e) => return error_response(&e, &meta),
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    if let Some(e) = outcome.negative_error() {
+        let meta = Meta {
+            cached: true,
+            age: outcome.cache.map(|cache| cache.age(now)),
+            ..meta
+        };
+        let mut response = error_response(&e, &meta)?;
+        response
+            .headers_mut()
+            .set("X-Cache", outcome.cache_status)?;
+        return Ok(response);
+    }
+
+    let document: Value = match serde_json::from_str(&outcome.body) {
+        Ok(document) => document,
+        Err(e) => {
+            console_error!("Upstream returned invalid JSON: {}", e);
+            return error_response(&ApiError::temporary_error(), &meta);
+        }
+    };
+    let document = match fields {
+        Some(fields) => fields.apply(&document),
+        None => document,
+    };
+
+    let meta = Meta {
+        cached: outcome.cache_status != "MISS",
+        stale: outcome.cache_status == "STALE",
+        age: outcome.cache.map(|cache| cache.age(now)),
+        upstream_ms: outcome.upstream_ms,
+        ..meta
+    }
+    .with_pagination(envelope::pagination(&document));
+
+    envelope_response(envelope::success(envelope::data(&document), &meta), 200)
+}
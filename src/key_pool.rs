@@ -0,0 +1,520 @@
+// Pool of Last.fm API credentials with weighted round-robin selection.
+// Keys that come back suspended (26) or rate limited (29) sit out a cool-down.
+
+use crate::error::{ApiError, ApiResult};
+use chrono::Utc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use worker::{console_error, console_log, Env};
+
+/// Cool-down for a key Last.fm reports as suspended (error 26)
+pub const SUSPENDED_COOL_DOWN_SECS: i64 = 3600;
+
+/// Cool-down for a key that hit Last.fm's rate limit (error 29)
+pub const RATE_LIMITED_COOL_DOWN_SECS: i64 = 120;
+
+// Cool-downs are shared across isolates through the RATE_LIMIT namespace
+const COOL_DOWN_PREFIX: &str = "keypool:cooldown:";
+const COOL_DOWN_REFRESH_SECS: i64 = 30;
+
+// KV rejects expiration TTLs under a minute
+const MIN_KV_TTL: i64 = 60;
+
+/// An API key and the shared secret used to sign calls made with it
+#[derive(Clone, PartialEq)]
+pub struct ApiCredentials {
+    pub api_key: String,
+    pub api_secret: String,
+    pub weight: u32,
+    // A caller's own key rather than one of ours
+    pub client: bool,
+}
+
+impl ApiCredentials {
+    /// A caller's own key (and secret, if it shared one) forwarded to Last.fm
+    pub fn client(api_key: &str, api_secret: Option<&str>) -> Self {
+        Self {
+            api_key: api_key.to_string(),
+            api_secret: api_secret.unwrap_or_default().to_string(),
+            weight: 0,
+            client: true,
+        }
+    }
+
+    /// Short fingerprint for logs and KV key names, so raw keys never leak
+    pub fn id(&self) -> String {
+        key_id(&self.api_key)
+    }
+}
+
+// Keep secrets out of logs
+impl fmt::Debug for ApiCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiCredentials")
+            .field("id", &self.id())
+            .field("weight", &self.weight)
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+/// Fingerprint of an API key, as returned by `ApiCredentials::id`
+pub fn key_id(api_key: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(&Sha256::digest(api_key.as_bytes())[..6])
+}
+
+/// Parse `key:secret[:weight]` entries separated by whitespace or commas.
+/// Entries without a usable key or a positive weight are skipped.
+pub fn parse_key_pool(spec: &str) -> Vec<ApiCredentials> {
+    spec.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let mut parts = entry.splitn(3, ':');
+            let api_key = parts.next()?.trim();
+            let api_secret = parts.next().unwrap_or_default().trim();
+            let weight = match parts.next() {
+                Some(weight) => weight.trim().parse().ok()?,
+                None => 1,
+            };
+
+            if api_key.is_empty() || weight == 0 {
+                return None;
+            }
+
+            Some(ApiCredentials {
+                api_key: api_key.to_string(),
+                api_secret: api_secret.to_string(),
+                weight,
+                client: false,
+            })
+        })
+        .collect()
+}
+
+/// Cool-down for a Last.fm error code, if the error means the key should rest
+pub fn cool_down_for_error(code: u32) -> Option<i64> {
+    match code {
+        26 => Some(SUSPENDED_COOL_DOWN_SECS),
+        29 => Some(RATE_LIMITED_COOL_DOWN_SECS),
+        _ => None,
+    }
+}
+
+struct PoolEntry {
+    credentials: ApiCredentials,
+    current_weight: i64,
+    cool_down_until: i64,
+}
+
+/// Smooth weighted round-robin over the keys that are not cooling down
+pub struct KeyPool {
+    entries: Vec<PoolEntry>,
+}
+
+impl KeyPool {
+    pub fn new(credentials: Vec<ApiCredentials>) -> Self {
+        Self {
+            entries: credentials
+                .into_iter()
+                .map(|credentials| PoolEntry {
+                    credentials,
+                    current_weight: 0,
+                    cool_down_until: 0,
+                })
+                .collect(),
+        }
+    }
+
+    /// Pick the next key. If every key is cooling down, the one that
+    /// recovers first is used rather than failing the request outright.
+    pub fn select(&mut self, now: i64) -> Option<ApiCredentials> {
+        let available: Vec<usize> = (0..self.entries.len())
+            .filter(|&i| self.entries[i].cool_down_until <= now)
+            .collect();
+
+        if available.is_empty() {
+            return self
+                .entries
+                .iter()
+                .min_by_key(|entry| entry.cool_down_until)
+                .map(|entry| entry.credentials.clone());
+        }
+
+        let total: i64 = available
+            .iter()
+            .map(|&i| self.entries[i].credentials.weight as i64)
+            .sum();
+
+        let mut best = available[0];
+        for &i in &available {
+            let entry = &mut self.entries[i];
+            entry.current_weight += entry.credentials.weight as i64;
+            if entry.current_weight > self.entries[best].current_weight {
+                best = i;
+            }
+        }
+
+        self.entries[best].current_weight -= total;
+        Some(self.entries[best].credentials.clone())
+    }
+
+    /// Look up the credentials for a specific key
+    pub fn find(&self, api_key: &str) -> Option<ApiCredentials> {
+        self.entries
+            .iter()
+            .find(|entry| entry.credentials.api_key == api_key)
+            .map(|entry| entry.credentials.clone())
+    }
+
+    /// Look up a key by its fingerprint
+    pub fn find_by_id(&self, id: &str) -> Option<ApiCredentials> {
+        self.entries
+            .iter()
+            .find(|entry| entry.credentials.id() == id)
+            .map(|entry| entry.credentials.clone())
+    }
+
+    /// The key a session was issued for, even while it cools down, since
+    /// Last.fm only accepts a session key signed with that key. Without one
+    /// (or once it has left the pool) the next key is selected.
+    pub fn select_bound(&mut self, key_id: Option<&str>, now: i64) -> Option<ApiCredentials> {
+        key_id
+            .and_then(|id| self.find_by_id(id))
+            .or_else(|| self.select(now))
+    }
+
+    /// Take a key (by fingerprint) out of rotation until `until`
+    pub fn cool_down(&mut self, id: &str, until: i64) {
+        for entry in &mut self.entries {
+            if entry.credentials.id() == id {
+                entry.cool_down_until = entry.cool_down_until.max(until);
+            }
+        }
+    }
+}
+
+// Per-isolate pool state; rebuilt if the configured keys change
+struct PoolState {
+    spec: String,
+    pool: KeyPool,
+    refreshed_at: i64,
+}
+
+thread_local! {
+    static POOL: RefCell<Option<PoolState>> = const { RefCell::new(None) };
+}
+
+// LASTFM_API_KEYS holds the pool; a single LASTFM_API_KEY/LASTFM_API_SECRET pair still works
+fn load_spec(env: &Env) -> ApiResult<String> {
+    if let Ok(keys) = env.secret("LASTFM_API_KEYS") {
+        return Ok(keys.to_string());
+    }
+
+    match env.secret("LASTFM_API_KEY") {
+        Ok(key) => {
+            let secret = env
+                .secret("LASTFM_API_SECRET")
+                .map(|s| s.to_string())
+                .unwrap_or_default();
+            Ok(format!("{key}:{secret}"))
+        }
+        Err(e) => {
+            console_error!("Failed to get LASTFM_API_KEY: {:?}", e);
+            Err(ApiError::temporary_error())
+        }
+    }
+}
+
+// Pull cool-downs recorded by other isolates
+async fn load_cool_downs(env: &Env) -> Vec<(String, i64)> {
+    let kv = match env.kv("RATE_LIMIT") {
+        Ok(kv) => kv,
+        Err(_) => return Vec::new(),
+    };
+
+    let keys = match kv
+        .list()
+        .prefix(COOL_DOWN_PREFIX.to_string())
+        .execute()
+        .await
+    {
+        Ok(page) => page.keys,
+        Err(e) => {
+            console_error!("Failed to list key cool-downs: {:?}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut cool_downs = Vec::new();
+    for key in keys {
+        let until = kv.get(&key.name).text().await.ok().flatten();
+        if let Some(until) = until.and_then(|v| v.parse().ok()) {
+            let id = key.name.trim_start_matches(COOL_DOWN_PREFIX).to_string();
+            cool_downs.push((id, until));
+        }
+    }
+    cool_downs
+}
+
+/// Credentials for the next upstream call. A `pinned` key that belongs to the
+/// pool is honoured so signed calls stay on the key they were signed with.
+pub async fn credentials(env: &Env, pinned: Option<&str>) -> ApiResult<ApiCredentials> {
+    pick(env, |pool, now| {
+        pinned
+            .and_then(|key| pool.find(key))
+            .or_else(|| pool.select(now))
+    })
+    .await
+}
+
+/// Credentials for a call carrying a session key, on the pooled key (by
+/// fingerprint) the session was issued for
+pub async fn session_credentials(env: &Env, key_id: Option<&str>) -> ApiResult<ApiCredentials> {
+    pick(env, |pool, now| pool.select_bound(key_id, now)).await
+}
+
+/// Key a raw session key was issued for, as the caller identifies it: a
+/// `key_id` parameter, or else the fingerprint of its `api_key`
+pub fn requested_key_id(params: &HashMap<String, String>) -> Option<String> {
+    params
+        .get("key_id")
+        .filter(|id| !id.is_empty())
+        .cloned()
+        .or_else(|| {
+            params
+                .get("api_key")
+                .filter(|key| !key.is_empty())
+                .map(|key| key_id(key))
+        })
+}
+
+async fn pick(
+    env: &Env,
+    choose: impl FnOnce(&mut KeyPool, i64) -> Option<ApiCredentials>,
+) -> ApiResult<ApiCredentials> {
+    let spec = load_spec(env)?;
+    let now = Utc::now().timestamp();
+
+    let needs_refresh = POOL.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.as_ref().map(|s| s.spec != spec).unwrap_or(true) {
+            *state = Some(PoolState {
+                pool: KeyPool::new(parse_key_pool(&spec)),
+                spec: spec.clone(),
+                refreshed_at: 0,
+            });
+        }
+        state
+            .as_ref()
+            .map(|s| now - s.refreshed_at >= COOL_DOWN_REFRESH_SECS)
+            .unwrap_or(false)
+    });
+
+    if needs_refresh {
+        let cool_downs = load_cool_downs(env).await;
+        POOL.with(|state| {
+            if let Some(state) = state.borrow_mut().as_mut() {
+                for (id, until) in &cool_downs {
+                    state.pool.cool_down(id, *until);
+                }
+                state.refreshed_at = now;
+            }
+        });
+    }
+
+    let selected = POOL.with(|state| {
+        let mut state = state.borrow_mut();
+        choose(&mut state.as_mut()?.pool, now)
+    });
+
+    selected.ok_or_else(|| {
+        console_error!("No Last.fm API keys configured");
+        ApiError::temporary_error()
+    })
+}
+
+/// Credentials for a call carrying the caller's `api_key`. With
+/// `allow_client_keys` a key outside the pool is forwarded as the caller's own,
+/// so Last.fm charges its quota; otherwise this is `credentials`.
+pub async fn request_credentials(
+    env: &Env,
+    api_key: Option<&str>,
+    allow_client_keys: bool,
+) -> ApiResult<ApiCredentials> {
+    let Some(api_key) = api_key.filter(|key| allow_client_keys && !key.is_empty()) else {
+        return credentials(env, api_key).await;
+    };
+
+    match credentials(env, Some(api_key)).await {
+        Ok(pooled) if pooled.api_key == api_key => Ok(pooled),
+        _ => Ok(ApiCredentials::client(api_key, None)),
+    }
+}
+
+/// Take a key out of rotation if Last.fm answered with error 26 or 29
+pub async fn report_error(env: &Env, credentials: &ApiCredentials, code: u32) {
+    // A caller's own key is theirs to manage
+    if credentials.client {
+        return;
+    }
+    let Some(cool_down) = cool_down_for_error(code) else {
+        return;
+    };
+
+    let id = credentials.id();
+    let until = Utc::now().timestamp() + cool_down;
+    console_log!(
+        "Cooling down Last.fm key {} for {}s after error {}",
+        id,
+        cool_down,
+        code
+    );
+
+    POOL.with(|state| {
+        if let Some(state) = state.borrow_mut().as_mut() {
+            state.pool.cool_down(&id, until);
+        }
+    });
+
+    let kv = match env.kv("RATE_LIMIT") {
+        Ok(kv) => kv,
+        Err(_) => return,
+    };
+    let ttl = cool_down.max(MIN_KV_TTL) as u64;
+    let result = match kv.put(&format!("{COOL_DOWN_PREFIX}{id}"), until.to_string()) {
+        Ok(builder) => builder.expiration_ttl(ttl).execute().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        console_error!("Failed to record key cool-down: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(spec: &str) -> KeyPool {
+        KeyPool::new(parse_key_pool(spec))
+    }
+
+    fn picks(pool: &mut KeyPool, now: i64, n: usize) -> Vec<String> {
+        (0..n).map(|_| pool.select(now).unwrap().api_key).collect()
+    }
+
+    #[test]
+    fn test_parse_key_pool() {
+        let keys = parse_key_pool("a:sa:3, b:sb\nc:sc:0 :orphan d");
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[0].weight, 3);
+        assert_eq!(keys[1].api_secret, "sb");
+        assert_eq!(keys[2].api_key, "d");
+        assert_eq!(keys[2].api_secret, "");
+    }
+
+    #[test]
+    fn test_weighted_round_robin_is_smooth() {
+        let mut pool = pool("a:x:2 b:y:1");
+        assert_eq!(picks(&mut pool, 0, 6), vec!["a", "b", "a", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn test_cooled_down_key_leaves_rotation_until_expiry() {
+        let mut pool = pool("a:x b:y");
+        let id = pool.find("a").unwrap().id();
+        pool.cool_down(&id, 100);
+
+        assert_eq!(picks(&mut pool, 50, 3), vec!["b", "b", "b"]);
+
+        let later = picks(&mut pool, 100, 4);
+        assert!(later.contains(&"a".to_string()));
+    }
+
+    #[test]
+    fn test_all_keys_cooling_down_uses_first_to_recover() {
+        let mut pool = pool("a:x b:y");
+        let (a, b) = (pool.find("a").unwrap().id(), pool.find("b").unwrap().id());
+        pool.cool_down(&a, 300);
+        pool.cool_down(&b, 200);
+
+        assert_eq!(pool.select(0).unwrap().api_key, "b");
+    }
+
+    #[test]
+    fn test_session_stays_on_issuing_key() {
+        let mut pool = pool("a:x b:y");
+        let b = pool.find("b").unwrap().id();
+
+        // Rotation alone would alternate between the two keys
+        assert_eq!(picks(&mut pool, 0, 2), vec!["a", "b"]);
+        for _ in 0..3 {
+            assert_eq!(pool.select_bound(Some(&b), 0).unwrap().api_key, "b");
+        }
+
+        // A session key is only valid with its own key, cooling down or not
+        pool.cool_down(&b, 100);
+        assert_eq!(pool.select_bound(Some(&b), 50).unwrap().api_key, "b");
+
+        // Unknown or missing bindings fall back to rotation
+        assert_eq!(pool.select_bound(Some("gone"), 50).unwrap().api_key, "a");
+        assert_eq!(pool.select_bound(None, 50).unwrap().api_key, "a");
+
+        // Signing with the bound key uses that key's secret
+        let mut params = HashMap::from([("sk".to_string(), "session".to_string())]);
+        let credentials = pool.select_bound(Some(&b), 50).unwrap();
+        crate::utils::sign_lastfm_params_with("track.scrobble", &mut params, credentials).unwrap();
+        assert_eq!(params["api_key"], "b");
+        let signature = params.remove("api_sig").unwrap();
+        assert_eq!(signature, crate::utils::sign_request_md5(&params, "y"));
+    }
+
+    #[test]
+    fn test_requested_key_id() {
+        let params = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            requested_key_id(&params(&[("key_id", "abc"), ("api_key", "b")])),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            requested_key_id(&params(&[("api_key", "b")])),
+            Some(key_id("b"))
+        );
+        assert_eq!(requested_key_id(&params(&[("sk", "s")])), None);
+    }
+
+    #[test]
+    fn test_cool_down_errors() {
+        assert_eq!(cool_down_for_error(26), Some(SUSPENDED_COOL_DOWN_SECS));
+        assert_eq!(cool_down_for_error(29), Some(RATE_LIMITED_COOL_DOWN_SECS));
+        assert_eq!(cool_down_for_error(6), None);
+    }
+
+    #[test]
+    fn test_debug_output_redacts_secrets() {
+        let keys = parse_key_pool("public-key:very-secret");
+        let debug = format!("{:?}", keys[0]);
+        assert!(!debug.contains("very-secret"));
+        assert!(!debug.contains("public-key"));
+    }
+
+    #[test]
+    fn test_client_credentials() {
+        let client = ApiCredentials::client("partner-key", Some("partner-secret"));
+        assert!(client.client);
+        assert_eq!(client.api_secret, "partner-secret");
+        assert_eq!(client.id(), key_id("partner-key"));
+        assert!(!format!("{client:?}").contains("partner-secret"));
+
+        assert!(ApiCredentials::client("partner-key", None)
+            .api_secret
+            .is_empty());
+        assert!(!parse_key_pool("ours:secret")[0].client);
+    }
+}
@@ -1,11 +1,19 @@
 use worker::*;
 
+mod cache_codec;
 mod common;
+mod config;
+mod diagnostics;
 pub mod error;
 mod handlers;
+mod key_pool;
 pub mod middleware;
 pub mod models;
+pub mod scrobble;
+mod throttle;
 mod utils;
+mod vault;
+mod warming;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod cli;
@@ -14,18 +22,25 @@ pub mod cli;
 #[cfg(test)]
 pub use models::sign_request;
 
-use handlers::{album, artist, auth, chart, geo, library, tag, track, user};
+use handlers::{
+    album, artist, audioscrobbler, auth, badge, batch, chart, chartdiff, compare, feed, geo,
+    health, library, listenbrainz, recommendations, scrobble as scrobbles, search, stream, tag,
+    tokens, track, user, v2,
+};
 use serde_json::Value;
 
 #[event(fetch)]
 async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
 
+    let config = config::Config::from_env(&env);
+    let origin = req.headers().get("Origin").ok().flatten();
     let router = Router::new();
 
     router
         // Health check
         .get("/health", |_, _| Response::ok("OK"))
+        .get_async("/health/deep", health::deep)
         // API Documentation endpoints
         .get("/api/docs", |_, _| {
             let swagger_ui = include_str!("swagger-ui.html");
@@ -88,6 +103,27 @@ async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         .get_async("/track/getSimilar", track::get_similar)
         .get_async("/track/getTopTags", track::get_top_tags)
         .get_async("/track/search", track::search)
+        // Artists, albums and tracks in one ranked list
+        .get_async("/search", search::handle)
+        // Server-Sent Events when a user's now-playing track changes
+        .get_async("/stream/nowplaying", stream::now_playing)
+        // Atom/RSS feeds: recent.atom, loved.rss, weekly-artists.atom, ...
+        .get_async("/feed/:user/:feed", feed::handle)
+        // SVG cards: nowplaying.svg, top-artists.svg
+        .get_async("/badge/:user/:badge", badge::handle)
+        // Audioscrobbler 1.2 protocol for legacy players
+        .get_async("/audioscrobbler", audioscrobbler::handshake)
+        .get_async("/audioscrobbler/", audioscrobbler::handshake)
+        .post_async("/audioscrobbler/nowplaying", audioscrobbler::now_playing)
+        .post_async("/audioscrobbler/submission", audioscrobbler::submission)
+        // ListenBrainz-compatible submissions
+        .post_async("/1/submit-listens", listenbrainz::submit_listens)
+        .get_async("/1/validate-token", listenbrainz::validate_token)
+        // Several public reads in one request
+        .post_async("/batch", batch::handle)
+        // Scrobble queue (submitted to Last.fm by the scheduled handler)
+        .post_async("/track/scrobble", scrobbles::submit)
+        .post_async("/track/scrobble/status", scrobbles::status)
         // Chart endpoints
         .get_async("/chart/getTopArtists", chart::get_top_artists)
         .get_async("/chart/getTopTags", chart::get_top_tags)
@@ -117,12 +153,28 @@ async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         .get_async("/user/getWeeklyArtistChart", user::get_weekly_artist_chart)
         .get_async("/user/getWeeklyChartList", user::get_weekly_chart_list)
         .get_async("/user/getWeeklyTrackChart", user::get_weekly_track_chart)
+        // Artists similar to a user's top artists, from cached lookups
+        .get_async("/user/recommendations", recommendations::handle)
+        // Taste compatibility between two users
+        .get_async("/user/compare", compare::handle)
+        // A weekly chart annotated with movements since the week before
+        .get_async("/user/weeklyChartDiff", chartdiff::handle)
         // Library endpoints
         .get_async("/library/getArtists", library::get_artists)
         // Auth endpoints (require API secret)
         .get_async("/auth/getSession", auth::get_session)
-        .get_async("/auth/getMobileSession", auth::get_mobile_session)
+        .get_async("/auth/getMobileSession", auth::get_mobile_session_via_query)
+        .post_async("/auth/getMobileSession", auth::get_mobile_session)
         .get_async("/auth/url", auth::get_auth_url)
+        // Proxy tokens issued by the session vault
+        .get_async("/auth/tokens", tokens::list_own)
+        .delete_async("/auth/tokens/:id", tokens::revoke_own)
+        .post_async("/auth/listenbrainz", tokens::link_listenbrainz)
+        .delete_async("/auth/listenbrainz", tokens::unlink_listenbrainz)
+        .get_async("/admin/tokens", tokens::admin_list)
+        .delete_async("/admin/tokens/:id", tokens::admin_revoke)
+        // Versioned API with a {data, meta, error} envelope
+        .get_async("/v2/:category/:method", v2::handle)
         // Catch all for unmatched routes
         .or_else_any_method_async(
             "/:path",
@@ -130,8 +182,27 @@ async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         )
         .run(req, env)
         .await
+        .and_then(|response| middleware::apply_cors_policy(response, &config, origin.as_deref()))
         .or_else(|err| {
             console_error!("Router error: {}", err);
             Response::error("Internal Server Error", 500)
         })
 }
+
+#[event(scheduled)]
+async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+
+    let cron = event.cron();
+
+    // Submit queued scrobbles on their own trigger only; the warming cron also
+    // fires at :00 and :30, and two drains at once would submit entries twice
+    if cron == scrobble::SCROBBLE_QUEUE_CRON {
+        scrobble::process_queue(&env).await;
+    }
+
+    // Refresh hot cache keys before they expire
+    if cron == warming::CACHE_WARMING_CRON {
+        warming::warm_cache(&env, &cron).await;
+    }
+}
@@ -1,3 +1,4 @@
+use crate::config::{Config, CorsPolicy};
 use crate::error::{ApiError, ApiResult};
 use crate::models::rate_limit_key;
 use crate::utils::{get_client_ip, validate_signature};
@@ -8,20 +9,80 @@ use worker::{Env, Request, Response};
 pub fn add_cors_headers(mut response: Response) -> Result<Response, worker::Error> {
     let headers = response.headers_mut();
     headers.set("Access-Control-Allow-Origin", "*")?;
-    headers.set("Access-Control-Allow-Methods", "GET, OPTIONS")?;
+    headers.set("Access-Control-Allow-Methods", "GET, POST, DELETE, OPTIONS")?;
     headers.set(
         "Access-Control-Allow-Headers",
-        "Content-Type, X-Request-Signature",
+        "Content-Type, Authorization, X-Request-Signature, X-Lastfm-Api-Secret",
     )?;
     headers.set("Access-Control-Max-Age", "86400")?;
+    headers.set(
+        "Access-Control-Expose-Headers",
+        "X-Request-Id, X-Cache, X-Cache-Age, X-Cache-TTL, X-Upstream-Time, \
+         X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset, Server-Timing",
+    )?;
+
+    Ok(response)
+}
+
+// Narrow the handlers' permissive CORS headers to the configured allow-list
+pub fn apply_cors_policy(
+    mut response: Response,
+    config: &Config,
+    origin: Option<&str>,
+) -> Result<Response, worker::Error> {
+    if config.cors == CorsPolicy::AnyOrigin
+        || !response.headers().has("Access-Control-Allow-Origin")?
+    {
+        return Ok(response);
+    }
+
+    let headers = response.headers_mut();
+    match config.cors.allow_origin(origin) {
+        Some(allowed) => headers.set("Access-Control-Allow-Origin", &allowed)?,
+        None => headers.delete("Access-Control-Allow-Origin")?,
+    }
+    headers.append("Vary", "Origin")?;
 
     Ok(response)
 }
 
+// Per-IP budget left after a request was counted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    // Counters expire a minute after the last request
+    pub reset_secs: u64,
+}
+
+impl RateLimitStatus {
+    pub fn exhausted(limit: u32) -> Self {
+        Self {
+            limit,
+            remaining: 0,
+            reset_secs: RATE_LIMIT_WINDOW_SECS,
+        }
+    }
+}
+
+/// Per-client counters cover one minute
+pub const RATE_LIMIT_WINDOW_SECS: u64 = 60;
+
 // Rate limiting middleware
-pub async fn rate_limit(req: &Request, env: &Env) -> ApiResult<()> {
+pub async fn rate_limit(req: &Request, env: &Env) -> ApiResult<RateLimitStatus> {
+    rate_limit_many(req, env, 1).await.map(|(_, status)| status)
+}
+
+// Count up to `cost` requests at once (e.g. the items of a batch), admitting as
+// many as the budget has room for. Errors only when none fit.
+pub async fn rate_limit_many(
+    req: &Request,
+    env: &Env,
+    cost: u32,
+) -> ApiResult<(u32, RateLimitStatus)> {
+    let config = Config::from_env(env);
     let ip = get_client_ip(req);
-    let key = rate_limit_key(&ip);
+    let key = config.rate_limit_key(&rate_limit_key(&ip));
 
     let kv = match env.kv("RATE_LIMIT") {
         Ok(kv) => kv,
@@ -32,32 +93,37 @@ pub async fn rate_limit(req: &Request, env: &Env) -> ApiResult<()> {
     };
 
     // Get current count
-    worker::console_log!("Getting rate limit for key: {}", key);
+    if config.verbose_logs {
+        worker::console_log!("Getting rate limit for key: {}", key);
+    }
     let count = match kv.get(&key).text().await {
         Ok(Some(count_str)) => {
-            worker::console_log!("Current count: {}", count_str);
+            if config.verbose_logs {
+                worker::console_log!("Current count: {}", count_str);
+            }
             count_str.parse::<u32>().unwrap_or(0)
         }
-        Ok(None) => {
-            worker::console_log!("No existing count");
-            0
-        }
+        Ok(None) => 0,
         Err(e) => {
             worker::console_log!("Error getting count: {:?}", e);
             0
         }
     };
 
-    // Check rate limit (100 requests per minute)
-    if count >= 100 {
+    // Check rate limit (requests per minute, 100 by default)
+    let admitted = cost.min(config.rate_limit_per_minute.saturating_sub(count));
+    if admitted == 0 {
         return Err(ApiError::rate_limit_exceeded());
     }
 
     // Increment counter
-    worker::console_log!("Incrementing counter to {}", count + 1);
-    match kv.put(&key, (count + 1).to_string()) {
-        Ok(builder) => match builder.expiration_ttl(60).execute().await {
-            Ok(_) => worker::console_log!("Rate limit updated successfully"),
+    match kv.put(&key, (count + admitted).to_string()) {
+        Ok(builder) => match builder
+            .expiration_ttl(RATE_LIMIT_WINDOW_SECS)
+            .execute()
+            .await
+        {
+            Ok(_) => {}
             Err(e) => {
                 worker::console_log!("Failed to update rate limit: {:?}", e);
                 return Err(ApiError::temporary_error());
@@ -69,7 +135,12 @@ pub async fn rate_limit(req: &Request, env: &Env) -> ApiResult<()> {
         }
     }
 
-    Ok(())
+    let status = RateLimitStatus {
+        limit: config.rate_limit_per_minute,
+        remaining: config.rate_limit_per_minute - (count + admitted),
+        reset_secs: RATE_LIMIT_WINDOW_SECS,
+    };
+    Ok((admitted, status))
 }
 
 // Request validation middleware
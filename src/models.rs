@@ -10,9 +10,7 @@ impl CacheKey for HashMap<String, String> {
     fn cache_key(&self, method: &str) -> String {
         let mut params: Vec<(String, String)> = self
             .iter()
-            .filter(|(k, _)| {
-                k.as_str() != "api_key" && k.as_str() != "format" && k.as_str() != "callback"
-            })
+            .filter(|(k, _)| !matches!(k.as_str(), "api_key" | "format" | "callback" | "fields"))
             .map(|(k, v)| (k.clone(), v.clone()))
             .collect();
 
@@ -0,0 +1,537 @@
+// Durable scrobble queue: submissions are acknowledged immediately, stored per
+// session and sent to Last.fm in batches from the scheduled handler.
+
+mod store;
+
+#[cfg(test)]
+mod tests;
+
+pub use store::{KvScrobbleStore, MemoryScrobbleStore, ScrobbleStore};
+
+use crate::key_pool::requested_key_id;
+use crate::utils::{proxy_post_to_lastfm, sign_lastfm_params};
+use crate::vault::{is_proxy_token, SessionVault};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use worker::{console_error, console_log, Env};
+
+/// Last.fm accepts at most 50 scrobbles per track.scrobble call
+pub const MAX_BATCH_SIZE: usize = 50;
+
+/// Give up on a scrobble after this many failed submissions
+pub const MAX_ATTEMPTS: u32 = 8;
+
+/// Cron trigger that drains the queue (must match wrangler.toml)
+pub const SCROBBLE_QUEUE_CRON: &str = "*/5 * * * *";
+
+// Backoff starts at one minute and is capped at six hours
+const BASE_BACKOFF_SECS: i64 = 60;
+const MAX_BACKOFF_SECS: i64 = 6 * 3600;
+
+/// A single play, in Last.fm's track.scrobble terms
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scrobble {
+    pub artist: String,
+    pub track: String,
+    pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mbid: Option<String>,
+}
+
+// Optional per-scrobble track.scrobble parameters
+const OPTIONAL_FIELDS: [&str; 5] = ["album", "albumArtist", "trackNumber", "duration", "mbid"];
+
+impl Scrobble {
+    fn optional_field(&self, name: &str) -> Option<&String> {
+        match name {
+            "album" => self.album.as_ref(),
+            "albumArtist" => self.album_artist.as_ref(),
+            "trackNumber" => self.track_number.as_ref(),
+            "duration" => self.duration.as_ref(),
+            "mbid" => self.mbid.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// A submission parsed from Last.fm-style track.scrobble parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrobbleSubmission {
+    pub session_key: String,
+    /// Pooled API key a raw session key was issued for; proxy tokens carry
+    /// their own in the vault
+    pub api_key_id: Option<String>,
+    pub scrobbles: Vec<Scrobble>,
+}
+
+impl ScrobbleSubmission {
+    /// Parse `sk` plus either `artist`/`track`/`timestamp` or the indexed
+    /// `artist[i]`/`track[i]`/`timestamp[i]` form used by Last.fm clients.
+    /// The session's API key comes from `key_id` or `api_key`.
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let session_key = params
+            .get("sk")
+            .filter(|sk| !sk.is_empty())
+            .cloned()
+            .ok_or_else(|| "Missing required parameter: sk".to_string())?;
+
+        let mut scrobbles = Vec::new();
+        if params.contains_key("artist") {
+            scrobbles.push(Self::scrobble_at(params, |name| name.to_string())?);
+        } else {
+            for i in 0..MAX_BATCH_SIZE {
+                if !params.contains_key(&format!("artist[{i}]")) {
+                    break;
+                }
+                scrobbles.push(Self::scrobble_at(params, |name| format!("{name}[{i}]"))?);
+            }
+        }
+
+        if scrobbles.is_empty() {
+            return Err("Missing required parameters: artist, track and timestamp".to_string());
+        }
+        if params.contains_key(&format!("artist[{MAX_BATCH_SIZE}]")) {
+            return Err(format!(
+                "Too many scrobbles - at most {MAX_BATCH_SIZE} per request"
+            ));
+        }
+
+        Ok(Self {
+            session_key,
+            api_key_id: requested_key_id(params),
+            scrobbles,
+        })
+    }
+
+    fn scrobble_at(
+        params: &HashMap<String, String>,
+        key: impl Fn(&str) -> String,
+    ) -> Result<Scrobble, String> {
+        let required = |name: &str| {
+            params
+                .get(&key(name))
+                .filter(|v| !v.is_empty())
+                .cloned()
+                .ok_or_else(|| format!("Missing required parameter: {}", key(name)))
+        };
+        let optional = |name: &str| params.get(&key(name)).filter(|v| !v.is_empty()).cloned();
+
+        let timestamp = required("timestamp")?.parse::<i64>().map_err(|_| {
+            format!(
+                "Invalid parameter: {} must be a UNIX timestamp",
+                key("timestamp")
+            )
+        })?;
+
+        Ok(Scrobble {
+            artist: required("artist")?,
+            track: required("track")?,
+            timestamp,
+            album: optional("album"),
+            album_artist: optional("albumArtist"),
+            track_number: optional("trackNumber"),
+            duration: optional("duration"),
+            mbid: optional("mbid"),
+        })
+    }
+}
+
+/// Opaque queue identifier for a session, so raw session keys never appear in KV key names
+pub fn session_id(session_key: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::digest(session_key.as_bytes());
+    hex::encode(&digest[..12])
+}
+
+/// Up to `MAX_BATCH_SIZE` scrobbles from one submission, queued as one entry
+/// so the KV writes for an import scale with its batches rather than its plays
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueuedScrobble {
+    pub id: String,
+    pub session_id: String,
+    pub session_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<String>,
+    pub scrobbles: Vec<Scrobble>,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub enqueued_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl QueuedScrobble {
+    pub fn new(
+        session_key: &str,
+        api_key_id: Option<&str>,
+        scrobbles: Vec<Scrobble>,
+        now: i64,
+    ) -> Self {
+        let session_id = session_id(session_key);
+        // Deterministic ids make client retries of the same plays idempotent
+        let mut context = md5::Context::new();
+        context.consume(session_id.as_bytes());
+        for scrobble in &scrobbles {
+            context.consume(format!(
+                "|{}|{}|{}",
+                scrobble.artist, scrobble.track, scrobble.timestamp
+            ));
+        }
+        let first = scrobbles.first().map_or(0, |s| s.timestamp);
+        let id = format!("{first:010}:{}", &format!("{:x}", context.compute())[..16]);
+
+        Self {
+            id,
+            session_id,
+            session_key: session_key.to_string(),
+            api_key_id: api_key_id.map(str::to_string),
+            scrobbles,
+            attempts: 0,
+            next_attempt_at: now,
+            enqueued_at: now,
+            last_error: None,
+        }
+    }
+}
+
+/// Per-session counters exposed by the status endpoint
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScrobbleStats {
+    #[serde(default)]
+    pub queued: u64,
+    #[serde(default)]
+    pub accepted: u64,
+    #[serde(default)]
+    pub ignored: u64,
+    #[serde(default)]
+    pub failed: u64,
+}
+
+/// Result of submitting one batch upstream
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubmitOutcome {
+    /// Last.fm processed the batch
+    Submitted { accepted: u64, ignored: u64 },
+    /// Transient failure (error 11, 16, 29 or network); retry later
+    Retry(String),
+    /// Permanent failure (e.g. invalid session); drop the batch
+    Rejected(String),
+}
+
+/// Sends a batch of scrobbles for one session to Last.fm
+#[async_trait(?Send)]
+pub trait ScrobbleSubmitter {
+    /// `api_key_id` is the pooled key the session was issued for, if known
+    async fn submit(
+        &self,
+        session_key: &str,
+        api_key_id: Option<&str>,
+        batch: &[Scrobble],
+    ) -> SubmitOutcome;
+}
+
+/// Submits batches to Last.fm with signed track.scrobble POST calls
+pub struct LastfmScrobbleSubmitter<'a> {
+    env: &'a Env,
+}
+
+impl<'a> LastfmScrobbleSubmitter<'a> {
+    pub fn new(env: &'a Env) -> Self {
+        Self { env }
+    }
+}
+
+#[async_trait(?Send)]
+impl ScrobbleSubmitter for LastfmScrobbleSubmitter<'_> {
+    async fn submit(
+        &self,
+        session_key: &str,
+        api_key_id: Option<&str>,
+        batch: &[Scrobble],
+    ) -> SubmitOutcome {
+        // Proxy tokens are resolved at submission time, so revoking one also
+        // stops its queued scrobbles
+        let (session_key, api_key_id) = if is_proxy_token(session_key) {
+            let resolved = match SessionVault::from_env(self.env) {
+                Some(vault) => vault.resolve(session_key).await,
+                None => Ok(None),
+            };
+            match resolved {
+                Ok(Some(session)) => (session.session_key, session.api_key_id),
+                Ok(None) => return SubmitOutcome::Rejected("Invalid session key".to_string()),
+                Err(e) => return SubmitOutcome::Retry(e),
+            }
+        } else {
+            (session_key.to_string(), api_key_id.map(str::to_string))
+        };
+
+        let mut params = batch_params(&session_key, batch);
+        let signed = sign_lastfm_params(
+            self.env,
+            "track.scrobble",
+            &mut params,
+            api_key_id.as_deref(),
+        )
+        .await;
+        if let Err(e) = signed {
+            return SubmitOutcome::Retry(e.message);
+        }
+
+        let mut response = match proxy_post_to_lastfm(self.env, "track.scrobble", params).await {
+            Ok(response) => response,
+            Err(e) => return SubmitOutcome::Retry(e.message),
+        };
+
+        match response.text().await {
+            Ok(body) => parse_submit_response(&body),
+            Err(e) => {
+                console_error!("Failed to read track.scrobble response: {}", e);
+                SubmitOutcome::Retry("Failed to read response".to_string())
+            }
+        }
+    }
+}
+
+/// Last.fm errors worth retrying: service offline, temporary error, rate limited
+pub fn is_retryable_error(code: u32) -> bool {
+    matches!(code, 11 | 16 | 29)
+}
+
+/// Exponential backoff delay before the next attempt
+pub fn backoff_seconds(attempts: u32) -> i64 {
+    let exponent = attempts.saturating_sub(1).min(16);
+    (BASE_BACKOFF_SECS << exponent).min(MAX_BACKOFF_SECS)
+}
+
+/// Build indexed track.scrobble parameters for a batch
+pub fn batch_params(session_key: &str, batch: &[Scrobble]) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    params.insert("sk".to_string(), session_key.to_string());
+
+    for (i, scrobble) in batch.iter().enumerate() {
+        params.insert(format!("artist[{i}]"), scrobble.artist.clone());
+        params.insert(format!("track[{i}]"), scrobble.track.clone());
+        params.insert(format!("timestamp[{i}]"), scrobble.timestamp.to_string());
+        for name in OPTIONAL_FIELDS {
+            if let Some(value) = scrobble.optional_field(name) {
+                params.insert(format!("{name}[{i}]"), value.clone());
+            }
+        }
+    }
+
+    params
+}
+
+/// Interpret a track.scrobble response body
+pub fn parse_submit_response(body: &str) -> SubmitOutcome {
+    if let Some(error) = crate::utils::parse_lastfm_error(body) {
+        return if is_retryable_error(error.error) {
+            SubmitOutcome::Retry(error.message)
+        } else {
+            SubmitOutcome::Rejected(error.message)
+        };
+    }
+
+    let value: serde_json::Value = match serde_json::from_str(body) {
+        Ok(value) => value,
+        Err(e) => return SubmitOutcome::Retry(format!("Invalid response: {e}")),
+    };
+
+    let count = |name: &str| {
+        let attr = &value["scrobbles"]["@attr"][name];
+        attr.as_u64()
+            .or_else(|| attr.as_str().and_then(|s| s.parse().ok()))
+            .unwrap_or(0)
+    };
+
+    SubmitOutcome::Submitted {
+        accepted: count("accepted"),
+        ignored: count("ignored"),
+    }
+}
+
+/// Summary of one queue run
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DrainReport {
+    pub batches: u64,
+    pub accepted: u64,
+    pub ignored: u64,
+    pub retried: u64,
+    pub failed: u64,
+}
+
+/// Queue every scrobble in a submission, one entry per batch of up to
+/// `MAX_BATCH_SIZE`, and return how many scrobbles were queued
+pub async fn enqueue<S: ScrobbleStore>(
+    store: &S,
+    submission: ScrobbleSubmission,
+    now: i64,
+) -> Result<usize, String> {
+    let count = submission.scrobbles.len();
+    let mut added = ScrobbleStats::default();
+    for chunk in submission.scrobbles.chunks(MAX_BATCH_SIZE) {
+        let entry = QueuedScrobble::new(
+            &submission.session_key,
+            submission.api_key_id.as_deref(),
+            chunk.to_vec(),
+            now,
+        );
+        if store.enqueue(&entry).await? {
+            added.queued += chunk.len() as u64;
+        }
+    }
+
+    if added.queued > 0 {
+        store
+            .record(&session_id(&submission.session_key), &added, 0)
+            .await?;
+    }
+    Ok(count)
+}
+
+// Split a session's entries into upstream batches: consecutive entries for the
+// same API key are packed together while they fit in one track.scrobble call
+fn pack_batches(entries: Vec<QueuedScrobble>) -> Vec<Vec<QueuedScrobble>> {
+    let mut batches: Vec<Vec<QueuedScrobble>> = Vec::new();
+    let mut size = 0;
+    for entry in entries {
+        let fits = batches.last().is_some_and(|batch| {
+            batch[0].api_key_id == entry.api_key_id
+                && size + entry.scrobbles.len() <= MAX_BATCH_SIZE
+        });
+        if fits {
+            size += entry.scrobbles.len();
+            if let Some(batch) = batches.last_mut() {
+                batch.push(entry);
+            }
+        } else {
+            size = entry.scrobbles.len();
+            batches.push(vec![entry]);
+        }
+    }
+    batches
+}
+
+/// Submit due entries in batches of up to 50 scrobbles per session.
+/// Transient failures are rescheduled with exponential backoff and
+/// dropped once they reach `MAX_ATTEMPTS`. Counts are in scrobbles.
+pub async fn drain_queue<S: ScrobbleStore, U: ScrobbleSubmitter>(
+    store: &S,
+    submitter: &U,
+    now: i64,
+    max_entries: usize,
+) -> Result<DrainReport, String> {
+    // The store picks due entries, but one may have been rescheduled since
+    let due: Vec<QueuedScrobble> = store
+        .pending(now, max_entries)
+        .await?
+        .into_iter()
+        .filter(|entry| entry.next_attempt_at <= now)
+        .collect();
+
+    // Group by session, keeping queue order within each session
+    let mut sessions: Vec<(String, Vec<QueuedScrobble>)> = Vec::new();
+    for entry in due {
+        match sessions.iter_mut().find(|(id, _)| *id == entry.session_id) {
+            Some((_, entries)) => entries.push(entry),
+            None => sessions.push((entry.session_id.clone(), vec![entry])),
+        }
+    }
+
+    let mut report = DrainReport::default();
+    for (session_id, entries) in sessions {
+        let mut stats = ScrobbleStats::default();
+        let mut dequeued = 0;
+
+        for chunk in pack_batches(entries) {
+            let batch: Vec<Scrobble> = chunk
+                .iter()
+                .flat_map(|e| e.scrobbles.iter().cloned())
+                .collect();
+            let size = batch.len() as u64;
+            report.batches += 1;
+
+            let (session_key, api_key_id) = (&chunk[0].session_key, chunk[0].api_key_id.as_deref());
+            match submitter.submit(session_key, api_key_id, &batch).await {
+                SubmitOutcome::Submitted { accepted, ignored } => {
+                    stats.accepted += accepted;
+                    stats.ignored += ignored;
+                    for entry in &chunk {
+                        store.remove(entry).await?;
+                    }
+                    dequeued += size;
+                }
+                SubmitOutcome::Retry(error) => {
+                    for mut entry in chunk {
+                        let count = entry.scrobbles.len() as u64;
+                        entry.attempts += 1;
+                        entry.last_error = Some(error.clone());
+                        if entry.attempts >= MAX_ATTEMPTS {
+                            stats.failed += count;
+                            dequeued += count;
+                            store.remove(&entry).await?;
+                        } else {
+                            entry.next_attempt_at = now + backoff_seconds(entry.attempts);
+                            report.retried += count;
+                            store.update(&entry).await?;
+                        }
+                    }
+                }
+                SubmitOutcome::Rejected(_) => {
+                    stats.failed += size;
+                    for entry in &chunk {
+                        store.remove(entry).await?;
+                    }
+                    dequeued += size;
+                }
+            }
+        }
+
+        report.accepted += stats.accepted;
+        report.ignored += stats.ignored;
+        report.failed += stats.failed;
+        store.record(&session_id, &stats, dequeued).await?;
+    }
+
+    Ok(report)
+}
+
+// Queue entries drained per scheduled run. Each is a KV read and delete plus
+// at most one track.scrobble call, which keeps a run well within the KV
+// operation and subrequest limits even when every entry is a full import batch.
+const MAX_ENTRIES_PER_RUN: usize = 50;
+
+/// Drain the KV-backed queue against Last.fm (called from the scheduled handler)
+pub async fn process_queue(env: &Env) {
+    let store = match KvScrobbleStore::new(env) {
+        Ok(store) => store,
+        Err(e) => {
+            console_error!("Scrobble queue unavailable: {:?}", e);
+            return;
+        }
+    };
+
+    let submitter = LastfmScrobbleSubmitter::new(env);
+    let now = chrono::Utc::now().timestamp();
+
+    match drain_queue(&store, &submitter, now, MAX_ENTRIES_PER_RUN).await {
+        Ok(report) => console_log!(
+            "Scrobble queue: {} batches, {} accepted, {} ignored, {} retried, {} failed",
+            report.batches,
+            report.accepted,
+            report.ignored,
+            report.retried,
+            report.failed
+        ),
+        Err(e) => console_error!("Failed to drain scrobble queue: {}", e),
+    }
+}
@@ -0,0 +1,314 @@
+// Storage backends for the scrobble queue
+
+use super::{QueuedScrobble, ScrobbleStats};
+use crate::vault::{is_proxy_token, open, seal};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use worker::{console_error, Env, KvStore};
+
+const PENDING_PREFIX: &str = "scrobble:pending:";
+const STATS_PREFIX: &str = "scrobble:stats:";
+
+// Last.fm rejects scrobbles older than 14 days, so there is no point keeping them longer
+const PENDING_TTL: u64 = 14 * 24 * 3600;
+
+// Pending keys listed per run while looking for due entries (one KV list
+// operation per 1000), so a large backlog in backoff can't hide due ones
+const MAX_LISTED_PENDING: usize = 5000;
+
+// Raw session keys are sealed at rest; proxy tokens are stored as they are
+const SEALED_PREFIX: &str = "sealed:";
+
+// Kept as KV metadata so due entries can be picked from a key listing
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingMetadata {
+    next_attempt_at: i64,
+}
+
+/// Persistence for queued scrobbles and per-session counters
+#[async_trait(?Send)]
+pub trait ScrobbleStore {
+    /// Add an entry to the queue. The same plays always map to the same
+    /// entry, so re-queuing them replaces that entry (resetting its retry
+    /// state) instead of adding a duplicate. Returns whether the entry is new.
+    async fn enqueue(&self, entry: &QueuedScrobble) -> Result<bool, String>;
+
+    /// Get up to `limit` entries due at `now`, oldest first within each
+    /// session. Entries still backing off never take the place of due ones.
+    async fn pending(&self, now: i64, limit: usize) -> Result<Vec<QueuedScrobble>, String>;
+
+    /// Persist retry state for a queued entry
+    async fn update(&self, entry: &QueuedScrobble) -> Result<(), String>;
+
+    /// Remove an entry from the queue
+    async fn remove(&self, entry: &QueuedScrobble) -> Result<(), String>;
+
+    /// Add to a session's counters; `dequeued` scrobbles have left the queue
+    async fn record(
+        &self,
+        session_id: &str,
+        delta: &ScrobbleStats,
+        dequeued: u64,
+    ) -> Result<(), String>;
+
+    /// Get a session's counters, including how many scrobbles are still queued
+    async fn stats(&self, session_id: &str) -> Result<ScrobbleStats, String>;
+}
+
+fn pending_key(entry: &QueuedScrobble) -> String {
+    format!("{PENDING_PREFIX}{}:{}", entry.session_id, entry.id)
+}
+
+// The queued count is kept alongside the other counters rather than counted
+// from a key listing, so it never goes below zero
+fn apply(stats: &mut ScrobbleStats, delta: &ScrobbleStats, dequeued: u64) {
+    stats.queued = (stats.queued + delta.queued).saturating_sub(dequeued);
+    stats.accepted += delta.accepted;
+    stats.ignored += delta.ignored;
+    stats.failed += delta.failed;
+}
+
+/// Queue stored in the CACHE KV namespace. Each entry is its own key, so
+/// concurrent submissions never overwrite each other.
+pub struct KvScrobbleStore {
+    kv: KvStore,
+    secret: String,
+}
+
+impl KvScrobbleStore {
+    /// Session keys are sealed with the SESSION_VAULT_KEY secret, which the
+    /// queue requires
+    pub fn new(env: &Env) -> Result<Self, worker::Error> {
+        let secret = env
+            .secret("SESSION_VAULT_KEY")
+            .map_err(|_| {
+                worker::Error::from("Scrobble queue needs SESSION_VAULT_KEY to seal session keys")
+            })?
+            .to_string();
+        Ok(Self {
+            kv: env.kv("CACHE")?,
+            secret,
+        })
+    }
+
+    // Serialized entry with a raw session key sealed
+    fn sealed_value(&self, entry: &QueuedScrobble) -> Result<String, String> {
+        let mut entry = entry.clone();
+        if !is_proxy_token(&entry.session_key) {
+            entry.session_key =
+                format!("{SEALED_PREFIX}{}", seal(&self.secret, &entry.session_key)?);
+        }
+        serde_json::to_string(&entry).map_err(|e| e.to_string())
+    }
+
+    fn opened_entry(&self, value: &str) -> Result<QueuedScrobble, String> {
+        let mut entry: QueuedScrobble = serde_json::from_str(value).map_err(|e| e.to_string())?;
+        if let Some(sealed) = entry.session_key.strip_prefix(SEALED_PREFIX) {
+            entry.session_key = open(&self.secret, sealed)?;
+        }
+        Ok(entry)
+    }
+
+    async fn put_pending(&self, entry: &QueuedScrobble) -> Result<(), String> {
+        let value = self.sealed_value(entry)?;
+        let metadata = PendingMetadata {
+            next_attempt_at: entry.next_attempt_at,
+        };
+        self.kv
+            .put(&pending_key(entry), value)
+            .map_err(|e| format!("{e:?}"))?
+            .expiration_ttl(PENDING_TTL)
+            .metadata(metadata)
+            .map_err(|e| format!("{e:?}"))?
+            .execute()
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    // Pending keys due at `now`, judged by their metadata; keys written
+    // without it are treated as due and checked once their value is read
+    async fn due_keys(&self, now: i64, limit: usize) -> Result<Vec<String>, String> {
+        let mut due = Vec::new();
+        let mut listed = 0;
+        let mut cursor = None;
+
+        loop {
+            let mut list = self.kv.list().prefix(PENDING_PREFIX.to_string());
+            if let Some(cursor) = cursor {
+                list = list.cursor(cursor);
+            }
+
+            let page = list.execute().await.map_err(|e| format!("{e:?}"))?;
+            listed += page.keys.len();
+            for key in page.keys {
+                let next_attempt_at = key
+                    .metadata
+                    .and_then(|m| serde_json::from_value::<PendingMetadata>(m).ok())
+                    .map_or(0, |m| m.next_attempt_at);
+                if next_attempt_at <= now {
+                    due.push(key.name);
+                }
+            }
+
+            if due.len() >= limit || listed >= MAX_LISTED_PENDING || page.list_complete {
+                break;
+            }
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        due.truncate(limit);
+        Ok(due)
+    }
+}
+
+#[async_trait(?Send)]
+impl ScrobbleStore for KvScrobbleStore {
+    async fn enqueue(&self, entry: &QueuedScrobble) -> Result<bool, String> {
+        let existing = self
+            .kv
+            .get(&pending_key(entry))
+            .text()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        self.put_pending(entry).await?;
+        Ok(existing.is_none())
+    }
+
+    async fn pending(&self, now: i64, limit: usize) -> Result<Vec<QueuedScrobble>, String> {
+        let mut entries = Vec::new();
+        for key in self.due_keys(now, limit).await? {
+            let value = self
+                .kv
+                .get(&key)
+                .text()
+                .await
+                .map_err(|e| format!("{e:?}"))?;
+            // Skip entries that expired or were removed since listing
+            let Some(value) = value else {
+                continue;
+            };
+            match self.opened_entry(&value) {
+                Ok(entry) => entries.push(entry),
+                // Left to expire, e.g. after the sealing secret changed
+                Err(e) => console_error!("Skipping unreadable queued scrobble {}: {}", key, e),
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn update(&self, entry: &QueuedScrobble) -> Result<(), String> {
+        self.put_pending(entry).await
+    }
+
+    async fn remove(&self, entry: &QueuedScrobble) -> Result<(), String> {
+        self.kv
+            .delete(&pending_key(entry))
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn record(
+        &self,
+        session_id: &str,
+        delta: &ScrobbleStats,
+        dequeued: u64,
+    ) -> Result<(), String> {
+        let mut stats = self.stats(session_id).await?;
+        apply(&mut stats, delta, dequeued);
+
+        let value = serde_json::to_string(&stats).map_err(|e| e.to_string())?;
+        self.kv
+            .put(&format!("{STATS_PREFIX}{session_id}"), value)
+            .map_err(|e| format!("{e:?}"))?
+            .execute()
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn stats(&self, session_id: &str) -> Result<ScrobbleStats, String> {
+        Ok(self
+            .kv
+            .get(&format!("{STATS_PREFIX}{session_id}"))
+            .text()
+            .await
+            .map_err(|e| format!("{e:?}"))?
+            .and_then(|v| serde_json::from_str(&v).ok())
+            .unwrap_or_default())
+    }
+}
+
+/// In-memory queue for tests and local development
+#[derive(Default)]
+pub struct MemoryScrobbleStore {
+    pending: RefCell<BTreeMap<String, QueuedScrobble>>,
+    stats: RefCell<HashMap<String, ScrobbleStats>>,
+}
+
+impl MemoryScrobbleStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait(?Send)]
+impl ScrobbleStore for MemoryScrobbleStore {
+    async fn enqueue(&self, entry: &QueuedScrobble) -> Result<bool, String> {
+        Ok(self
+            .pending
+            .borrow_mut()
+            .insert(pending_key(entry), entry.clone())
+            .is_none())
+    }
+
+    async fn pending(&self, now: i64, limit: usize) -> Result<Vec<QueuedScrobble>, String> {
+        Ok(self
+            .pending
+            .borrow()
+            .values()
+            .filter(|entry| entry.next_attempt_at <= now)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn update(&self, entry: &QueuedScrobble) -> Result<(), String> {
+        self.pending
+            .borrow_mut()
+            .insert(pending_key(entry), entry.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, entry: &QueuedScrobble) -> Result<(), String> {
+        self.pending.borrow_mut().remove(&pending_key(entry));
+        Ok(())
+    }
+
+    async fn record(
+        &self,
+        session_id: &str,
+        delta: &ScrobbleStats,
+        dequeued: u64,
+    ) -> Result<(), String> {
+        let mut stats = self.stats.borrow_mut();
+        apply(
+            stats.entry(session_id.to_string()).or_default(),
+            delta,
+            dequeued,
+        );
+        Ok(())
+    }
+
+    async fn stats(&self, session_id: &str) -> Result<ScrobbleStats, String> {
+        Ok(self
+            .stats
+            .borrow()
+            .get(session_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+}
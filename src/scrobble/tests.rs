@@ -0,0 +1,362 @@
+// Unit tests for the scrobble queue
+
+#[cfg(test)]
+mod scrobble_tests {
+    use super::super::*;
+    use futures::executor::block_on;
+    use std::cell::RefCell;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn submission(count: usize) -> ScrobbleSubmission {
+        submission_for("session", count)
+    }
+
+    fn submission_for(session_key: &str, count: usize) -> ScrobbleSubmission {
+        ScrobbleSubmission {
+            session_key: session_key.to_string(),
+            api_key_id: None,
+            scrobbles: (0..count)
+                .map(|i| Scrobble {
+                    artist: "Radiohead".to_string(),
+                    track: format!("Track {i}"),
+                    timestamp: 1_700_000_000 + i as i64,
+                    album: None,
+                    album_artist: None,
+                    track_number: None,
+                    duration: None,
+                    mbid: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Submitter that replays scripted outcomes and records batch sizes
+    /// and the API key each batch was to be signed with
+    struct ScriptedSubmitter {
+        outcomes: RefCell<Vec<SubmitOutcome>>,
+        batches: RefCell<Vec<usize>>,
+        key_ids: RefCell<Vec<Option<String>>>,
+    }
+
+    impl ScriptedSubmitter {
+        fn new(outcomes: Vec<SubmitOutcome>) -> Self {
+            Self {
+                outcomes: RefCell::new(outcomes),
+                batches: RefCell::new(Vec::new()),
+                key_ids: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl ScrobbleSubmitter for ScriptedSubmitter {
+        async fn submit(
+            &self,
+            _session_key: &str,
+            api_key_id: Option<&str>,
+            batch: &[Scrobble],
+        ) -> SubmitOutcome {
+            self.batches.borrow_mut().push(batch.len());
+            self.key_ids
+                .borrow_mut()
+                .push(api_key_id.map(str::to_string));
+            self.outcomes.borrow_mut().remove(0)
+        }
+    }
+
+    #[test]
+    fn test_parse_single_submission() {
+        let submission = ScrobbleSubmission::from_params(&params(&[
+            ("sk", "abc"),
+            ("artist", "Radiohead"),
+            ("track", "Creep"),
+            ("timestamp", "1700000000"),
+            ("album", "Pablo Honey"),
+        ]))
+        .unwrap();
+
+        assert_eq!(submission.session_key, "abc");
+        assert_eq!(submission.api_key_id, None);
+        assert_eq!(submission.scrobbles.len(), 1);
+        assert_eq!(
+            submission.scrobbles[0].album.as_deref(),
+            Some("Pablo Honey")
+        );
+    }
+
+    #[test]
+    fn test_parse_indexed_submission() {
+        let submission = ScrobbleSubmission::from_params(&params(&[
+            ("sk", "abc"),
+            ("artist[0]", "Radiohead"),
+            ("track[0]", "Creep"),
+            ("timestamp[0]", "1700000000"),
+            ("artist[1]", "Muse"),
+            ("track[1]", "Uprising"),
+            ("timestamp[1]", "1700000300"),
+        ]))
+        .unwrap();
+
+        assert_eq!(submission.scrobbles.len(), 2);
+        assert_eq!(submission.scrobbles[1].artist, "Muse");
+    }
+
+    #[test]
+    fn test_parse_binds_session_to_api_key() {
+        let scrobble = [("artist", "A"), ("track", "T"), ("timestamp", "1")];
+        let with = |extra: (&str, &str)| {
+            let mut pairs = vec![("sk", "abc"), extra];
+            pairs.extend(scrobble);
+            ScrobbleSubmission::from_params(&params(&pairs)).unwrap()
+        };
+
+        assert_eq!(
+            with(("key_id", "0a1b2c")).api_key_id.as_deref(),
+            Some("0a1b2c")
+        );
+        assert_eq!(
+            with(("api_key", "pooled")).api_key_id,
+            Some(crate::key_pool::key_id("pooled"))
+        );
+    }
+
+    #[test]
+    fn test_drain_signs_with_the_queued_key() {
+        let store = MemoryScrobbleStore::new();
+        let mut bound = submission_for("bound", 1);
+        bound.api_key_id = Some("0a1b2c".to_string());
+        block_on(enqueue(&store, bound, 0)).unwrap();
+
+        let submitter = ScriptedSubmitter::new(vec![SubmitOutcome::Submitted {
+            accepted: 1,
+            ignored: 0,
+        }]);
+        block_on(drain_queue(&store, &submitter, 0, 10)).unwrap();
+        assert_eq!(
+            *submitter.key_ids.borrow(),
+            vec![Some("0a1b2c".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_submissions() {
+        let missing_sk = params(&[("artist", "A"), ("track", "T"), ("timestamp", "1")]);
+        assert!(ScrobbleSubmission::from_params(&missing_sk).is_err());
+
+        let bad_timestamp = params(&[
+            ("sk", "abc"),
+            ("artist", "A"),
+            ("track", "T"),
+            ("timestamp", "yesterday"),
+        ]);
+        assert!(ScrobbleSubmission::from_params(&bad_timestamp).is_err());
+
+        let missing_track = params(&[("sk", "abc"), ("artist[0]", "A"), ("timestamp[0]", "1")]);
+        assert!(ScrobbleSubmission::from_params(&missing_track).is_err());
+    }
+
+    #[test]
+    fn test_batch_params_are_indexed() {
+        let batch = submission(2).scrobbles;
+        let params = batch_params("session", &batch);
+
+        assert_eq!(params.get("sk").unwrap(), "session");
+        assert_eq!(params.get("track[1]").unwrap(), "Track 1");
+        assert_eq!(params.get("timestamp[0]").unwrap(), "1700000000");
+        assert!(!params.contains_key("album[0]"));
+    }
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        assert_eq!(backoff_seconds(1), 60);
+        assert_eq!(backoff_seconds(2), 120);
+        assert_eq!(backoff_seconds(4), 480);
+        assert_eq!(backoff_seconds(30), 6 * 3600);
+    }
+
+    #[test]
+    fn test_parse_submit_response() {
+        let ok = r#"{"scrobbles":{"@attr":{"accepted":2,"ignored":1},"scrobble":[]}}"#;
+        assert_eq!(
+            parse_submit_response(ok),
+            SubmitOutcome::Submitted {
+                accepted: 2,
+                ignored: 1
+            }
+        );
+
+        let offline = r#"{"error":11,"message":"Service Offline"}"#;
+        assert!(matches!(
+            parse_submit_response(offline),
+            SubmitOutcome::Retry(_)
+        ));
+
+        let bad_session = r#"{"error":9,"message":"Invalid session key"}"#;
+        assert!(matches!(
+            parse_submit_response(bad_session),
+            SubmitOutcome::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn test_enqueue_is_idempotent_per_play() {
+        let store = MemoryScrobbleStore::new();
+        block_on(enqueue(&store, submission(3), 100)).unwrap();
+        block_on(enqueue(&store, submission(3), 200)).unwrap();
+
+        let stats = block_on(store.stats(&session_id("session"))).unwrap();
+        assert_eq!(stats.queued, 3);
+    }
+
+    #[test]
+    fn test_drain_submits_in_batches_of_fifty() {
+        let store = MemoryScrobbleStore::new();
+        block_on(enqueue(&store, submission(120), 100)).unwrap();
+
+        let submitter = ScriptedSubmitter::new(vec![
+            SubmitOutcome::Submitted {
+                accepted: 50,
+                ignored: 0,
+            },
+            SubmitOutcome::Submitted {
+                accepted: 49,
+                ignored: 1,
+            },
+            SubmitOutcome::Submitted {
+                accepted: 20,
+                ignored: 0,
+            },
+        ]);
+
+        let report = block_on(drain_queue(&store, &submitter, 100, 500)).unwrap();
+        assert_eq!(*submitter.batches.borrow(), vec![50, 50, 20]);
+        assert_eq!(report.accepted, 119);
+        assert_eq!(report.ignored, 1);
+
+        let stats = block_on(store.stats(&session_id("session"))).unwrap();
+        assert_eq!(
+            stats,
+            ScrobbleStats {
+                queued: 0,
+                accepted: 119,
+                ignored: 1,
+                failed: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_drain_retries_with_backoff_then_gives_up() {
+        let store = MemoryScrobbleStore::new();
+        block_on(enqueue(&store, submission(1), 0)).unwrap();
+
+        // First failure reschedules one minute later
+        let submitter = ScriptedSubmitter::new(vec![SubmitOutcome::Retry("offline".into())]);
+        let report = block_on(drain_queue(&store, &submitter, 0, 500)).unwrap();
+        assert_eq!(report.retried, 1);
+
+        assert!(block_on(store.pending(59, 10)).unwrap().is_empty());
+        let entry = block_on(store.pending(60, 10)).unwrap().remove(0);
+        assert_eq!(entry.attempts, 1);
+        assert_eq!(entry.next_attempt_at, 60);
+
+        // Not due yet: nothing is submitted
+        let idle = ScriptedSubmitter::new(vec![]);
+        let report = block_on(drain_queue(&store, &idle, 30, 500)).unwrap();
+        assert_eq!(report.batches, 0);
+
+        // Keep failing until MAX_ATTEMPTS is reached
+        let mut now = 60;
+        for _ in 1..MAX_ATTEMPTS {
+            let submitter = ScriptedSubmitter::new(vec![SubmitOutcome::Retry("offline".into())]);
+            block_on(drain_queue(&store, &submitter, now, 500)).unwrap();
+            now += MAX_BACKOFF_SECS;
+        }
+
+        let stats = block_on(store.stats(&session_id("session"))).unwrap();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.failed, 1);
+    }
+
+    #[test]
+    fn test_backed_off_session_does_not_block_due_ones() {
+        // The backed-off session must come first in key order to fill the page
+        let (mut busy, mut idle) = ("busy-session", "idle-session");
+        if session_id(busy) > session_id(idle) {
+            std::mem::swap(&mut busy, &mut idle);
+        }
+
+        let store = MemoryScrobbleStore::new();
+        block_on(enqueue(&store, submission_for(busy, 250), 0)).unwrap();
+        let failing = ScriptedSubmitter::new(vec![SubmitOutcome::Retry("offline".into()); 5]);
+        let report = block_on(drain_queue(&store, &failing, 0, 500)).unwrap();
+        assert_eq!(report.retried, 250);
+
+        // 5 entries in backoff sort before the due one and exceed the cap
+        block_on(enqueue(&store, submission_for(idle, 1), 10)).unwrap();
+        let submitter = ScriptedSubmitter::new(vec![SubmitOutcome::Submitted {
+            accepted: 1,
+            ignored: 0,
+        }]);
+        let report = block_on(drain_queue(&store, &submitter, 10, 4)).unwrap();
+        assert_eq!(*submitter.batches.borrow(), vec![1]);
+        assert_eq!(report.accepted, 1);
+        assert_eq!(
+            block_on(store.stats(&session_id(busy))).unwrap().queued,
+            250
+        );
+    }
+
+    #[test]
+    fn test_import_is_queued_per_batch() {
+        let store = MemoryScrobbleStore::new();
+        let queued = block_on(enqueue(&store, submission(1000), 0)).unwrap();
+        assert_eq!(queued, 1000);
+
+        let entries = block_on(store.pending(0, 100)).unwrap();
+        assert_eq!(entries.len(), 20);
+        assert!(entries.iter().all(|e| e.scrobbles.len() == MAX_BATCH_SIZE));
+        assert_eq!(
+            block_on(store.stats(&session_id("session")))
+                .unwrap()
+                .queued,
+            1000
+        );
+    }
+
+    #[test]
+    fn test_drain_packs_single_plays_into_batches() {
+        let store = MemoryScrobbleStore::new();
+        for scrobble in submission(60).scrobbles {
+            let single = ScrobbleSubmission {
+                session_key: "session".to_string(),
+                api_key_id: None,
+                scrobbles: vec![scrobble],
+            };
+            block_on(enqueue(&store, single, 0)).unwrap();
+        }
+
+        let submitter = ScriptedSubmitter::new(vec![
+            SubmitOutcome::Submitted {
+                accepted: 50,
+                ignored: 0,
+            },
+            SubmitOutcome::Retry("offline".into()),
+        ]);
+        let report = block_on(drain_queue(&store, &submitter, 0, 100)).unwrap();
+        assert_eq!(*submitter.batches.borrow(), vec![50, 10]);
+        assert_eq!(report.retried, 10);
+        assert_eq!(
+            block_on(store.stats(&session_id("session")))
+                .unwrap()
+                .queued,
+            10
+        );
+    }
+}
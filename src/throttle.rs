@@ -0,0 +1,414 @@
+// Global upstream throttle: keeps the worker as a whole within Last.fm's
+// request rate policy for each API key, across requests and isolates. Each
+// key's sliding window lives in its own Durable Object, which hands out slots
+// one at a time.
+
+use crate::error::{ApiError, ApiResult};
+use crate::key_pool::ApiCredentials;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use worker::{
+    console_error, console_log, durable_object, Env, Method, Request, RequestInit, Response, State,
+};
+
+/// Last.fm asks for no more than 5 requests per second per key
+pub const DEFAULT_REQUESTS_PER_SECOND: u32 = 5;
+
+/// Seconds clients are told to wait when the budget is used up
+pub const RETRY_AFTER_SECS: u64 = 3;
+
+/// Durable Object namespace holding one window per upstream key
+pub const THROTTLE_BINDING: &str = "UPSTREAM_THROTTLE";
+
+// Requests wait at most this long for a slot before giving up
+const MAX_QUEUE_WAIT_MS: i64 = 2_000;
+
+const WINDOW_MS: i64 = 1_000;
+
+/// Budget for one upstream key
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+    pub requests_per_second: u32,
+    pub max_wait_ms: i64,
+}
+
+impl ThrottleConfig {
+    pub fn per_second(requests_per_second: u32) -> Self {
+        Self {
+            requests_per_second,
+            max_wait_ms: MAX_QUEUE_WAIT_MS,
+        }
+    }
+}
+
+/// Outcome of asking for an upstream request slot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ThrottleDecision {
+    /// The slot is reserved; go ahead once `wait_ms` have passed
+    Admitted { wait_ms: i64 },
+    /// Budget exhausted and the next free slot is too far away
+    Throttled { retry_after_ms: i64 },
+}
+
+/// Sliding one-second window for one upstream key: no second ever holds more
+/// than `requests_per_second` slots. Slots are reserved in arrival order, so
+/// a caller told to wait owns its slot and only has to sleep.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SlotWindow {
+    // Start times of the most recent slots, oldest first
+    slots: VecDeque<i64>,
+}
+
+impl SlotWindow {
+    pub fn reserve(&mut self, config: &ThrottleConfig, now_ms: i64) -> ThrottleDecision {
+        let limit = config.requests_per_second.max(1) as usize;
+        let slot = match self.slots.len() {
+            len if len < limit => now_ms,
+            len => now_ms.max(self.slots[len - limit] + WINDOW_MS),
+        };
+
+        let wait_ms = slot - now_ms;
+        if wait_ms > config.max_wait_ms {
+            return ThrottleDecision::Throttled {
+                retry_after_ms: wait_ms,
+            };
+        }
+
+        self.slots.push_back(slot);
+        while self.slots.len() > limit {
+            self.slots.pop_front();
+        }
+        ThrottleDecision::Admitted { wait_ms }
+    }
+}
+
+/// Time source, so the throttle can run against a simulated clock in tests
+#[async_trait(?Send)]
+pub trait Clock {
+    fn now_ms(&self) -> i64;
+    async fn sleep(&self, ms: i64);
+}
+
+/// Wall clock backed by the Workers timer
+pub struct SystemClock;
+
+#[async_trait(?Send)]
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+
+    async fn sleep(&self, ms: i64) {
+        worker::Delay::from(std::time::Duration::from_millis(ms.max(0) as u64)).await;
+    }
+}
+
+/// Windows shared by every request for the same key
+#[async_trait(?Send)]
+pub trait ThrottleStore {
+    async fn reserve(
+        &self,
+        key_id: &str,
+        config: &ThrottleConfig,
+    ) -> Result<ThrottleDecision, String>;
+}
+
+/// Windows in the UPSTREAM_THROTTLE Durable Object namespace, one object per key
+pub struct DurableThrottleStore<'a> {
+    env: &'a Env,
+}
+
+impl<'a> DurableThrottleStore<'a> {
+    pub fn new(env: &'a Env) -> Self {
+        Self { env }
+    }
+}
+
+#[async_trait(?Send)]
+impl ThrottleStore for DurableThrottleStore<'_> {
+    async fn reserve(
+        &self,
+        key_id: &str,
+        config: &ThrottleConfig,
+    ) -> Result<ThrottleDecision, String> {
+        let namespace = self
+            .env
+            .durable_object(THROTTLE_BINDING)
+            .map_err(|e| format!("{e:?}"))?;
+        let stub = namespace
+            .id_from_name(key_id)
+            .and_then(|id| id.get_stub())
+            .map_err(|e| format!("{e:?}"))?;
+
+        let body = serde_json::to_string(config).map_err(|e| e.to_string())?;
+        let request = Request::new_with_init(
+            "https://upstream-throttle/reserve",
+            RequestInit::new()
+                .with_method(Method::Post)
+                .with_body(Some(body.into())),
+        )
+        .map_err(|e| format!("{e:?}"))?;
+
+        let mut response = stub
+            .fetch_with_request(request)
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        response.json().await.map_err(|e| format!("{e:?}"))
+    }
+}
+
+/// Durable Object holding the window for one upstream key. The window is
+/// only kept in memory: an object is evicted after it has been idle, by which
+/// time every slot in it is long past.
+#[durable_object(fetch)]
+pub struct UpstreamThrottle {
+    window: RefCell<SlotWindow>,
+}
+
+impl DurableObject for UpstreamThrottle {
+    fn new(_state: State, _env: Env) -> Self {
+        Self {
+            window: RefCell::new(SlotWindow::default()),
+        }
+    }
+
+    async fn fetch(&self, mut req: Request) -> worker::Result<Response> {
+        let config: ThrottleConfig = req.json().await?;
+        let decision = self
+            .window
+            .borrow_mut()
+            .reserve(&config, SystemClock.now_ms());
+        Response::from_json(&decision)
+    }
+}
+
+/// Take a request slot for `key_id`, waiting briefly when the current second
+/// is full. Store errors fail open.
+pub async fn acquire(
+    store: &dyn ThrottleStore,
+    clock: &dyn Clock,
+    key_id: &str,
+    config: ThrottleConfig,
+) -> ThrottleDecision {
+    match store.reserve(key_id, &config).await {
+        Ok(ThrottleDecision::Admitted { wait_ms }) => {
+            if wait_ms > 0 {
+                clock.sleep(wait_ms).await;
+            }
+            ThrottleDecision::Admitted { wait_ms }
+        }
+        Ok(throttled) => throttled,
+        Err(e) => {
+            console_error!("Upstream throttle unavailable: {}", e);
+            ThrottleDecision::Admitted { wait_ms: 0 }
+        }
+    }
+}
+
+fn config_from_env(env: &Env) -> ThrottleConfig {
+    let requests_per_second = env
+        .var("UPSTREAM_REQUESTS_PER_SECOND")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .filter(|&rps| rps > 0)
+        .unwrap_or(DEFAULT_REQUESTS_PER_SECOND);
+
+    ThrottleConfig::per_second(requests_per_second)
+}
+
+/// Wait for an upstream slot for these credentials, or fail with
+/// `ApiError::upstream_throttled` when the key's budget is used up
+pub async fn throttle_upstream(env: &Env, credentials: &ApiCredentials) -> ApiResult<()> {
+    let key_id = credentials.id();
+    let config = config_from_env(env);
+    let store = DurableThrottleStore::new(env);
+    match acquire(&store, &SystemClock, &key_id, config).await {
+        ThrottleDecision::Admitted { wait_ms } => {
+            if wait_ms > 0 {
+                console_log!("Upstream key {} queued for {}ms", key_id, wait_ms);
+            }
+            Ok(())
+        }
+        ThrottleDecision::Throttled { retry_after_ms } => {
+            console_log!(
+                "Upstream key {} throttled, next slot in {}ms",
+                key_id,
+                retry_after_ms
+            );
+            Err(ApiError::upstream_throttled())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+
+    /// Clock that only moves when something sleeps or time is advanced
+    struct SimulatedClock {
+        now: Cell<i64>,
+    }
+
+    impl SimulatedClock {
+        fn at(now: i64) -> Self {
+            Self {
+                now: Cell::new(now),
+            }
+        }
+
+        fn advance(&self, ms: i64) {
+            self.now.set(self.now.get() + ms);
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Clock for SimulatedClock {
+        fn now_ms(&self) -> i64 {
+            self.now.get()
+        }
+
+        async fn sleep(&self, ms: i64) {
+            self.advance(ms);
+        }
+    }
+
+    /// In-memory windows on a simulated clock, standing in for the Durable Object
+    struct MemoryThrottleStore<'a> {
+        clock: &'a SimulatedClock,
+        windows: RefCell<HashMap<String, SlotWindow>>,
+    }
+
+    impl<'a> MemoryThrottleStore<'a> {
+        fn new(clock: &'a SimulatedClock) -> Self {
+            Self {
+                clock,
+                windows: RefCell::new(HashMap::new()),
+            }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl ThrottleStore for MemoryThrottleStore<'_> {
+        async fn reserve(
+            &self,
+            key_id: &str,
+            config: &ThrottleConfig,
+        ) -> Result<ThrottleDecision, String> {
+            Ok(self
+                .windows
+                .borrow_mut()
+                .entry(key_id.to_string())
+                .or_default()
+                .reserve(config, self.clock.now_ms()))
+        }
+    }
+
+    fn config(requests_per_second: u32) -> ThrottleConfig {
+        ThrottleConfig {
+            requests_per_second,
+            max_wait_ms: 2_000,
+        }
+    }
+
+    #[test]
+    fn test_burst_is_capped_at_the_per_second_rate() {
+        let clock = SimulatedClock::at(1_000);
+        let store = MemoryThrottleStore::new(&clock);
+
+        for _ in 0..5 {
+            let decision = block_on(acquire(&store, &clock, "k", config(5)));
+            assert_eq!(decision, ThrottleDecision::Admitted { wait_ms: 0 });
+        }
+
+        // The sixth call waits until the first slot is a second old
+        clock.advance(300);
+        let decision = block_on(acquire(&store, &clock, "k", config(5)));
+        assert_eq!(decision, ThrottleDecision::Admitted { wait_ms: 700 });
+        assert_eq!(clock.now_ms(), 2_000);
+    }
+
+    #[test]
+    fn test_throttles_when_the_wait_is_too_long() {
+        let clock = SimulatedClock::at(0);
+        let store = MemoryThrottleStore::new(&clock);
+        let config = ThrottleConfig {
+            max_wait_ms: 500,
+            ..config(2)
+        };
+
+        // Two slots now, and the next pair is a second away
+        for _ in 0..2 {
+            block_on(store.reserve("k", &config)).unwrap();
+        }
+        let decision = block_on(acquire(&store, &clock, "k", config));
+        assert_eq!(
+            decision,
+            ThrottleDecision::Throttled {
+                retry_after_ms: 1_000
+            }
+        );
+        assert_eq!(clock.now_ms(), 0);
+    }
+
+    #[test]
+    fn test_never_exceeds_the_rate_over_time() {
+        let clock = SimulatedClock::at(0);
+        let store = MemoryThrottleStore::new(&clock);
+
+        // 50 callers arriving at once go out at 5 per second
+        let mut sent_at = Vec::new();
+        for _ in 0..50 {
+            if let Ok(ThrottleDecision::Admitted { wait_ms }) =
+                block_on(store.reserve("k", &config(5)))
+            {
+                sent_at.push(wait_ms);
+            }
+        }
+
+        assert_eq!(sent_at.len(), 15);
+        for window in sent_at.windows(6) {
+            assert!(window[5] - window[0] >= 1_000);
+        }
+    }
+
+    #[test]
+    fn test_idle_keys_get_a_full_second_again() {
+        let clock = SimulatedClock::at(0);
+        let store = MemoryThrottleStore::new(&clock);
+
+        for _ in 0..5 {
+            block_on(acquire(&store, &clock, "k", config(5)));
+        }
+        clock.advance(60_000);
+
+        let decisions: Vec<ThrottleDecision> = (0..6)
+            .map(|_| block_on(store.reserve("k", &config(5))).unwrap())
+            .collect();
+        assert!(decisions[..5]
+            .iter()
+            .all(|d| *d == ThrottleDecision::Admitted { wait_ms: 0 }));
+        assert_eq!(decisions[5], ThrottleDecision::Admitted { wait_ms: 1_000 });
+    }
+
+    #[test]
+    fn test_budgets_are_per_key() {
+        let clock = SimulatedClock::at(0);
+        let store = MemoryThrottleStore::new(&clock);
+
+        block_on(acquire(&store, &clock, "a", config(1)));
+        let decision = block_on(acquire(&store, &clock, "b", config(1)));
+        assert_eq!(decision, ThrottleDecision::Admitted { wait_ms: 0 });
+    }
+
+    #[test]
+    fn test_per_second_config() {
+        let config = ThrottleConfig::per_second(5);
+        assert_eq!(config.requests_per_second, 5);
+        assert_eq!(config.max_wait_ms, 2_000);
+    }
+}
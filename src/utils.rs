@@ -1,4 +1,9 @@
+use crate::cache_codec;
+use crate::config::Config;
 use crate::error::{ApiError, ApiResult};
+use crate::key_pool::{self, ApiCredentials};
+use crate::throttle;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use worker::{console_error, console_log, Env, Error, Headers, Request, Response, Url};
 
@@ -26,6 +31,22 @@ pub fn parse_query_params(req: &Request) -> Result<HashMap<String, String>, Erro
     Ok(params)
 }
 
+// Parse form or JSON body parameters from a POST request
+pub async fn parse_body_params(req: &mut Request) -> ApiResult<HashMap<String, String>> {
+    let content_type = req
+        .headers()
+        .get("Content-Type")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    let body = req.text().await.map_err(|_| ApiError::temporary_error())?;
+
+    crate::common::url::parse_body_params(&content_type, &body)
+        .map_err(ApiError::invalid_parameters)
+}
+
 // Build Last.fm API URL
 pub fn build_lastfm_url(
     base_url: &str,
@@ -36,34 +57,99 @@ pub fn build_lastfm_url(
     crate::common::url::build_lastfm_url(base_url, method, params, api_key)
 }
 
+// Get the Last.fm API base URL from vars
+fn lastfm_base_url(env: &Env) -> String {
+    match env.var("LASTFM_API_BASE_URL") {
+        Ok(url) => url.to_string(),
+        Err(_) => "https://ws.audioscrobbler.com/2.0/".to_string(), // Default Last.fm API URL
+    }
+}
+
+// Header a caller uses to share its own Last.fm secret so the proxy can sign for it
+pub const CLIENT_SECRET_HEADER: &str = "X-Lastfm-Api-Secret";
+
+// Pick pool credentials, keeping an api_key the caller already signed with
+// (or, with ALLOW_CLIENT_API_KEYS, the caller's own key)
+async fn lastfm_credentials(
+    env: &Env,
+    params: &HashMap<String, String>,
+) -> ApiResult<ApiCredentials> {
+    let allow_client_keys = Config::from_env(env).allow_client_api_keys;
+    key_pool::request_credentials(
+        env,
+        params.get("api_key").map(String::as_str),
+        allow_client_keys,
+    )
+    .await
+}
+
+// The caller's own api_key and secret for signing, when client keys are allowed
+pub fn client_signing_credentials(
+    req: &Request,
+    env: &Env,
+    params: &HashMap<String, String>,
+) -> Option<ApiCredentials> {
+    if !Config::from_env(env).allow_client_api_keys {
+        return None;
+    }
+    let api_key = params.get("api_key").filter(|key| !key.is_empty())?;
+    let secret = req
+        .headers()
+        .get(CLIENT_SECRET_HEADER)
+        .ok()
+        .flatten()
+        .filter(|secret| !secret.is_empty())?;
+
+    Some(ApiCredentials::client(api_key, Some(&secret)))
+}
+
+// Send a prepared request to Last.fm API, cooling down the key on error 26 or 29
+async fn send_to_lastfm(
+    env: &Env,
+    request: Request,
+    credentials: &ApiCredentials,
+) -> ApiResult<Response> {
+    let mut response = worker::Fetch::Request(request).send().await.map_err(|e| {
+        console_error!("Failed to fetch from Last.fm: {}", e);
+        ApiError::service_offline()
+    })?;
+
+    // Read the body once, then hand callers a fresh response over the same bytes
+    let status = response.status_code();
+    let headers = response.headers().clone();
+    let body = response.bytes().await.map_err(|e| {
+        console_error!("Failed to read Last.fm response: {}", e);
+        ApiError::service_offline()
+    })?;
+
+    if let Some(error) = std::str::from_utf8(&body).ok().and_then(parse_lastfm_error) {
+        key_pool::report_error(env, credentials, error.error).await;
+    }
+
+    Ok(Response::from_bytes(body)?
+        .with_status(status)
+        .with_headers(headers))
+}
+
 // Make request to Last.fm API
 pub async fn proxy_to_lastfm(
     env: &Env,
     method: &str,
     params: HashMap<String, String>,
 ) -> ApiResult<Response> {
-    console_log!("Getting API key from secrets...");
-    let api_key = match env.secret("LASTFM_API_KEY") {
-        Ok(key) => {
-            console_log!("API key retrieved successfully");
-            // Phase alignment check
-            key.to_string()
-        }
-        Err(e) => {
-            console_error!("Failed to get LASTFM_API_KEY: {:?}", e);
-            return Err(ApiError::temporary_error());
-        }
-    };
-
-    let base_url = match env.var("LASTFM_API_BASE_URL") {
-        Ok(url) => url.to_string(),
-        Err(_) => "https://ws.audioscrobbler.com/2.0/".to_string(), // Default Last.fm API URL
-    };
+    let credentials = lastfm_credentials(env, &params).await?;
+    throttle::throttle_upstream(env, &credentials).await?;
+    let base_url = lastfm_base_url(env);
 
-    let url = build_lastfm_url(&base_url, method, &params, &api_key)
+    let url = build_lastfm_url(&base_url, method, &params, &credentials.api_key)
         .map_err(|_| ApiError::temporary_error())?;
 
-    console_log!("Proxying request to: {}", url.to_string());
+    // The query string carries the api_key and user parameters
+    if Config::from_env(env).redact_logs {
+        console_log!("Proxying request to: {} (method={})", base_url, method);
+    } else {
+        console_log!("Proxying request to: {}", url.to_string());
+    }
 
     let headers = Headers::new();
     headers.set("User-Agent", "lastfm-proxy-worker/1.0")?;
@@ -76,41 +162,166 @@ pub async fn proxy_to_lastfm(
     )
     .map_err(|_| ApiError::temporary_error())?;
 
-    let response = worker::Fetch::Request(request).send().await.map_err(|e| {
-        console_error!("Failed to fetch from Last.fm: {}", e);
-        ApiError::service_offline()
-    })?;
+    send_to_lastfm(env, request, &credentials).await
+}
 
-    Ok(response)
+// Make POST request to Last.fm API with parameters in a form body
+// (keeps credentials out of URLs and upstream access logs)
+pub async fn proxy_post_to_lastfm(
+    env: &Env,
+    method: &str,
+    params: HashMap<String, String>,
+) -> ApiResult<Response> {
+    let credentials = lastfm_credentials(env, &params).await?;
+    throttle::throttle_upstream(env, &credentials).await?;
+    let base_url = lastfm_base_url(env);
+
+    let body = crate::common::url::build_lastfm_form_body(method, &params, &credentials.api_key);
+
+    console_log!("Proxying POST request to: {} (method={})", base_url, method);
+
+    let headers = Headers::new();
+    headers.set("User-Agent", "lastfm-proxy-worker/1.0")?;
+    headers.set("Content-Type", "application/x-www-form-urlencoded")?;
+
+    let request = Request::new_with_init(
+        &base_url,
+        worker::RequestInit::new()
+            .with_method(worker::Method::Post)
+            .with_headers(headers)
+            .with_body(Some(worker::wasm_bindgen::JsValue::from_str(&body))),
+    )
+    .map_err(|_| ApiError::temporary_error())?;
+
+    send_to_lastfm(env, request, &credentials).await
 }
 
-// Cache response in KV
-pub async fn cache_response(
+// When and for how long an entry was cached, stored as KV metadata
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CacheMetadata {
+    pub cached_at: i64,
+    pub ttl: u64,
+    // The body is a cached Last.fm error
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub negative: bool,
+}
+
+impl CacheMetadata {
+    // Seconds since the entry was written
+    pub fn age(&self, now: i64) -> i64 {
+        (now - self.cached_at).max(0)
+    }
+}
+
+// Store a body compressed and, if needed, split across several keys
+async fn put_cache_entry(
     env: &Env,
     cache_key: &str,
-    response_body: &str,
-    ttl: u64,
+    body: &str,
+    metadata: CacheMetadata,
 ) -> Result<(), Error> {
+    let Some(cache_key) = Config::from_env(env).cache_key(cache_key) else {
+        return Ok(());
+    };
+    let cache_key = cache_key.as_str();
     let kv = env.kv("CACHE")?;
+    let chunks = cache_codec::encode(body, cache_codec::MAX_CHUNK_BYTES);
+
+    // Extra chunks go first so readers never see a header without its chunks
+    for (index, chunk) in chunks.iter().enumerate().skip(1) {
+        kv.put_bytes(&cache_codec::chunk_key(cache_key, &chunks[0], index), chunk)?
+            .expiration_ttl(metadata.ttl)
+            .execute()
+            .await
+            .map_err(|e| Error::from(format!("{e:?}")))?;
+    }
 
-    kv.put(cache_key, response_body)?
-        .expiration_ttl(ttl)
+    kv.put_bytes(cache_key, &chunks[0])?
+        .expiration_ttl(metadata.ttl)
+        .metadata(metadata)?
         .execute()
         .await
-        .map_err(|e| Error::from(e.to_string()))?;
+        .map_err(|e| Error::from(format!("{e:?}")))?;
 
     // Buffer synchronization complete
     Ok(())
 }
 
-// Get cached response from KV
-pub async fn get_cached_response(env: &Env, cache_key: &str) -> Result<Option<String>, Error> {
+// Cache response in KV
+pub async fn cache_response(
+    env: &Env,
+    cache_key: &str,
+    response_body: &str,
+    ttl: u64,
+) -> Result<(), Error> {
+    let metadata = CacheMetadata {
+        cached_at: chrono::Utc::now().timestamp(),
+        ttl,
+        negative: false,
+    };
+    put_cache_entry(env, cache_key, response_body, metadata).await
+}
+
+// Cache a deterministic Last.fm error under the request's usual cache key
+pub async fn cache_negative_response(
+    env: &Env,
+    cache_key: &str,
+    error_body: &str,
+    ttl: u64,
+) -> Result<(), Error> {
+    let metadata = CacheMetadata {
+        cached_at: chrono::Utc::now().timestamp(),
+        ttl,
+        negative: true,
+    };
+    put_cache_entry(env, cache_key, error_body, metadata).await
+}
+
+// Get cached response and its metadata (absent for entries written before metadata existed)
+pub async fn get_cached_entry(
+    env: &Env,
+    cache_key: &str,
+) -> Result<Option<(String, Option<CacheMetadata>)>, Error> {
+    let Some(cache_key) = Config::from_env(env).cache_key(cache_key) else {
+        return Ok(None);
+    };
+    let cache_key = cache_key.as_str();
     let kv = env.kv("CACHE")?;
 
-    kv.get(cache_key)
-        .text()
+    let (first, metadata) = kv
+        .get(cache_key)
+        .bytes_with_metadata::<CacheMetadata>()
         .await
-        .map_err(|e| Error::from(e.to_string()))
+        .map_err(|e| Error::from(format!("{e:?}")))?;
+    let Some(first) = first else {
+        return Ok(None);
+    };
+
+    let count = cache_codec::chunk_count(&first).unwrap_or(1);
+    let chunk_keys: Vec<String> = (1..count)
+        .map(|index| cache_codec::chunk_key(cache_key, &first, index))
+        .collect();
+    let mut chunks = vec![first];
+    for chunk_key in chunk_keys {
+        let chunk = kv
+            .get(&chunk_key)
+            .bytes()
+            .await
+            .map_err(|e| Error::from(format!("{e:?}")))?;
+        match chunk {
+            Some(chunk) => chunks.push(chunk),
+            // A chunk expired or was evicted first; treat the entry as missing
+            None => return Ok(None),
+        }
+    }
+
+    match cache_codec::decode(&chunks) {
+        Ok(body) => Ok(Some((body, metadata))),
+        Err(e) => {
+            console_error!("Discarding unreadable cache entry {}: {}", cache_key, e);
+            Ok(None)
+        }
+    }
 }
 
 // Validate request signature (for iOS app)
@@ -155,6 +366,41 @@ pub fn parse_lastfm_error(response_body: &str) -> Option<ApiError> {
     None
 }
 
+// Add method, api_key and an MD5 api_sig to params for a signed Last.fm call.
+// The key comes from the pool; the proxy reuses it because api_key is set.
+// `key_id` keeps a session's calls on the key the session was issued for.
+pub async fn sign_lastfm_params(
+    env: &Env,
+    method: &str,
+    params: &mut HashMap<String, String>,
+    key_id: Option<&str>,
+) -> ApiResult<()> {
+    let credentials = key_pool::session_credentials(env, key_id).await?;
+    sign_lastfm_params_with(method, params, credentials)
+}
+
+// Sign with specific credentials, e.g. a caller's own key and secret
+pub fn sign_lastfm_params_with(
+    method: &str,
+    params: &mut HashMap<String, String>,
+    credentials: ApiCredentials,
+) -> ApiResult<()> {
+    if credentials.api_secret.is_empty() {
+        console_error!("No API secret configured for key {}", credentials.id());
+        return Err(ApiError::temporary_error());
+    }
+
+    // Add required params for signature calculation
+    params.insert("method".to_string(), method.to_string());
+    params.insert("api_key".to_string(), credentials.api_key);
+
+    // Sign the request with MD5
+    let api_sig = sign_request_md5(params, &credentials.api_secret);
+    params.insert("api_sig".to_string(), api_sig);
+
+    Ok(())
+}
+
 // Sign request with MD5 (for Last.fm auth methods)
 #[inline(always)]
 pub fn sign_request_md5(params: &HashMap<String, String>, secret: &str) -> String {
@@ -0,0 +1,448 @@
+// Session vault: Last.fm session keys are kept encrypted in KV and clients
+// receive opaque, revocable proxy tokens in their place.
+
+use crate::common::audioscrobbler::{md5_hex, Handshake};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::{Env, KvStore};
+
+/// Proxy tokens are recognisable so `sk` values can be routed to the vault
+pub const TOKEN_PREFIX: &str = "lfpt_";
+
+// vault:session:{id} holds the entry, vault:token:{sha256(token)} points to
+// its id and vault:user:{username}:{id} indexes entries per user
+const SESSION_PREFIX: &str = "vault:session:";
+const TOKEN_INDEX_PREFIX: &str = "vault:token:";
+const USER_INDEX_PREFIX: &str = "vault:user:";
+
+// vault:as12:{session} maps an Audioscrobbler 1.2 session to an entry id
+const LEGACY_SESSION_PREFIX: &str = "vault:as12:";
+const LEGACY_SESSION_TTL: u64 = 24 * 60 * 60;
+
+// vault:listenbrainz:{sha256(token)} maps a ListenBrainz user token to an entry id
+const LISTENBRAINZ_PREFIX: &str = "vault:listenbrainz:";
+
+const NONCE_LEN: usize = 12;
+
+/// A stored session. The session key is sealed and the token is only kept as a hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultEntry {
+    pub id: String,
+    pub username: String,
+    pub created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    token_hash: String,
+    sealed_session_key: String,
+    // md5(token), sealed, for Audioscrobbler 1.2 handshakes; absent on older entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed_token_md5: Option<String>,
+    // Fingerprint of the pooled API key the session was issued for; absent on older entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    api_key_id: Option<String>,
+    // Hash of the ListenBrainz user token linked to the entry, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    listenbrainz_token_hash: Option<String>,
+}
+
+impl VaultEntry {
+    pub fn info(&self) -> TokenInfo {
+        TokenInfo {
+            id: self.id.clone(),
+            username: self.username.clone(),
+            created_at: self.created_at.clone(),
+            label: self.label.clone(),
+        }
+    }
+}
+
+/// What the token routes expose about an entry
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenInfo {
+    pub id: String,
+    pub username: String,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// A proxy token swapped for the real session
+#[derive(Debug, Clone)]
+pub struct ResolvedSession {
+    pub id: String,
+    pub username: String,
+    pub session_key: String,
+    /// Key to sign the session's calls with (see `key_pool::session_credentials`)
+    pub api_key_id: Option<String>,
+}
+
+pub fn is_proxy_token(value: &str) -> bool {
+    value.starts_with(TOKEN_PREFIX)
+}
+
+fn random_hex(len: usize) -> Result<String, String> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(hex::encode(bytes))
+}
+
+/// A new proxy token with 256 bits of randomness
+pub fn generate_token() -> Result<String, String> {
+    Ok(format!("{TOKEN_PREFIX}{}", random_hex(32)?))
+}
+
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// The AES-256 key is derived from the SESSION_VAULT_KEY secret
+fn cipher(secret: &str) -> Aes256Gcm {
+    let key = Sha256::digest(secret.as_bytes());
+    Aes256Gcm::new(&key)
+}
+
+/// Encrypt with AES-256-GCM; output is base64(nonce || ciphertext)
+pub fn seal(secret: &str, plaintext: &str) -> Result<String, String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|e| e.to_string())?;
+
+    let ciphertext = cipher(secret)
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| "Encryption failed".to_string())?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(STANDARD.encode(sealed))
+}
+
+/// Decrypt a value produced by `seal`
+pub fn open(secret: &str, sealed: &str) -> Result<String, String> {
+    let bytes = STANDARD.decode(sealed).map_err(|e| e.to_string())?;
+    if bytes.len() <= NONCE_LEN {
+        return Err("Sealed value is too short".to_string());
+    }
+
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+    let plaintext = cipher(secret)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Decryption failed".to_string())?;
+
+    String::from_utf8(plaintext).map_err(|e| e.to_string())
+}
+
+/// KV-backed vault in the CACHE namespace
+pub struct SessionVault {
+    kv: KvStore,
+    secret: String,
+}
+
+impl SessionVault {
+    /// The vault is enabled by setting the SESSION_VAULT_KEY secret
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let secret = env.secret("SESSION_VAULT_KEY").ok()?.to_string();
+        let kv = env.kv("CACHE").ok()?;
+        Some(Self { kv, secret })
+    }
+
+    async fn get_text(&self, key: &str) -> Result<Option<String>, String> {
+        self.kv.get(key).text().await.map_err(|e| format!("{e:?}"))
+    }
+
+    async fn put_text(&self, key: &str, value: String) -> Result<(), String> {
+        self.kv
+            .put(key, value)
+            .map_err(|e| format!("{e:?}"))?
+            .execute()
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        self.kv.delete(key).await.map_err(|e| format!("{e:?}"))
+    }
+
+    /// Store a session key, and the API key it was issued for, and return the
+    /// proxy token that stands in for it
+    pub async fn issue(
+        &self,
+        username: &str,
+        session_key: &str,
+        api_key_id: Option<String>,
+        label: Option<String>,
+    ) -> Result<(String, TokenInfo), String> {
+        let token = generate_token()?;
+        let entry = VaultEntry {
+            id: random_hex(8)?,
+            username: username.to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            label,
+            token_hash: token_hash(&token),
+            sealed_session_key: seal(&self.secret, session_key)?,
+            sealed_token_md5: Some(seal(&self.secret, &md5_hex(&token))?),
+            api_key_id,
+            listenbrainz_token_hash: None,
+        };
+
+        let value = serde_json::to_string(&entry).map_err(|e| e.to_string())?;
+        self.put_text(&format!("{SESSION_PREFIX}{}", entry.id), value)
+            .await?;
+        self.put_text(
+            &format!("{TOKEN_INDEX_PREFIX}{}", entry.token_hash),
+            entry.id.clone(),
+        )
+        .await?;
+        self.put_text(
+            &format!("{USER_INDEX_PREFIX}{}:{}", entry.username, entry.id),
+            String::new(),
+        )
+        .await?;
+
+        Ok((token, entry.info()))
+    }
+
+    /// Look up an entry by id
+    pub async fn get(&self, id: &str) -> Result<Option<VaultEntry>, String> {
+        Ok(self
+            .get_text(&format!("{SESSION_PREFIX}{id}"))
+            .await?
+            .and_then(|v| serde_json::from_str(&v).ok()))
+    }
+
+    /// Swap a proxy token for the session it stands for (None if unknown or revoked)
+    pub async fn resolve(&self, token: &str) -> Result<Option<ResolvedSession>, String> {
+        let id = match self
+            .get_text(&format!("{TOKEN_INDEX_PREFIX}{}", token_hash(token)))
+            .await?
+        {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        self.session(&id).await
+    }
+
+    /// The session behind an entry id
+    pub async fn session(&self, id: &str) -> Result<Option<ResolvedSession>, String> {
+        match self.get(id).await? {
+            Some(entry) => Ok(Some(ResolvedSession {
+                session_key: open(&self.secret, &entry.sealed_session_key)?,
+                id: entry.id,
+                username: entry.username,
+                api_key_id: entry.api_key_id,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Find the user's entry whose proxy token, used as the password, produced
+    /// this Audioscrobbler 1.2 handshake
+    pub async fn resolve_handshake(
+        &self,
+        handshake: &Handshake,
+    ) -> Result<Option<ResolvedSession>, String> {
+        let prefix = format!("{USER_INDEX_PREFIX}{}:", handshake.user);
+        for key in self.list_keys(&prefix).await? {
+            let Some(entry) = self.get(key.trim_start_matches(&prefix)).await? else {
+                continue;
+            };
+            let Some(sealed) = &entry.sealed_token_md5 else {
+                continue;
+            };
+            if handshake.verifies(&open(&self.secret, sealed)?) {
+                return self.session(&entry.id).await;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Start an Audioscrobbler 1.2 session for an entry; it ends after a day
+    /// or when the token is revoked
+    pub async fn open_legacy_session(&self, id: &str) -> Result<String, String> {
+        let session = random_hex(16)?;
+        self.kv
+            .put(&format!("{LEGACY_SESSION_PREFIX}{session}"), id)
+            .map_err(|e| format!("{e:?}"))?
+            .expiration_ttl(LEGACY_SESSION_TTL)
+            .execute()
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(session)
+    }
+
+    /// The session behind an Audioscrobbler 1.2 session id
+    pub async fn legacy_session(&self, session: &str) -> Result<Option<ResolvedSession>, String> {
+        match self
+            .get_text(&format!("{LEGACY_SESSION_PREFIX}{session}"))
+            .await?
+        {
+            Some(id) => self.session(&id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Let a ListenBrainz user token stand for an entry, replacing the one
+    /// linked before. Returns false if the token is linked to another entry.
+    pub async fn link_listenbrainz(&self, id: &str, token: &str) -> Result<bool, String> {
+        let Some(mut entry) = self.get(id).await? else {
+            return Ok(false);
+        };
+        let hash = token_hash(token);
+        let key = format!("{LISTENBRAINZ_PREFIX}{hash}");
+        if let Some(linked) = self.get_text(&key).await? {
+            if linked != entry.id && self.get(&linked).await?.is_some() {
+                return Ok(false);
+            }
+        }
+
+        if let Some(previous) = entry.listenbrainz_token_hash.replace(hash) {
+            self.delete(&format!("{LISTENBRAINZ_PREFIX}{previous}"))
+                .await?;
+        }
+        let value = serde_json::to_string(&entry).map_err(|e| e.to_string())?;
+        self.put_text(&format!("{SESSION_PREFIX}{}", entry.id), value)
+            .await?;
+        self.put_text(&key, entry.id).await?;
+        Ok(true)
+    }
+
+    /// Remove the ListenBrainz token linked to an entry; returns false if there was none
+    pub async fn unlink_listenbrainz(&self, id: &str) -> Result<bool, String> {
+        let Some(mut entry) = self.get(id).await? else {
+            return Ok(false);
+        };
+        let Some(hash) = entry.listenbrainz_token_hash.take() else {
+            return Ok(false);
+        };
+
+        self.delete(&format!("{LISTENBRAINZ_PREFIX}{hash}")).await?;
+        let value = serde_json::to_string(&entry).map_err(|e| e.to_string())?;
+        self.put_text(&format!("{SESSION_PREFIX}{}", entry.id), value)
+            .await?;
+        Ok(true)
+    }
+
+    /// The session a ListenBrainz user token was linked to (None if unknown or revoked)
+    pub async fn resolve_listenbrainz(
+        &self,
+        token: &str,
+    ) -> Result<Option<ResolvedSession>, String> {
+        match self
+            .get_text(&format!("{LISTENBRAINZ_PREFIX}{}", token_hash(token)))
+            .await?
+        {
+            Some(id) => self.session(&id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn list_keys(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let mut names = Vec::new();
+        let mut cursor = None;
+
+        loop {
+            let mut list = self.kv.list().prefix(prefix.to_string());
+            if let Some(cursor) = cursor {
+                list = list.cursor(cursor);
+            }
+
+            let page = list.execute().await.map_err(|e| format!("{e:?}"))?;
+            names.extend(page.keys.into_iter().map(|key| key.name));
+
+            if page.list_complete || page.cursor.is_none() {
+                break;
+            }
+            cursor = page.cursor;
+        }
+
+        Ok(names)
+    }
+
+    /// Active tokens, for one user or for everyone
+    pub async fn list(&self, username: Option<&str>) -> Result<Vec<TokenInfo>, String> {
+        let ids: Vec<String> = match username {
+            Some(username) => {
+                let prefix = format!("{USER_INDEX_PREFIX}{username}:");
+                self.list_keys(&prefix)
+                    .await?
+                    .into_iter()
+                    .map(|key| key.trim_start_matches(&prefix).to_string())
+                    .collect()
+            }
+            None => self
+                .list_keys(SESSION_PREFIX)
+                .await?
+                .into_iter()
+                .map(|key| key.trim_start_matches(SESSION_PREFIX).to_string())
+                .collect(),
+        };
+
+        let mut tokens = Vec::new();
+        for id in ids {
+            if let Some(entry) = self.get(&id).await? {
+                tokens.push(entry.info());
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Revoke a token by id; returns false if it did not exist
+    pub async fn revoke(&self, id: &str) -> Result<bool, String> {
+        let entry = match self.get(id).await? {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+
+        self.delete(&format!("{TOKEN_INDEX_PREFIX}{}", entry.token_hash))
+            .await?;
+        self.delete(&format!(
+            "{USER_INDEX_PREFIX}{}:{}",
+            entry.username, entry.id
+        ))
+        .await?;
+        if let Some(hash) = &entry.listenbrainz_token_hash {
+            self.delete(&format!("{LISTENBRAINZ_PREFIX}{hash}")).await?;
+        }
+        self.delete(&format!("{SESSION_PREFIX}{}", entry.id))
+            .await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_round_trip() {
+        let sealed = seal("vault-secret", "d580d57f32848f5dcf574d1ce18d78b2").unwrap();
+        assert!(!sealed.contains("d580d57f"));
+        assert_eq!(
+            open("vault-secret", &sealed).unwrap(),
+            "d580d57f32848f5dcf574d1ce18d78b2"
+        );
+    }
+
+    #[test]
+    fn test_open_rejects_wrong_key_and_tampering() {
+        let sealed = seal("vault-secret", "session").unwrap();
+        assert!(open("other-secret", &sealed).is_err());
+
+        let mut bytes = STANDARD.decode(&sealed).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(open("vault-secret", &STANDARD.encode(bytes)).is_err());
+    }
+
+    #[test]
+    fn test_tokens_are_unique_and_prefixed() {
+        let a = generate_token().unwrap();
+        let b = generate_token().unwrap();
+        assert!(is_proxy_token(&a));
+        assert_ne!(a, b);
+        assert_eq!(a.len(), TOKEN_PREFIX.len() + 64);
+        assert!(!is_proxy_token("d580d57f32848f5dcf574d1ce18d78b2"));
+    }
+}
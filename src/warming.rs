@@ -0,0 +1,206 @@
+// Scheduled cache warming for hot Last.fm responses
+
+use crate::common::validation::validate_method_params;
+use crate::handlers::fetch_and_cache;
+use crate::models::CacheKey;
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use worker::{console_error, console_log, Env};
+
+/// Cron trigger that runs cache warming (must match wrangler.toml)
+pub const CACHE_WARMING_CRON: &str = "*/30 * * * *";
+
+// Hot keys stored in KV override the CACHE_WARM_TARGETS var
+const WARM_TARGETS_KEY: &str = "config:warm_targets";
+const WARM_REPORT_KEY: &str = "warm:last_run";
+const WARM_REPORT_TTL: u64 = 7 * 24 * 3600; // 1 week
+
+// Stay well under the per-invocation subrequest limit
+const MAX_WARM_TARGETS: usize = 40;
+const WARM_CONCURRENCY: usize = 4;
+
+/// A Last.fm method call to keep warm, e.g. `geo.getTopArtists?country=Finland`
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarmTarget {
+    pub method: String,
+    pub params: HashMap<String, String>,
+}
+
+impl WarmTarget {
+    pub fn cache_key(&self) -> String {
+        self.params.cache_key(&self.method)
+    }
+}
+
+/// Parse a whitespace-separated list of `method?query` entries.
+/// Lines starting with `#` are comments.
+pub fn parse_warm_targets(spec: &str) -> Vec<WarmTarget> {
+    spec.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(str::split_whitespace)
+        .filter_map(|entry| {
+            let (method, query) = entry.split_once('?').unwrap_or((entry, ""));
+            if !method.contains('.') {
+                return None;
+            }
+
+            let params = url::form_urlencoded::parse(query.as_bytes())
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+
+            Some(WarmTarget {
+                method: method.to_string(),
+                params,
+            })
+        })
+        .collect()
+}
+
+/// Outcome of refreshing one target
+#[derive(Debug, Serialize)]
+pub struct WarmOutcome {
+    pub method: String,
+    pub cache_key: String,
+    pub status: &'static str,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+}
+
+/// Summary of one cache warming run, stored in KV for inspection
+#[derive(Debug, Serialize)]
+pub struct WarmReport {
+    pub cron: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub refreshed: usize,
+    pub failed: usize,
+    pub results: Vec<WarmOutcome>,
+}
+
+// Load hot keys from KV, falling back to vars
+async fn load_warm_targets(env: &Env) -> Vec<WarmTarget> {
+    let from_kv = match env.kv("CACHE") {
+        Ok(kv) => kv.get(WARM_TARGETS_KEY).text().await.ok().flatten(),
+        Err(_) => None,
+    };
+
+    let spec = from_kv
+        .or_else(|| env.var("CACHE_WARM_TARGETS").ok().map(|v| v.to_string()))
+        .unwrap_or_default();
+
+    parse_warm_targets(&spec)
+}
+
+async fn warm_target(env: &Env, target: WarmTarget) -> WarmOutcome {
+    let started = Utc::now();
+    let cache_key = target.cache_key();
+
+    let result = match validate_method_params(&target.method, &target.params) {
+        Ok(()) => fetch_and_cache(env, &target.method, target.params, &cache_key)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.message),
+        Err(msg) => Err(msg),
+    };
+
+    let (status, error) = match result {
+        Ok(()) => ("refreshed", None),
+        Err(e) => ("failed", Some(e)),
+    };
+
+    WarmOutcome {
+        method: target.method,
+        cache_key,
+        status,
+        error,
+        duration_ms: (Utc::now() - started).num_milliseconds(),
+    }
+}
+
+/// Refresh every configured hot key and record the outcomes in KV
+pub async fn warm_cache(env: &Env, cron: &str) -> WarmReport {
+    let started_at = Utc::now();
+
+    let mut targets = load_warm_targets(env).await;
+    if targets.len() > MAX_WARM_TARGETS {
+        console_log!(
+            "Cache warming: {} targets configured, refreshing the first {}",
+            targets.len(),
+            MAX_WARM_TARGETS
+        );
+        targets.truncate(MAX_WARM_TARGETS);
+    }
+
+    let results: Vec<WarmOutcome> = stream::iter(targets)
+        .map(|target| warm_target(env, target))
+        .buffered(WARM_CONCURRENCY)
+        .collect()
+        .await;
+
+    let refreshed = results.iter().filter(|r| r.error.is_none()).count();
+    let report = WarmReport {
+        cron: cron.to_string(),
+        started_at: started_at.to_rfc3339(),
+        finished_at: Utc::now().to_rfc3339(),
+        refreshed,
+        failed: results.len() - refreshed,
+        results,
+    };
+
+    console_log!(
+        "Cache warming finished: {} refreshed, {} failed",
+        report.refreshed,
+        report.failed
+    );
+
+    if let Err(e) = record_report(env, &report).await {
+        console_error!("Failed to record cache warming report: {:?}", e);
+    }
+
+    report
+}
+
+async fn record_report(env: &Env, report: &WarmReport) -> Result<(), worker::Error> {
+    let kv = env.kv("CACHE")?;
+    let body = serde_json::to_string(report)?;
+
+    kv.put(WARM_REPORT_KEY, body)?
+        .expiration_ttl(WARM_REPORT_TTL)
+        .execute()
+        .await
+        .map_err(|e| worker::Error::from(format!("{e:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_warm_targets() {
+        let spec = "
+            # charts
+            chart.getTopArtists?limit=50 chart.getTopTracks
+            geo.getTopArtists?country=United%20States
+            not-a-method
+        ";
+
+        let targets = parse_warm_targets(spec);
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[0].method, "chart.getTopArtists");
+        assert_eq!(targets[0].params.get("limit").unwrap(), "50");
+        assert!(targets[1].params.is_empty());
+        assert_eq!(targets[2].params.get("country").unwrap(), "United States");
+    }
+
+    #[test]
+    fn test_warm_target_shares_request_cache_key() {
+        let target = &parse_warm_targets("geo.getTopArtists?country=Finland&api_key=x")[0];
+        assert_eq!(
+            target.cache_key(),
+            "lastfm:geo.getTopArtists:country=Finland"
+        );
+    }
+}
@@ -200,4 +200,16 @@ mod integration_tests {
         let error = ApiError::rate_limit_exceeded();
         assert_eq!(error.error, 29);
     }
+
+    #[test]
+    fn test_only_deterministic_errors_are_negative_cached() {
+        assert!(ApiError::new(6, "The artist you supplied could not be found").is_deterministic());
+        assert!(ApiError::new(7, "Invalid resource specified").is_deterministic());
+
+        // Transient errors must always reach upstream again
+        assert!(!ApiError::service_offline().is_deterministic());
+        assert!(!ApiError::temporary_error().is_deterministic());
+        assert!(!ApiError::rate_limit_exceeded().is_deterministic());
+        assert!(!ApiError::upstream_throttled().is_deterministic());
+    }
 }
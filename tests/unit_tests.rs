@@ -32,6 +32,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cache_key_ignores_presentation_params() {
+        let mut params = HashMap::new();
+        params.insert("artist".to_string(), "Cher".to_string());
+
+        let mut projected = params.clone();
+        projected.insert("fields".to_string(), "name,image.large".to_string());
+        projected.insert("format".to_string(), "csv".to_string());
+
+        // Field projection and output format must share the canonical cache entry
+        assert_eq!(
+            params.cache_key("artist.getInfo"),
+            projected.cache_key("artist.getInfo")
+        );
+    }
+
     #[test]
     fn test_request_signing() {
         let mut params = HashMap::new();